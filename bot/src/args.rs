@@ -0,0 +1,61 @@
+//! Splitting command messages into arguments.
+
+/// Splits a command into whitespace-separated arguments.
+///
+/// Double quotes group words with spaces into one argument, e.g. `header="Synced from {{repo}}"`,
+/// and are removed from the argument. A backslash escapes the next character inside quotes.
+pub fn split(content: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut arg: Option<String> = None;
+    let mut quoted = false;
+    let mut chars = content.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '"' => {
+                quoted = !quoted;
+                arg.get_or_insert_with(String::new);
+            }
+            '\\' if quoted => {
+                if let Some(escaped) = chars.next() {
+                    arg.get_or_insert_with(String::new).push(escaped);
+                }
+            }
+            ch if ch.is_whitespace() && !quoted => args.extend(arg.take()),
+            ch => arg.get_or_insert_with(String::new).push(ch),
+        }
+    }
+    args.extend(arg);
+    args
+}
+
+/// Parses a channel mention such as `<#123>` into the channel ID.
+pub fn channel_mention(arg: &str) -> Option<u64> {
+    arg.strip_prefix("<#")?.strip_suffix('>')?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quotes_group_words() {
+        assert_eq!(
+            split(r#"mirror  url header="Synced from {{repo}}" footer="say \"hi\"" x="""#),
+            vec![
+                "mirror",
+                "url",
+                "header=Synced from {{repo}}",
+                r#"footer=say "hi""#,
+                "x=",
+            ]
+        );
+    }
+
+    #[test]
+    fn channel_mentions_are_parsed() {
+        assert_eq!(channel_mention("<#123>"), Some(123));
+        assert_eq!(channel_mention("<@123>"), None);
+        assert_eq!(channel_mention("<#general>"), None);
+        assert_eq!(channel_mention("split=<#123>"), None);
+    }
+}
@@ -0,0 +1,532 @@
+//! The Discord operations used by the bot logic.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context as _;
+use once_cell::sync::Lazy;
+use reqwest::StatusCode;
+use serenity::builder::CreateEmbed;
+use serenity::http::{Http, HttpError};
+use serenity::model::id::{ChannelId, MessageId};
+
+use common::render::{Embed, Field};
+
+use crate::permissions;
+
+/// Base URL of the Discord API version that supports threads and forums,
+/// which serenity does not implement yet
+const DISCORD_API: &str = "https://discord.com/api/v9";
+
+/// Discord error code of changes to messages in an archived thread
+const THREAD_ARCHIVED_CODE: isize = 50083;
+
+/// Maximum number of times a rate limited request is retried
+const MAX_RETRIES: usize = 3;
+
+/// The client of requests that serenity has no method for, shared to reuse connections
+static CLIENT: Lazy<reqwest::Client> = Lazy::new(reqwest::Client::new);
+
+/// The error of `ChatSink` methods that change messages in an archived thread
+#[derive(Debug)]
+pub struct ThreadArchived;
+
+impl fmt::Display for ThreadArchived {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("The thread is archived")
+    }
+}
+
+impl std::error::Error for ThreadArchived {}
+
+/// The kinds of channels that mirrors can be posted in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelKind {
+    /// A text or announcement channel
+    Text,
+    /// A thread, including posts in forum channels
+    Thread,
+    /// A forum channel, where each mirror gets its own post
+    Forum,
+}
+
+impl ChannelKind {
+    /// Maps a Discord channel type, returning `None` for channels without messages.
+    pub fn from_type(kind: u64) -> Option<Self> {
+        match kind {
+            0 | 5 => Some(Self::Text),
+            10..=12 => Some(Self::Thread),
+            15 => Some(Self::Forum),
+            _ => None,
+        }
+    }
+}
+
+/// A channel looked up through the Discord API
+#[derive(Debug, Clone)]
+pub struct Channel {
+    pub id: u64,
+    /// `None` for direct messages
+    pub guild_id: Option<u64>,
+    /// `None` for channels that mirrors cannot be posted in
+    pub kind: Option<ChannelKind>,
+    /// Whether the channel is an archived thread
+    pub archived: bool,
+    /// The channel that a thread was created in
+    pub parent_id: Option<u64>,
+    /// Threads use the permission overwrites of their parent channel instead
+    pub overwrites: Vec<permissions::Overwrite>,
+}
+
+/// A destination for mirror messages and warnings
+#[async_trait::async_trait]
+pub trait ChatSink: Send + Sync {
+    /// Posts a message in a channel, returning its ID.
+    async fn send(&self, channel_id: u64, content: &str) -> anyhow::Result<u64>;
+
+    /// Posts a message with a file attached in a channel, returning its ID.
+    async fn send_file(
+        &self,
+        channel_id: u64,
+        content: &str,
+        filename: &str,
+        data: &[u8],
+    ) -> anyhow::Result<u64>;
+
+    /// Replaces the content of a message.
+    ///
+    /// Fails with `ThreadArchived` if the message is in an archived thread,
+    /// like `edit_embed` and `delete`.
+    async fn edit(&self, channel_id: u64, message_id: u64, content: &str) -> anyhow::Result<()>;
+
+    /// Posts an embed with `description` and `fields` in a channel, returning the message ID.
+    async fn send_embed(
+        &self,
+        channel_id: u64,
+        embed: &Embed,
+        description: &str,
+        fields: &[Field],
+    ) -> anyhow::Result<u64>;
+
+    /// Replaces the embed of a message.
+    async fn edit_embed(
+        &self,
+        channel_id: u64,
+        message_id: u64,
+        embed: &Embed,
+        description: &str,
+        fields: &[Field],
+    ) -> anyhow::Result<()>;
+
+    /// Deletes a message.
+    async fn delete(&self, channel_id: u64, message_id: u64) -> anyhow::Result<()>;
+
+    /// Adds a reaction of the bot to a message.
+    async fn react(&self, channel_id: u64, message_id: u64, emoji: char) -> anyhow::Result<()>;
+
+    /// Removes all reactions of an emoji from a message.
+    async fn unreact(&self, channel_id: u64, message_id: u64, emoji: char) -> anyhow::Result<()>;
+
+    /// Returns the current content of a message.
+    async fn fetch(&self, channel_id: u64, message_id: u64) -> anyhow::Result<String>;
+
+    /// Creates a post titled `title` in a forum channel with `content` as its first message,
+    /// returning the ID of the post thread.
+    async fn create_post(&self, forum_id: u64, title: &str, content: &str) -> anyhow::Result<u64>;
+
+    /// Unarchives a thread so that its messages can be edited again.
+    ///
+    /// Returns `false` without changes if the channel is not an archived thread.
+    async fn unarchive(&self, channel_id: u64) -> anyhow::Result<bool>;
+
+    /// Looks up a channel, including threads and forums.
+    async fn channel(&self, channel_id: u64) -> anyhow::Result<Channel>;
+
+    /// Looks up the owner and role permissions of a guild.
+    async fn guild(&self, guild_id: u64) -> anyhow::Result<permissions::Guild>;
+
+    /// Returns the role IDs of a guild member.
+    async fn member_roles(&self, guild_id: u64, user_id: u64) -> anyhow::Result<Vec<u64>>;
+
+    /// Returns the user ID of the bot.
+    async fn current_user_id(&self) -> anyhow::Result<u64>;
+}
+
+/// Sends messages through the Discord HTTP API
+pub struct Serenity(pub Arc<Http>);
+
+impl Serenity {
+    /// Sends a request that serenity has no method for.
+    ///
+    /// Rate limited requests are retried after the delay requested by Discord.
+    async fn raw(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        body: Option<serde_json::Value>,
+    ) -> anyhow::Result<reqwest::Response> {
+        #[derive(serde::Deserialize)]
+        struct RawError {
+            code: isize,
+            message: String,
+        }
+
+        let url = format!("{}{}", DISCORD_API, path);
+        let mut retries = 0;
+        loop {
+            let mut request = CLIENT
+                .request(method.clone(), &url)
+                // serenity adds the `Bot ` prefix to the token
+                .header(reqwest::header::AUTHORIZATION, &self.0.token);
+            if let Some(body) = &body {
+                request = request.json(body);
+            }
+            let response = request.send().await?;
+
+            let status = response.status();
+            if status.is_success() {
+                return Ok(response);
+            }
+            if status == StatusCode::TOO_MANY_REQUESTS && retries < MAX_RETRIES {
+                retries += 1;
+                let delay = retry_after(&response);
+                log::warn!(
+                    "Rate limited on {} {}, retrying in {:?}",
+                    &method,
+                    path,
+                    delay
+                );
+                tokio::time::sleep(delay).await;
+                continue;
+            }
+            match response.json::<RawError>().await {
+                Ok(error) if error.code == THREAD_ARCHIVED_CODE => {
+                    return Err(ThreadArchived.into())
+                }
+                Ok(error) => anyhow::bail!(
+                    "{} {} failed with {}: {} (code {})",
+                    &method,
+                    path,
+                    status,
+                    error.message,
+                    error.code
+                ),
+                Err(_) => anyhow::bail!("{} {} failed with {}", &method, path, status),
+            }
+        }
+    }
+
+    /// Sends a `GET` request that serenity has no method for and parses the response.
+    async fn get<T: serde::de::DeserializeOwned>(&self, path: &str) -> anyhow::Result<T> {
+        self.raw(reqwest::Method::GET, path, None)
+            .await?
+            .json()
+            .await
+            .context("Discord API is not working correctly")
+    }
+}
+
+/// Returns the delay requested by the `Retry-After` header of a rate limited response.
+fn retry_after(response: &reqwest::Response) -> Duration {
+    response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        // Discord sends fractional seconds
+        .and_then(|value| value.parse::<f64>().ok())
+        .filter(|&secs| secs.is_finite() && secs >= 0.0)
+        .map_or(Duration::from_secs(1), Duration::from_secs_f64)
+}
+
+/// Turns the Discord error of changes in archived threads into `ThreadArchived`.
+fn archived_error(err: serenity::Error) -> anyhow::Error {
+    if let serenity::Error::Http(http) = &err {
+        if let HttpError::UnsuccessfulRequest(response) = &**http {
+            if response.error.code == THREAD_ARCHIVED_CODE {
+                return ThreadArchived.into();
+            }
+        }
+    }
+    err.into()
+}
+
+fn parse_id(id: Option<String>) -> Result<Option<u64>, std::num::ParseIntError> {
+    id.map(|id| id.parse()).transpose()
+}
+
+fn build_embed<'e>(
+    e: &'e mut CreateEmbed,
+    embed: &Embed,
+    description: &str,
+    fields: &[Field],
+) -> &'e mut CreateEmbed {
+    e.title(&embed.title)
+        .url(&embed.url)
+        .colour(embed.color)
+        .footer(|f| f.text(&embed.footer))
+        .timestamp(embed.timestamp.as_str())
+        .description(description)
+        .fields(
+            fields
+                .iter()
+                .map(|field| (&field.name, &field.value, field.inline)),
+        )
+}
+
+#[async_trait::async_trait]
+impl ChatSink for Serenity {
+    async fn send(&self, channel_id: u64, content: &str) -> anyhow::Result<u64> {
+        let message = ChannelId::from(channel_id)
+            .send_message(&self.0, |m| m.content(content))
+            .await?;
+        Ok(*message.id.as_u64())
+    }
+
+    async fn send_file(
+        &self,
+        channel_id: u64,
+        content: &str,
+        filename: &str,
+        data: &[u8],
+    ) -> anyhow::Result<u64> {
+        let message = ChannelId::from(channel_id)
+            .send_message(&self.0, |m| {
+                if !content.is_empty() {
+                    m.content(content);
+                }
+                m.add_file((data, filename))
+            })
+            .await?;
+        Ok(*message.id.as_u64())
+    }
+
+    async fn edit(&self, channel_id: u64, message_id: u64, content: &str) -> anyhow::Result<()> {
+        ChannelId::from(channel_id)
+            .edit_message(&self.0, MessageId::from(message_id), |m| m.content(content))
+            .await
+            .map_err(archived_error)?;
+        Ok(())
+    }
+
+    async fn send_embed(
+        &self,
+        channel_id: u64,
+        embed: &Embed,
+        description: &str,
+        fields: &[Field],
+    ) -> anyhow::Result<u64> {
+        let message = ChannelId::from(channel_id)
+            .send_message(&self.0, |m| {
+                m.embed(|e| build_embed(e, embed, description, fields))
+            })
+            .await?;
+        Ok(*message.id.as_u64())
+    }
+
+    async fn edit_embed(
+        &self,
+        channel_id: u64,
+        message_id: u64,
+        embed: &Embed,
+        description: &str,
+        fields: &[Field],
+    ) -> anyhow::Result<()> {
+        ChannelId::from(channel_id)
+            .edit_message(&self.0, MessageId::from(message_id), |m| {
+                m.embed(|e| build_embed(e, embed, description, fields))
+            })
+            .await
+            .map_err(archived_error)?;
+        Ok(())
+    }
+
+    async fn delete(&self, channel_id: u64, message_id: u64) -> anyhow::Result<()> {
+        ChannelId::from(channel_id)
+            .delete_message(&self.0, MessageId::from(message_id))
+            .await
+            .map_err(archived_error)?;
+        Ok(())
+    }
+
+    async fn react(&self, channel_id: u64, message_id: u64, emoji: char) -> anyhow::Result<()> {
+        ChannelId::from(channel_id)
+            .create_reaction(&self.0, MessageId::from(message_id), emoji)
+            .await?;
+        Ok(())
+    }
+
+    async fn unreact(&self, channel_id: u64, message_id: u64, emoji: char) -> anyhow::Result<()> {
+        ChannelId::from(channel_id)
+            .delete_reaction_emoji(&self.0, MessageId::from(message_id), emoji)
+            .await?;
+        Ok(())
+    }
+
+    async fn fetch(&self, channel_id: u64, message_id: u64) -> anyhow::Result<String> {
+        let message = self.0.get_message(channel_id, message_id).await?;
+        Ok(message.content)
+    }
+
+    async fn create_post(&self, forum_id: u64, title: &str, content: &str) -> anyhow::Result<u64> {
+        #[derive(serde::Deserialize)]
+        struct Thread {
+            id: String,
+        }
+
+        let thread: Thread = self
+            .raw(
+                reqwest::Method::POST,
+                &format!("/channels/{}/threads", forum_id),
+                Some(serde_json::json!({
+                    "name": title,
+                    "message": { "content": content },
+                })),
+            )
+            .await?
+            .json()
+            .await
+            .context("Discord API is not working correctly")?;
+        Ok(thread.id.parse().context("Thread ID is not an integer")?)
+    }
+
+    async fn unarchive(&self, channel_id: u64) -> anyhow::Result<bool> {
+        let channel = self.channel(channel_id).await?;
+        if !channel.archived {
+            return Ok(false);
+        }
+        self.raw(
+            reqwest::Method::PATCH,
+            &format!("/channels/{}", channel_id),
+            Some(serde_json::json!({ "archived": false })),
+        )
+        .await?;
+        Ok(true)
+    }
+
+    async fn channel(&self, channel_id: u64) -> anyhow::Result<Channel> {
+        #[derive(serde::Deserialize)]
+        struct RawChannel {
+            id: String,
+            #[serde(rename = "type")]
+            kind: u64,
+            guild_id: Option<String>,
+            parent_id: Option<String>,
+            thread_metadata: Option<ThreadMetadata>,
+            #[serde(default)]
+            permission_overwrites: Vec<RawOverwrite>,
+        }
+        #[derive(serde::Deserialize)]
+        struct ThreadMetadata {
+            archived: bool,
+        }
+        #[derive(serde::Deserialize)]
+        struct RawOverwrite {
+            id: String,
+            /// 0 for roles, 1 for members
+            #[serde(rename = "type")]
+            kind: u64,
+            allow: String,
+            deny: String,
+        }
+
+        let channel: RawChannel = self.get(&format!("/channels/{}", channel_id)).await?;
+        let overwrites = channel
+            .permission_overwrites
+            .iter()
+            .map(|overwrite| {
+                Ok(permissions::Overwrite {
+                    id: overwrite.id.parse()?,
+                    member: overwrite.kind == 1,
+                    allow: overwrite.allow.parse()?,
+                    deny: overwrite.deny.parse()?,
+                })
+            })
+            .collect::<Result<_, std::num::ParseIntError>>()
+            .context("Permission overwrite is not an integer")?;
+        Ok(Channel {
+            id: channel.id.parse().context("Channel ID is not an integer")?,
+            guild_id: parse_id(channel.guild_id).context("Guild ID is not an integer")?,
+            kind: ChannelKind::from_type(channel.kind),
+            archived: matches!(channel.thread_metadata, Some(metadata) if metadata.archived),
+            parent_id: parse_id(channel.parent_id).context("Parent ID is not an integer")?,
+            overwrites,
+        })
+    }
+
+    async fn guild(&self, guild_id: u64) -> anyhow::Result<permissions::Guild> {
+        #[derive(serde::Deserialize)]
+        struct RawGuild {
+            owner_id: String,
+            roles: Vec<RawRole>,
+        }
+        #[derive(serde::Deserialize)]
+        struct RawRole {
+            id: String,
+            permissions: String,
+        }
+
+        let guild: RawGuild = self.get(&format!("/guilds/{}", guild_id)).await?;
+        let roles = guild
+            .roles
+            .iter()
+            .map(|role| Ok((role.id.parse()?, role.permissions.parse()?)))
+            .collect::<Result<_, std::num::ParseIntError>>()
+            .context("Role is not an integer")?;
+        Ok(permissions::Guild {
+            id: guild_id,
+            owner_id: guild
+                .owner_id
+                .parse()
+                .context("Owner ID is not an integer")?,
+            roles,
+        })
+    }
+
+    async fn member_roles(&self, guild_id: u64, user_id: u64) -> anyhow::Result<Vec<u64>> {
+        #[derive(serde::Deserialize)]
+        struct RawMember {
+            roles: Vec<String>,
+        }
+
+        let member: RawMember = self
+            .get(&format!("/guilds/{}/members/{}", guild_id, user_id))
+            .await?;
+        member
+            .roles
+            .iter()
+            .map(|role| role.parse())
+            .collect::<Result<_, _>>()
+            .context("Role ID is not an integer")
+    }
+
+    async fn current_user_id(&self) -> anyhow::Result<u64> {
+        Ok(*self.0.get_current_user().await?.id.as_u64())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rate_limited(retry_after: Option<&str>) -> reqwest::Response {
+        let mut response = warp::http::Response::builder().status(429);
+        if let Some(retry_after) = retry_after {
+            response = response.header("Retry-After", retry_after);
+        }
+        response.body("").unwrap().into()
+    }
+
+    #[test]
+    fn retry_after_reads_fractional_seconds() {
+        assert_eq!(
+            retry_after(&rate_limited(Some("0.25"))),
+            Duration::from_millis(250)
+        );
+        assert_eq!(retry_after(&rate_limited(None)), Duration::from_secs(1));
+        assert_eq!(
+            retry_after(&rate_limited(Some("-1"))),
+            Duration::from_secs(1)
+        );
+    }
+}
@@ -0,0 +1,31 @@
+//! The health and metrics listener of the bot.
+
+use std::net;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use common::{db, health};
+
+/// Runtime state checked by the readiness endpoint
+#[derive(Default)]
+pub struct Status {
+    pub gateway_connected: AtomicBool,
+}
+
+pub async fn serve(port: u16, conn: db::Conn, status: Arc<Status>) {
+    let conn = Arc::new(conn);
+
+    let routes = health::routes(move || {
+        let conn = Arc::clone(&conn);
+        let status = Arc::clone(&status);
+        async move {
+            health::check_redis(&conn).await?;
+            if !status.gateway_connected.load(Ordering::SeqCst) {
+                return Err("Discord gateway disconnected");
+            }
+            Ok(())
+        }
+    });
+    let addr = net::SocketAddr::from(([0, 0, 0, 0], port));
+    warp::serve(routes).run(addr).await;
+}
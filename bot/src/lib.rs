@@ -0,0 +1,8 @@
+//! The Discord-independent logic of the bot, also used by the integration tests.
+
+pub mod args;
+pub mod chat;
+pub mod lifecycle;
+pub mod mirror;
+pub mod permissions;
+pub mod seen;
@@ -0,0 +1,72 @@
+//! Reacting to repos being archived, renamed, deleted or losing the GitHub App.
+
+use anyhow::Context as _;
+
+use common::db;
+
+use crate::chat::ChatSink;
+use crate::mirror;
+
+/// Applies the lifecycle policy of a mirror group.
+pub async fn handle(
+    chat: &impl ChatSink,
+    conn: &db::Conn,
+    lifecycle: db::Lifecycle,
+) -> anyhow::Result<()> {
+    let channel_id = lifecycle.channel_id;
+
+    if lifecycle.event.is_app_event() {
+        chat.send(
+            channel_id,
+            &format!(
+                "⚠️  {} was {}. The mirrored file in this channel will not be updated \
+                until a repo admin installs the blob-mirror GitHub App \
+                at https://github.com/apps/blob-mirror for this repo again.",
+                &lifecycle.repo_name, lifecycle.event
+            ),
+        )
+        .await?;
+        return Ok(());
+    }
+
+    match lifecycle.policy {
+        db::LifecyclePolicy::Freeze => {
+            // repeated events do not post another banner
+            if !conn
+                .freeze_group(&lifecycle.group)
+                .await
+                .context("Error freezing mirror group")?
+            {
+                return Ok(());
+            }
+            // a separate message, so that the mirrored content is kept in full
+            chat.send(
+                channel_id,
+                &format!(
+                    "🧊 **This mirror is frozen** because {} was {}. \
+                    The content above will no longer be updated.",
+                    &lifecycle.repo_name, lifecycle.event
+                ),
+            )
+            .await?;
+        }
+        db::LifecyclePolicy::Notify => {
+            chat.send(
+                channel_id,
+                &format!(
+                    "⚠️  {} was {}. The mirrored file in this channel will no longer be updated.",
+                    &lifecycle.repo_name, lifecycle.event
+                ),
+            )
+            .await?;
+        }
+        db::LifecyclePolicy::Teardown => {
+            mirror::delete_messages(chat, channel_id, &lifecycle.message_ids).await;
+            conn.delete_group(lifecycle.repo_id, &lifecycle.group)
+                .await
+                .context("Error deleting mirror group")?;
+        }
+    }
+
+    Ok(())
+}
@@ -1,29 +1,80 @@
-use std::cmp;
-use std::convert::TryInto;
 use std::future::Future;
 use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::Context as _;
-use futures::future::{self, FutureExt};
+use futures::future::FutureExt;
+use serenity::client::bridge::gateway::event::ShardStageUpdateEvent;
+use serenity::gateway::ConnectionStage;
 use serenity::model::channel::Message;
 use serenity::model::gateway::{Activity, Ready};
-use serenity::model::id::{ChannelId, MessageId};
 use serenity::prelude::*;
+use tokio::sync::watch;
 
-use common::db;
-use common::secret::Secret;
+use bot::chat::{ChatSink, Serenity};
+use bot::{args, lifecycle, mirror, seen};
+use common::contents::Fetcher;
+use common::secret::{Secret, Service};
+use common::settings::Settings;
+use common::{db, metrics, render, template};
+
+mod health;
+mod throttle;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     pretty_env_logger::init();
 
-    let secret = common::secret::load().context("Failed loading secret file")?;
+    match std::env::args().nth(1).as_deref() {
+        None => {}
+        Some("check-config") => return common::check::run(Service::Bot).await,
+        Some(command) => {
+            anyhow::bail!(
+                "Unknown subcommand {:?}. Usage: bot [check-config]",
+                command
+            )
+        }
+    }
+
+    let secret = common::secret::load(Service::Bot).context("Failed loading secret file")?;
 
     let conn = db::Conn::new(&secret)
         .await
         .context("Failed initializing database")?;
 
+    let settings = common::settings::watch(
+        db::Conn::new(&secret)
+            .await
+            .context("Failed initializing database")?,
+    )
+    .await?;
+
+    let concurrency = Arc::new(throttle::Concurrency::new(settings.borrow().concurrency));
+    {
+        let mut settings = settings.clone();
+        let concurrency = Arc::clone(&concurrency);
+        tokio::spawn(async move {
+            while settings.changed().await.is_ok() {
+                let limit = settings.borrow().concurrency;
+                concurrency.resize(limit);
+            }
+        });
+    }
+
+    let status = Arc::new(health::Status::default());
+    if let Some(port) = secret.bot.health_port {
+        let conn = db::Conn::new(&secret)
+            .await
+            .context("Failed initializing database")?;
+        tokio::spawn(health::serve(port, conn, Arc::clone(&status)));
+    }
+
     let handler = Handler {
+        status,
+        started: AtomicBool::new(false),
+        concurrency,
         // client_id: secret.discord.client_id,
         prefix1: format!("<@!{}>", secret.discord.client_id),
         prefix2: format!("<@{}>", secret.discord.client_id),
@@ -34,9 +85,20 @@ async fn main() -> anyhow::Result<()> {
     };
     log::info!("Invite link: {}", &handler.invite_link);
 
-    let mut client = Client::builder(&secret.discord.token)
+    let token = secret
+        .discord
+        .token
+        .as_ref()
+        .expect("Checked by secret::load")
+        .expose()
+        .to_string();
+    let fetcher = Fetcher::new(&secret.github).context("Failed initializing GitHub client")?;
+    let mut client = Client::builder(&token)
         .type_map_insert::<Data<Secret>>(secret)
         .type_map_insert::<Data<db::Conn>>(conn)
+        .type_map_insert::<Data<SettingsRx>>(settings)
+        .type_map_insert::<Data<throttle::CommandLimiter>>(throttle::CommandLimiter::default())
+        .type_map_insert::<Data<Fetcher>>(fetcher)
         .event_handler(handler)
         .await?;
 
@@ -50,21 +112,51 @@ impl<T: Send + Sync + 'static> TypeMapKey for Data<T> {
     type Value = T;
 }
 
+type SettingsRx = watch::Receiver<Arc<Settings>>;
+
+/// Returns the current runtime settings.
+async fn settings(ctx: &Context) -> Arc<Settings> {
+    let tymap = ctx.data.read().await;
+    let settings = tymap
+        .get::<Data<SettingsRx>>()
+        .expect("Settings uninitialized");
+    let current = Arc::clone(&settings.borrow());
+    current
+}
+
 struct Handler {
+    status: Arc<health::Status>,
+    /// Whether the background tasks were spawned by an earlier `ready` event
+    started: AtomicBool,
+    concurrency: Arc<throttle::Concurrency>,
     // client_id: u64,
     prefix1: String,
     prefix2: String,
     invite_link: String,
 }
 
-const MESSAGE_MAX_LENGTH: usize = serenity::constants::MESSAGE_CODE_LIMIT;
+/// How often to check for expired "not installed" warnings
+const WARNING_EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
 
 #[async_trait::async_trait]
 impl EventHandler for Handler {
+    async fn shard_stage_update(&self, _: Context, event: ShardStageUpdateEvent) {
+        self.status
+            .gateway_connected
+            .store(event.new == ConnectionStage::Connected, Ordering::SeqCst);
+    }
+
     async fn ready(&self, ctx: Context, _: Ready) {
+        self.status.gateway_connected.store(true, Ordering::SeqCst);
+
         ctx.set_activity(Activity::playing("https://github.com/SOF3/blob-mirror"))
             .await;
 
+        // serenity fires `ready` again on every reconnect
+        if self.started.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
         let mut conn = {
             let data = ctx.data.read().await;
             let secret = data.get::<Data<Secret>>().expect("Secret uninitialized");
@@ -73,12 +165,26 @@ impl EventHandler for Handler {
         };
         {
             let ctx = ctx.clone();
+            let concurrency = Arc::clone(&self.concurrency);
             tokio::spawn(async move {
                 while let Some(update) = conn.recv().await {
-                    tokio::spawn(handle_update(update, ctx.clone()).map(|result| {
-                        if let Err(err) = result {
-                            log::error!("Error dispatching update: {}", err);
-                        }
+                    if update.published_at != 0 {
+                        let lag = metrics::unix_millis().saturating_sub(update.published_at);
+                        metrics::PUBSUB_LAG_SECONDS.observe(lag as f64 / 1000.);
+                    }
+                    let permit = concurrency.acquire().await;
+                    tokio::spawn(handle_update(update, ctx.clone()).map(move |result| {
+                        drop(permit);
+                        let outcome = match result {
+                            Ok(()) => "ok",
+                            Err(err) => {
+                                log::error!("Error dispatching update: {}", err);
+                                "error"
+                            }
+                        };
+                        metrics::UPDATES_PROCESSED
+                            .with_label_values(&[outcome])
+                            .inc();
                     }));
                 }
             });
@@ -102,6 +208,57 @@ impl EventHandler for Handler {
                 }
             });
         }
+
+        {
+            let ctx = ctx.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(WARNING_EXPIRY_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    if let Err(err) = abandon_expired_warnings(&ctx).await {
+                        log::error!("Error abandoning expired warnings: {:?}", err);
+                    }
+                }
+            });
+        }
+
+        let mut conn = {
+            let data = ctx.data.read().await;
+            let secret = data.get::<Data<Secret>>().expect("Secret uninitialized");
+            common::db::subscriber::<db::Command>(secret, "commands")
+                .expect("Failed to initialize database connection")
+        };
+        {
+            let ctx = ctx.clone();
+            tokio::spawn(async move {
+                while let Some(command) = conn.recv().await {
+                    tokio::spawn(handle_command(command, ctx.clone()).map(|result| {
+                        if let Err(err) = result {
+                            log::error!("Error dispatching command: {}", err);
+                        }
+                    }));
+                }
+            });
+        }
+
+        let mut conn = {
+            let data = ctx.data.read().await;
+            let secret = data.get::<Data<Secret>>().expect("Secret uninitialized");
+            common::db::subscriber::<db::Lifecycle>(secret, "lifecycle")
+                .expect("Failed to initialize database connection")
+        };
+        {
+            let ctx = ctx.clone();
+            tokio::spawn(async move {
+                while let Some(lifecycle) = conn.recv().await {
+                    tokio::spawn(handle_lifecycle(lifecycle, ctx.clone()).map(|result| {
+                        if let Err(err) = result {
+                            log::error!("Error dispatching lifecycle: {}", err);
+                        }
+                    }));
+                }
+            });
+        }
     }
 
     async fn message(&self, ctx: Context, msg: Message) {
@@ -116,9 +273,8 @@ impl EventHandler for Handler {
 
             log::debug!("Received command: {}", content.trim());
             let reaction = msg.react(&ctx, '⏳').await;
-            let mut args = content
-                .split(char::is_whitespace)
-                .filter(|str| !str.is_empty());
+            let args = args::split(content);
+            let mut args = args.iter().map(String::as_str);
 
             let ret = match args.next() {
                 Some("invite") => {
@@ -160,204 +316,194 @@ async fn mirror_cmd(
     msg: &Message,
     mut args: impl Iterator<Item = &str>,
 ) -> anyhow::Result<()> {
-    let url = args
-        .next()
-        .context("Usage: `mirror <url> [message splits]`")?;
-    let mut pages = match args.next() {
-        Some(pages) => pages
-            .parse::<usize>()
-            .context("Usage: `mirror <url> [message splits]`")?,
-        None => 1,
-    };
-
-    struct UrlInfo<T: AsRef<str>> {
-        user: T,
-        repo: T,
-        path: T,
-    }
-
-    fn parse_url(url: &str) -> Option<UrlInfo<&str>> {
-        if let Some(url) = url.strip_prefix("https://github.com/") {
-            let mut split = url.splitn(4, '/');
-            let user = split.next()?;
-            let repo = split.next()?;
-            let _ = split.next()?;
-            let path = split.next()?;
-
-            Some(UrlInfo { user, repo, path })
-        } else if let Some(url) = url.strip_prefix("https://raw.githubusercontent.com/") {
-            let mut split = url.splitn(3, '/');
-            let user = split.next()?;
-            let repo = split.next()?;
-            let path = split.next()?;
-
-            Some(UrlInfo { user, repo, path })
-        } else {
-            None
+    const USAGE: &str = "Usage: `mirror <url> [#channel] [split=<message count>|sections] \
+        [lifecycle=freeze|notify|teardown] [mode=text|embed|fields] \
+        [format=raw|json|yaml|toml|markdown|attachment] [select=/json/pointer] \
+        [header=\"<template>\"] [footer=\"<template>\"]`";
+
+    let settings = settings(ctx).await;
+
+    let url = args.next().context(USAGE)?;
+    let mut options = mirror::Options::new(&settings);
+    let mut target_channel = None;
+    for arg in args {
+        if let Some(channel_id) = args::channel_mention(arg) {
+            anyhow::ensure!(target_channel.is_none(), USAGE);
+            target_channel = Some(channel_id);
+            continue;
+        }
+        let mut split = arg.splitn(2, '=');
+        match (split.next(), split.next()) {
+            (Some("lifecycle"), Some(value)) => options.lifecycle = value.parse().context(USAGE)?,
+            (Some("mode"), Some(value)) => options.mode = value.parse().context(USAGE)?,
+            (Some("format"), Some(value)) => options.format = Some(value.parse().context(USAGE)?),
+            (Some("select"), Some(value)) => options.select = Some(value.to_string()),
+            (Some("header"), Some(value)) => options.frame.header = value.to_string(),
+            (Some("footer"), Some(value)) => options.frame.footer = value.to_string(),
+            (Some("split"), Some(value)) => match value.parse::<usize>() {
+                Ok(pages) => {
+                    options.pages = pages;
+                    options.split = render::Split::Pages;
+                }
+                Err(_) => options.split = value.parse().context(USAGE)?,
+            },
+            _ => anyhow::bail!(USAGE),
         }
     }
 
-    let info = parse_url(url).context("The URL must be a file on GitHub repo.")?;
-
-    let channel = msg
-        .channel_id
-        .to_channel(ctx)
+    let chat = Serenity(Arc::clone(&ctx.http));
+    let command_channel_id = *msg.channel_id.as_u64();
+    let channel = chat
+        .channel(command_channel_id)
         .await
-        .context("blob-mirror is only usable in guild channels")?
-        .guild()
         .context("blob-mirror is only usable in guild channels")?;
-
-    let real_url = format!(
-        "https://raw.githubusercontent.com/{}/{}/{}",
-        info.user, info.repo, info.path
+    let guild_id = channel
+        .guild_id
+        .context("blob-mirror is only usable in guild channels")?;
+    anyhow::ensure!(
+        settings.allows_guild(guild_id),
+        "This server is not allowed to create mirrors"
     );
-    let text = reqwest::get(&real_url)
-        .await
-        .context("Failed to fetch file")?
-        .text()
-        .await
-        .context("The file is not valid UTF-8")?;
-
-    #[derive(serde::Deserialize)]
-    struct GhRepo {
-        id: u64,
-    }
-
-    let client = reqwest::Client::new();
-    let gh_repo = client
-        .get(&format!(
-            "https://api.github.com/repos/{}/{}",
-            info.user, info.repo
-        ))
-        .header("User-Agent", "blob-mirror/v0.1")
-        .send()
-        .await
-        .context("Failed to lookup repo")?
-        .json::<GhRepo>()
-        .await
-        .context("GitHub API is not working correctly")?;
-    let repo_id = gh_repo.id;
-
-    pages = cmp::max(text.len() / MESSAGE_MAX_LENGTH + 1, pages);
-
-    let mut message_ids = Vec::with_capacity(pages);
-    for i in 0..pages {
-        let range = (MESSAGE_MAX_LENGTH * i)..cmp::min(MESSAGE_MAX_LENGTH * (i + 1), text.len());
-        let slice = text
-            .get(range)
-            .unwrap_or("*(message reserved for expansion)*");
-        let message = channel.send_message(ctx, |m| m.content(slice)).await?;
-        message_ids.push(*message.id.as_u64());
+    {
+        let tymap = ctx.data.read().await;
+        let limiter = tymap
+            .get::<Data<throttle::CommandLimiter>>()
+            .expect("CommandLimiter uninitialized");
+        anyhow::ensure!(
+            limiter.try_acquire(guild_id, settings.commands_per_minute),
+            "Too many mirror commands in this server, please try again in a minute"
+        );
     }
 
-    let channel_id = *msg.channel_id.as_u64();
-
-    let seen = {
-        let tymap = ctx.data.read().await;
-        let conn = tymap.get::<Data<db::Conn>>().expect("Conn uninitialized");
-        if let Err(err) = conn
-            .add_update(repo_id, info.path, channel_id, &message_ids)
-            .await
-        {
-            log::error!("Error storing message group: {}", err);
-            anyhow::bail!("Error storing message group");
-        }
-        conn.is_seen(repo_id).await
+    let author = mirror::Author {
+        id: *msg.author.id.as_u64(),
+        roles: msg
+            .member
+            .as_ref()
+            .map(|member| member.roles.iter().map(|role| *role.as_u64()).collect()),
     };
+    let target = mirror::resolve_target(&chat, &channel, target_channel, &author, &options).await?;
+
+    let mirror = create_mirror(ctx, target, url, options).await?;
+    mirror::confirm(&chat, command_channel_id, url, &mirror).await?;
+
+    let tymap = ctx.data.read().await;
+    let conn = tymap.get::<Data<db::Conn>>().expect("Conn uninitialized");
+    let limits = &tymap
+        .get::<Data<Secret>>()
+        .expect("Secret uninitialized")
+        .bot
+        .on_seen;
+    seen::warn(
+        &chat,
+        conn,
+        limits,
+        mirror,
+        command_channel_id,
+        *msg.id.as_u64(),
+    )
+    .await
+}
 
-    match seen {
-        Ok(true) => (),
-        Ok(false) => {
-            let heard = msg
-                .reply(
-                    ctx,
-                    &format!(
-                        "⚠️  I have never heard from this repo ({}/{}). \
-                    Please contact the repo admin to install the blob-mirror GitHub App \
-                    at https://github.com/apps/blob-mirror for this repo.\n\
-                    This message will be deleted when I hear from the repo.",
-                        info.user, info.repo
-                    ),
-                )
-                .await?;
-            msg.react(&ctx, '🙈').await?;
-            {
-                let tymap = ctx.data.read().await;
-                let conn = tymap.get::<Data<db::Conn>>().expect("Conn uninitialized");
-                if let Err(err) = conn
-                    .delete_on_seen(repo_id, *heard.channel_id.as_u64(), *heard.id.as_u64())
-                    .await
-                {
-                    log::error!("Error scheduling seen message deletion: {:?}", err);
-                }
-                if let Err(err) = conn
-                    .dereact_on_seen(repo_id, *msg.channel_id.as_u64(), *msg.id.as_u64())
-                    .await
-                {
-                    log::error!("Error scheduling seen message deletion: {:?}", err);
-                }
-            }
+/// Posts a new mirror group in a guild channel, thread or forum.
+async fn create_mirror(
+    ctx: &Context,
+    target: mirror::Target,
+    url: &str,
+    options: mirror::Options,
+) -> anyhow::Result<mirror::Mirror> {
+    let tymap = ctx.data.read().await;
+    let conn = tymap.get::<Data<db::Conn>>().expect("Conn uninitialized");
+    let secret = tymap.get::<Data<Secret>>().expect("Secret uninitialized");
+    let fetcher = tymap.get::<Data<Fetcher>>().expect("Fetcher uninitialized");
+    mirror::create(
+        &Serenity(Arc::clone(&ctx.http)),
+        conn,
+        fetcher,
+        &secret.github,
+        target,
+        url,
+        options,
+    )
+    .await
+}
+
+async fn handle_command(command: db::Command, ctx: Context) -> anyhow::Result<()> {
+    match command {
+        db::Command::Mirror {
+            guild_id,
+            channel_id,
+            url,
+            pages,
+            split,
+            lifecycle,
+            mode,
+            format,
+            select,
+            header,
+            footer,
+        } => {
+            let chat = Serenity(Arc::clone(&ctx.http));
+            let channel = chat
+                .channel(channel_id)
+                .await
+                .context("Cannot access the mirror channel")?;
+            let target = mirror::Target::new(&channel)?;
+            anyhow::ensure!(
+                target.guild_id == guild_id,
+                "The mirror channel is not in the requested guild"
+            );
+            let settings = settings(&ctx).await;
+            anyhow::ensure!(
+                settings.allows_guild(guild_id),
+                "Guild {} is not allowed to create mirrors",
+                guild_id
+            );
+            let options = mirror::Options {
+                pages,
+                split,
+                lifecycle,
+                mode: mode.unwrap_or(settings.default_render_mode),
+                format,
+                select,
+                frame: template::Frame { header, footer },
+                max_messages: settings.max_messages,
+            };
+            // before posting anything, so that no forum post is left half-created
+            mirror::check_target(&chat, &channel, None, &options).await?;
+            create_mirror(&ctx, target, &url, options).await?;
         }
-        Err(err) => {
-            log::error!("Error checking seen status: {:?}", err);
+        db::Command::Unmirror { repo_id, group } => {
+            let tymap = ctx.data.read().await;
+            let conn = tymap.get::<Data<db::Conn>>().expect("Conn uninitialized");
+            mirror::delete(&Serenity(Arc::clone(&ctx.http)), conn, repo_id, &group).await?;
         }
     }
 
     Ok(())
 }
 
-async fn handle_update(update: db::Update, ctx: Context) -> anyhow::Result<()> {
-    let resp = reqwest::get(&update.url)
-        .await
-        .context("Failed to download file")?;
-    let mut text: String = resp.text().await.context("The file is not valid UTF-8")?;
-
-    let max_len = update.message_ids.len() * MESSAGE_MAX_LENGTH;
-    if max_len < text.len() {
-        let err = format!("\u{2026}\nSee <{}> for more", &update.url);
-        text.truncate(max_len - err.len());
-        text += &err;
-    }
+async fn abandon_expired_warnings(ctx: &Context) -> anyhow::Result<()> {
+    let tymap = ctx.data.read().await;
+    let conn = tymap.get::<Data<db::Conn>>().expect("Conn uninitialized");
+    seen::abandon_expired_warnings(&Serenity(Arc::clone(&ctx.http)), conn).await
+}
 
-    let channel = ChannelId::from(update.channel_id);
-    for (i, message) in update.message_ids.iter().enumerate() {
-        let range = (MESSAGE_MAX_LENGTH * i)..cmp::min(MESSAGE_MAX_LENGTH * (i + 1), text.len());
-        let slice = text
-            .get(range)
-            .unwrap_or("*(message reserved for expansion)*");
-        let message = MessageId::from(*message);
-        let mut message = channel.message(&ctx, message).await?;
-        message.edit(&ctx, |m| m.content(slice)).await?;
-    }
+async fn handle_update(update: db::Update, ctx: Context) -> anyhow::Result<()> {
+    let tymap = ctx.data.read().await;
+    let conn = tymap.get::<Data<db::Conn>>().expect("Conn uninitialized");
+    let fetcher = tymap.get::<Data<Fetcher>>().expect("Fetcher uninitialized");
+    mirror::update(&Serenity(Arc::clone(&ctx.http)), conn, fetcher, update).await
+}
 
-    Ok(())
+async fn handle_lifecycle(lifecycle: db::Lifecycle, ctx: Context) -> anyhow::Result<()> {
+    let tymap = ctx.data.read().await;
+    let conn = tymap.get::<Data<db::Conn>>().expect("Conn uninitialized");
+    lifecycle::handle(&Serenity(Arc::clone(&ctx.http)), conn, lifecycle).await
 }
 
 async fn handle_on_seen(on_seen: db::OnSeen, ctx: Context) -> anyhow::Result<()> {
-    let ctx = &ctx;
-    let deletions = on_seen.deletions.chunks_exact(2).map(|pair| {
-        let [channel_id, message_id]: [u64; 2] = pair.try_into().expect("chunks_exact(2)");
-        async move {
-            let channel_id = ChannelId::from(channel_id);
-            let message_id = MessageId::from(message_id);
-            if let Ok(message) = channel_id.message(&ctx, message_id).await {
-                let _ = message.delete(&ctx).await; // doesn't matter if we can't delete
-            }
-        }
-    });
-    let dereacts = on_seen.dereacts.chunks_exact(2).map(|pair| {
-        let [channel_id, message_id]: [u64; 2] = pair.try_into().expect("chunks_exact(2)");
-        async move {
-            let channel_id = ChannelId::from(channel_id);
-            let message_id = MessageId::from(message_id);
-            if let Ok(message) = channel_id.message(&ctx, message_id).await {
-                let _ = message.delete_reaction_emoji(&ctx, '🙈').await; // doesn't matter if we can't delete
-            }
-        }
-    });
-
-    future::join(future::join_all(deletions), future::join_all(dereacts)).await;
-
+    seen::handle_on_seen(&Serenity(Arc::clone(&ctx.http)), on_seen).await;
     Ok(())
 }
 
@@ -0,0 +1,664 @@
+//! Creating mirror groups and rendering updates into them.
+
+use std::cmp;
+
+use anyhow::Context as _;
+
+use common::attachment::Attachment;
+use common::contents::{Blob, Fetcher};
+use common::settings::Settings;
+use common::{db, format, metrics, render, secret, template};
+
+use crate::chat::{self, ChatSink, ThreadArchived};
+use crate::permissions;
+
+pub use common::render::MESSAGE_MAX_LENGTH;
+
+/// Maximum length of a thread name in characters
+const THREAD_NAME_MAX_LENGTH: usize = 100;
+
+/// The channel to post a new mirror group in
+#[derive(Debug, Clone, Copy)]
+pub struct Target {
+    pub guild_id: u64,
+    pub channel_id: u64,
+    /// Whether `channel_id` is a forum channel, in which a post is created for the group
+    pub forum: bool,
+}
+
+impl Target {
+    /// Returns the target of mirrors posted in a channel.
+    pub fn new(channel: &chat::Channel) -> anyhow::Result<Self> {
+        let guild_id = channel
+            .guild_id
+            .context("blob-mirror is only usable in guild channels")?;
+        let kind = channel
+            .kind
+            .context("Mirrors can only be posted in text channels, threads and forums")?;
+        Ok(Self {
+            guild_id,
+            channel_id: channel.id,
+            forum: kind == chat::ChannelKind::Forum,
+        })
+    }
+}
+
+/// The options of a new mirror group
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Minimum number of messages with `render::Split::Pages`;
+    /// more are posted if the file does not fit
+    pub pages: usize,
+    pub split: render::Split,
+    pub lifecycle: db::LifecyclePolicy,
+    pub mode: render::Mode,
+    /// The file format; detected from the extension if `None`
+    pub format: Option<format::Format>,
+    /// The sub-tree of a structured file to show
+    pub select: Option<String>,
+    /// The header and footer templates of each page
+    pub frame: template::Frame,
+    /// Maximum number of messages; the file is truncated beyond it
+    pub max_messages: usize,
+}
+
+impl Options {
+    /// Returns the options of a `mirror` command that only specifies the URL.
+    pub fn new(settings: &Settings) -> Self {
+        Self {
+            pages: settings.default_pages,
+            split: render::Split::Pages,
+            lifecycle: db::LifecyclePolicy::default(),
+            mode: settings.default_render_mode,
+            format: None,
+            select: None,
+            frame: template::Frame::default(),
+            max_messages: settings.max_messages,
+        }
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self::new(&Settings::default())
+    }
+}
+
+/// A newly created mirror group
+pub struct Mirror {
+    pub repo_id: u64,
+    pub group: String,
+    /// The channel the messages were posted in, which is the new post for forum targets
+    pub channel_id: u64,
+    pub user: String,
+    pub repo: String,
+}
+
+struct UrlInfo<T: AsRef<str>> {
+    user: T,
+    repo: T,
+    path: T,
+}
+
+/// Parses a link to a file on the web interface or the raw host of `github`.
+fn parse_url<'t>(github: &secret::Github, url: &'t str) -> Option<UrlInfo<&'t str>> {
+    fn strip_base<'t>(url: &'t str, base: &str) -> Option<&'t str> {
+        url.strip_prefix(base.trim_end_matches('/'))?
+            .strip_prefix('/')
+    }
+
+    if let Some(url) = strip_base(url, &github.web_url) {
+        let mut split = url.splitn(4, '/');
+        let user = split.next()?;
+        let repo = split.next()?;
+        match split.next()? {
+            "blob" | "raw" => {}
+            _ => return None,
+        }
+        let path = split.next()?;
+
+        Some(UrlInfo { user, repo, path })
+    } else if let Some(url) = strip_base(url, &github.raw_url) {
+        let mut split = url.splitn(3, '/');
+        let user = split.next()?;
+        let repo = split.next()?;
+        let path = split.next()?;
+
+        Some(UrlInfo { user, repo, path })
+    } else {
+        None
+    }
+}
+
+/// The user that issued a `mirror` command
+#[derive(Debug, Clone)]
+pub struct Author {
+    pub id: u64,
+    /// The roles of the author if they came with the command; looked up otherwise
+    pub roles: Option<Vec<u64>>,
+}
+
+/// Returns where a `mirror` command issued in `command_channel` posts the mirror,
+/// which is `target_channel` if it is given.
+///
+/// Other channels than the one of the command must be in the same guild
+/// and pass `check_target`.
+pub async fn resolve_target(
+    chat: &impl ChatSink,
+    command_channel: &chat::Channel,
+    target_channel: Option<u64>,
+    author: &Author,
+    options: &Options,
+) -> anyhow::Result<Target> {
+    match target_channel {
+        Some(channel_id) if channel_id != command_channel.id => {
+            let channel = chat
+                .channel(channel_id)
+                .await
+                .context("Cannot access the target channel")?;
+            anyhow::ensure!(
+                channel.guild_id.is_some() && channel.guild_id == command_channel.guild_id,
+                "The target channel is not in this server"
+            );
+            check_target(chat, &channel, Some(author), options).await?;
+            Target::new(&channel)
+        }
+        _ => Target::new(command_channel),
+    }
+}
+
+/// Ensures that blob-mirror can post a mirror in `channel`,
+/// and that the author of the command, if any, can manage the messages of that channel.
+///
+/// Commands from the dashboard have no author,
+/// since the dashboard already checked that the user can manage the server.
+pub async fn check_target(
+    chat: &impl ChatSink,
+    channel: &chat::Channel,
+    author: Option<&Author>,
+    options: &Options,
+) -> anyhow::Result<()> {
+    let guild_id = channel
+        .guild_id
+        .context("blob-mirror is only usable in guild channels")?;
+    let kind = channel
+        .kind
+        .context("Mirrors can only be posted in text channels, threads and forums")?;
+
+    let parent;
+    let overwrites = match channel.parent_id {
+        Some(parent_id) if kind == chat::ChannelKind::Thread => {
+            parent = chat
+                .channel(parent_id)
+                .await
+                .context("Cannot access the parent of the target thread")?;
+            &parent.overwrites
+        }
+        _ => &channel.overwrites,
+    };
+    let guild = chat
+        .guild(guild_id)
+        .await
+        .context("Cannot access the server roles")?;
+
+    let mut required = permissions::VIEW_CHANNEL
+        | match kind {
+            chat::ChannelKind::Text => permissions::SEND_MESSAGES,
+            chat::ChannelKind::Thread => permissions::SEND_MESSAGES_IN_THREADS,
+            chat::ChannelKind::Forum => {
+                permissions::SEND_MESSAGES | permissions::SEND_MESSAGES_IN_THREADS
+            }
+        };
+    if options.mode != render::Mode::Text {
+        required |= permissions::EMBED_LINKS;
+    }
+    if options.format == Some(format::Format::Attachment) {
+        required |= permissions::ATTACH_FILES;
+    }
+    let bot_id = chat.current_user_id().await?;
+    let bot_roles = chat.member_roles(guild_id, bot_id).await?;
+    permissions::ensure(
+        guild.permissions(overwrites, bot_id, &bot_roles),
+        required,
+        "blob-mirror is",
+    )?;
+
+    let author = match author {
+        Some(author) => author,
+        None => return Ok(()),
+    };
+    let author_roles = match &author.roles {
+        Some(roles) => roles.clone(),
+        None => chat.member_roles(guild_id, author.id).await?,
+    };
+    permissions::ensure(
+        guild.permissions(overwrites, author.id, &author_roles),
+        permissions::VIEW_CHANNEL | permissions::MANAGE_MESSAGES,
+        "You are",
+    )
+}
+
+/// Tells the channel of a `mirror` command where the mirror was posted, if it is elsewhere.
+pub async fn confirm(
+    chat: &impl ChatSink,
+    command_channel_id: u64,
+    url: &str,
+    mirror: &Mirror,
+) -> anyhow::Result<()> {
+    if mirror.channel_id != command_channel_id {
+        chat.send(
+            command_channel_id,
+            &format!("Mirroring <{}> in <#{}>", url, mirror.channel_id),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Posts the messages of a new mirror group in `target` and stores the group.
+pub async fn create(
+    chat: &impl ChatSink,
+    conn: &db::Conn,
+    fetcher: &Fetcher,
+    github: &secret::Github,
+    target: Target,
+    url: &str,
+    options: Options,
+) -> anyhow::Result<Mirror> {
+    let info = parse_url(github, url).context("The URL must be a file on GitHub repo.")?;
+    options.frame.validate()?;
+    anyhow::ensure!(
+        options.pages <= options.max_messages,
+        "A mirror can have at most {} messages",
+        options.max_messages
+    );
+    let repo_name = format!("{}/{}", info.user, info.repo);
+
+    let installation_id = fetcher
+        .installation(&repo_name)
+        .await
+        .context("Failed to lookup the GitHub App installation")?;
+    let file = fetcher
+        .fetch_blob(
+            &github.branch_contents_url(&repo_name, info.path),
+            installation_id,
+        )
+        .await
+        .context("Failed to fetch file")?;
+
+    let repo_id = fetcher
+        .repo(&github.repo_url(&repo_name), installation_id)
+        .await
+        .context("Failed to lookup repo")?
+        .id;
+
+    let blob_url = github.blob_url(&repo_name, info.path);
+    let embed = options
+        .mode
+        .embed(repo_id, &repo_name, info.path, &blob_url, None);
+    let format = match file.blob {
+        // binary files can only be attached
+        Blob::Binary(_) => format::Format::Attachment,
+        Blob::Text(_) => options
+            .format
+            .unwrap_or_else(|| format::Format::detect(info.path)),
+    };
+    let spec = format::Spec {
+        format,
+        select: options.select,
+    };
+    let vars = template::Vars::new(&repo_name, info.path, None, None);
+    let layout = format::Layout {
+        spec: &spec,
+        embed: embed.as_ref(),
+        frame: &options.frame,
+        vars: &vars,
+        url: &blob_url,
+    };
+    let channel_id = if target.forum {
+        let title: String = vars.path.chars().take(THREAD_NAME_MAX_LENGTH).collect();
+        chat.create_post(
+            target.channel_id,
+            &title,
+            &format!("Mirror of <{}>", &blob_url),
+        )
+        .await
+        .context("Failed to create forum post")?
+    } else {
+        target.channel_id
+    };
+
+    let mut blob = None;
+    let message_ids = match &file.blob {
+        Blob::Text(text) if format != format::Format::Attachment => {
+            let mut pages = format::page_count(text, &layout);
+            if options.split == render::Split::Pages {
+                pages = cmp::max(pages, options.pages);
+            }
+            // longer files are truncated with a link to the file
+            pages = cmp::min(pages, options.max_messages);
+            let rendered = format::pages(text, &layout, pages);
+
+            let mut message_ids = Vec::with_capacity(pages);
+            for (index, page) in rendered.pages.iter().enumerate() {
+                let fields = rendered.fields.get(index).map_or(&[][..], Vec::as_slice);
+                message_ids.push(send_page(chat, channel_id, embed.as_ref(), page, fields).await?);
+            }
+            message_ids
+        }
+        _ => {
+            let attachment = Attachment::new(info.path, file.blob.as_bytes(), &file.sha);
+            attachment.check_size()?;
+            let message = chat
+                .send_file(
+                    channel_id,
+                    &attachment.caption(&layout),
+                    &attachment.filename,
+                    file.blob.as_bytes(),
+                )
+                .await?;
+            blob = Some(file.sha.as_str());
+            vec![message]
+        }
+    };
+
+    let group = match conn
+        .add_update(&db::NewGroup {
+            repo_id,
+            repo_name: &repo_name,
+            path: info.path,
+            guild_id: target.guild_id,
+            channel_id,
+            message_ids: &message_ids,
+            lifecycle: options.lifecycle,
+            mode: options.mode,
+            format: &spec,
+            split: options.split,
+            frame: &options.frame,
+            blob,
+        })
+        .await
+    {
+        Ok(group) => group,
+        Err(err) => {
+            log::error!("Error storing message group: {}", err);
+            anyhow::bail!("Error storing message group");
+        }
+    };
+
+    Ok(Mirror {
+        repo_id,
+        group,
+        channel_id,
+        user: info.user.to_string(),
+        repo: info.repo.to_string(),
+    })
+}
+
+/// Posts a page as a message or an embed, returning the message ID.
+async fn send_page(
+    chat: &impl ChatSink,
+    channel_id: u64,
+    embed: Option<&render::Embed>,
+    page: &str,
+    fields: &[render::Field],
+) -> anyhow::Result<u64> {
+    match embed {
+        Some(embed) => chat.send_embed(channel_id, embed, page, fields).await,
+        None => chat.send(channel_id, page).await,
+    }
+}
+
+/// Replaces a page in a message or an embed.
+///
+/// Messages in archived threads cannot be edited,
+/// so the thread is unarchived and the edit retried once if it fails with `ThreadArchived`.
+pub(crate) async fn edit_page(
+    chat: &impl ChatSink,
+    channel_id: u64,
+    message_id: u64,
+    embed: Option<&render::Embed>,
+    page: &str,
+    fields: &[render::Field],
+) -> anyhow::Result<()> {
+    let edit = || async move {
+        match embed {
+            Some(embed) => {
+                chat.edit_embed(channel_id, message_id, embed, page, fields)
+                    .await
+            }
+            None => chat.edit(channel_id, message_id, page).await,
+        }
+    };
+    match edit().await {
+        Err(err) if err.is::<ThreadArchived>() && unarchive(chat, channel_id).await => edit().await,
+        result => result,
+    }
+}
+
+/// Unarchives a thread, returning whether it was archived.
+async fn unarchive(chat: &impl ChatSink, channel_id: u64) -> bool {
+    match chat.unarchive(channel_id).await {
+        Ok(unarchived) => unarchived,
+        Err(err) => {
+            log::warn!("Error unarchiving channel {}: {:?}", channel_id, err);
+            false
+        }
+    }
+}
+
+/// Writes the pages rendered by the web service into a mirror group.
+///
+/// Groups split by sections get messages added or removed at the end
+/// to match the number of pages.
+/// Attachments are downloaded through `fetcher`.
+pub async fn update(
+    chat: &impl ChatSink,
+    conn: &db::Conn,
+    fetcher: &Fetcher,
+    update: db::Update,
+) -> anyhow::Result<()> {
+    if update.format.format == format::Format::Attachment {
+        return update_attachment(chat, conn, fetcher, update).await;
+    }
+
+    let message_ids = match update.split {
+        // the bot may have resized the group since the update was published
+        render::Split::Sections => conn.group(&update.group).await?.message_ids,
+        render::Split::Pages => {
+            anyhow::ensure!(
+                update.pages.len() == update.message_ids.len(),
+                "Update of group {} has {} pages for {} messages; it may have been published by an older web service",
+                &update.group,
+                update.pages.len(),
+                update.message_ids.len()
+            );
+            update.message_ids.clone()
+        }
+    };
+    let fields = |index: usize| update.fields.get(index).map_or(&[][..], Vec::as_slice);
+
+    for (index, (&message, page)) in message_ids.iter().zip(&update.pages).enumerate() {
+        let timer = metrics::DISCORD_EDIT_SECONDS.start_timer();
+        let result = edit_page(
+            chat,
+            update.channel_id,
+            message,
+            update.embed.as_ref(),
+            page,
+            fields(index),
+        )
+        .await;
+        timer.observe_duration();
+        if result.is_err() {
+            metrics::DISCORD_EDIT_FAILURES.inc();
+        }
+        result?;
+    }
+
+    if update.pages.len() > message_ids.len() {
+        let mut added = Vec::new();
+        for (index, page) in update.pages.iter().enumerate().skip(message_ids.len()) {
+            let sent = send_page(
+                chat,
+                update.channel_id,
+                update.embed.as_ref(),
+                page,
+                fields(index),
+            )
+            .await;
+            match sent {
+                Ok(message) => added.push(message),
+                Err(err) => {
+                    // keep track of the messages already posted
+                    conn.push_group_messages(&update.group, &added).await?;
+                    return Err(err);
+                }
+            }
+        }
+        conn.push_group_messages(&update.group, &added).await?;
+    } else if update.pages.len() < message_ids.len() {
+        let removed = &message_ids[update.pages.len()..];
+        conn.pop_group_messages(&update.group, removed).await?;
+        delete_messages(chat, update.channel_id, removed).await;
+    }
+
+    conn.mark_group_updated(&update.group).await?;
+
+    Ok(())
+}
+
+/// Re-posts the attachment of a mirror group if the file changed, or edits its caption otherwise.
+///
+/// Attachments cannot be edited, so the new message replaces the old one in the group.
+async fn update_attachment(
+    chat: &impl ChatSink,
+    conn: &db::Conn,
+    fetcher: &Fetcher,
+    update: db::Update,
+) -> anyhow::Result<()> {
+    let attachment = update.attachment.as_ref().with_context(|| {
+        format!(
+            "Update of group {} has no attachment; it may have been published by an older web service",
+            &update.group
+        )
+    })?;
+    let caption = update.pages.first().map_or("", String::as_str);
+    let group = conn.group(&update.group).await?;
+
+    if group.blob.as_deref() == Some(attachment.sha.as_str()) {
+        for &message in &group.message_ids {
+            edit_page(chat, update.channel_id, message, None, caption, &[]).await?;
+        }
+    } else {
+        let message = if attachment.fits() {
+            let file = fetcher
+                .fetch_blob(&update.contents_url, update.installation_id)
+                .await
+                .context("Failed to fetch file")?;
+            chat.send_file(
+                update.channel_id,
+                caption,
+                &attachment.filename,
+                file.blob.as_bytes(),
+            )
+            .await?
+        } else {
+            chat.send(update.channel_id, caption).await?
+        };
+        conn.pop_group_messages(&update.group, &group.message_ids)
+            .await?;
+        conn.push_group_messages(&update.group, &[message]).await?;
+        conn.set_group_blob(&update.group, &attachment.sha).await?;
+        delete_messages(chat, update.channel_id, &group.message_ids).await;
+    }
+
+    conn.mark_group_updated(&update.group).await?;
+
+    Ok(())
+}
+
+/// Deletes the messages of a mirror group and the group itself.
+pub async fn delete(
+    chat: &impl ChatSink,
+    conn: &db::Conn,
+    repo_id: u64,
+    group: &str,
+) -> anyhow::Result<()> {
+    let info = conn.group(group).await?;
+    delete_messages(chat, info.channel_id, &info.message_ids).await;
+    conn.delete_group(repo_id, group)
+        .await
+        .context("Error deleting mirror group")
+}
+
+/// Deletes messages, ignoring those that were already deleted manually.
+pub(crate) async fn delete_messages(chat: &impl ChatSink, channel_id: u64, message_ids: &[u64]) {
+    let mut retried = false;
+    for &message in message_ids {
+        // doesn't matter if the message was already deleted manually
+        match chat.delete(channel_id, message).await {
+            Err(err) if err.is::<ThreadArchived>() && !retried => {
+                retried = true;
+                if unarchive(chat, channel_id).await {
+                    let _ = chat.delete(channel_id, message).await;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse<'t>(github: &secret::Github, url: &'t str) -> Option<(&'t str, &'t str, &'t str)> {
+        parse_url(github, url).map(|info| (info.user, info.repo, info.path))
+    }
+
+    #[test]
+    fn parse_url_recognises_configured_hosts() {
+        let github = secret::Github {
+            web_url: String::from("https://ghes.example.com"),
+            raw_url: String::from("https://raw.ghes.example.com"),
+            ..secret::Github::default()
+        };
+        let file = Some(("SOF3", "blob-mirror", "master/README.md"));
+        assert_eq!(
+            parse(
+                &github,
+                "https://ghes.example.com/SOF3/blob-mirror/blob/master/README.md"
+            ),
+            file
+        );
+        assert_eq!(
+            parse(
+                &github,
+                "https://ghes.example.com/SOF3/blob-mirror/raw/master/README.md"
+            ),
+            file
+        );
+        assert_eq!(
+            parse(
+                &github,
+                "https://raw.ghes.example.com/SOF3/blob-mirror/master/README.md"
+            ),
+            file
+        );
+        assert_eq!(
+            parse(
+                &github,
+                "https://github.com/SOF3/blob-mirror/blob/master/README.md"
+            ),
+            None
+        );
+        assert_eq!(
+            parse(
+                &github,
+                "https://ghes.example.com/SOF3/blob-mirror/tree/master/docs"
+            ),
+            None
+        );
+    }
+}
@@ -0,0 +1,164 @@
+//! Computing the permissions of a member in a channel.
+//!
+//! serenity does not know the thread permissions yet,
+//! so permissions are kept as the raw bit sets of the Discord API.
+
+use std::collections::HashMap;
+
+pub const ADMINISTRATOR: u64 = 1 << 3;
+pub const VIEW_CHANNEL: u64 = 1 << 10;
+pub const SEND_MESSAGES: u64 = 1 << 11;
+pub const MANAGE_MESSAGES: u64 = 1 << 13;
+pub const EMBED_LINKS: u64 = 1 << 14;
+pub const ATTACH_FILES: u64 = 1 << 15;
+pub const SEND_MESSAGES_IN_THREADS: u64 = 1 << 38;
+
+/// Names of the permissions as shown in the Discord client
+const NAMES: &[(u64, &str)] = &[
+    (ADMINISTRATOR, "Administrator"),
+    (VIEW_CHANNEL, "View Channel"),
+    (SEND_MESSAGES, "Send Messages"),
+    (MANAGE_MESSAGES, "Manage Messages"),
+    (EMBED_LINKS, "Embed Links"),
+    (ATTACH_FILES, "Attach Files"),
+    (SEND_MESSAGES_IN_THREADS, "Send Messages in Threads"),
+];
+
+/// The roles of a guild
+#[derive(Debug, Clone, Default)]
+pub struct Guild {
+    pub id: u64,
+    pub owner_id: u64,
+    /// Permissions by role ID; the `@everyone` role has the ID of the guild
+    pub roles: HashMap<u64, u64>,
+}
+
+/// A permission overwrite of a channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Overwrite {
+    /// The role or member ID
+    pub id: u64,
+    /// Whether `id` is a member rather than a role
+    pub member: bool,
+    pub allow: u64,
+    pub deny: u64,
+}
+
+impl Guild {
+    /// Computes the permissions of a member with `member_roles` in a channel with `overwrites`.
+    ///
+    /// Follows <https://discord.com/developers/docs/topics/permissions#permission-overwrites>.
+    pub fn permissions(
+        &self,
+        overwrites: &[Overwrite],
+        member_id: u64,
+        member_roles: &[u64],
+    ) -> u64 {
+        if member_id == self.owner_id {
+            return !0;
+        }
+
+        let mut permissions = self.roles.get(&self.id).copied().unwrap_or_default();
+        for role in member_roles {
+            permissions |= self.roles.get(role).copied().unwrap_or_default();
+        }
+        if permissions & ADMINISTRATOR != 0 {
+            return !0;
+        }
+
+        if let Some(everyone) = overwrites.iter().find(|o| !o.member && o.id == self.id) {
+            permissions &= !everyone.deny;
+            permissions |= everyone.allow;
+        }
+        let (mut allow, mut deny) = (0, 0);
+        for overwrite in overwrites {
+            if !overwrite.member && member_roles.contains(&overwrite.id) {
+                allow |= overwrite.allow;
+                deny |= overwrite.deny;
+            }
+        }
+        permissions &= !deny;
+        permissions |= allow;
+        if let Some(member) = overwrites.iter().find(|o| o.member && o.id == member_id) {
+            permissions &= !member.deny;
+            permissions |= member.allow;
+        }
+        permissions
+    }
+}
+
+/// Returns an error listing the permissions in `required` that are missing from `permissions`.
+pub fn ensure(permissions: u64, required: u64, subject: &str) -> anyhow::Result<()> {
+    let missing: Vec<&str> = NAMES
+        .iter()
+        .filter(|&&(bit, _)| required & bit != 0 && permissions & bit == 0)
+        .map(|&(_, name)| name)
+        .collect();
+    anyhow::ensure!(
+        missing.is_empty(),
+        "{} missing permissions in the target channel: {}",
+        subject,
+        missing.join(", ")
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overwrites_apply_in_order() {
+        let guild = Guild {
+            id: 1,
+            owner_id: 2,
+            roles: vec![
+                (1, VIEW_CHANNEL | SEND_MESSAGES),
+                (3, EMBED_LINKS),
+                (4, ADMINISTRATOR),
+            ]
+            .into_iter()
+            .collect(),
+        };
+        let overwrites = [
+            Overwrite {
+                id: 1,
+                member: false,
+                allow: 0,
+                deny: SEND_MESSAGES | VIEW_CHANNEL,
+            },
+            Overwrite {
+                id: 3,
+                member: false,
+                allow: VIEW_CHANNEL,
+                deny: EMBED_LINKS,
+            },
+            Overwrite {
+                id: 5,
+                member: true,
+                allow: SEND_MESSAGES,
+                deny: 0,
+            },
+        ];
+
+        assert_eq!(guild.permissions(&overwrites, 6, &[]), 0);
+        assert_eq!(guild.permissions(&overwrites, 6, &[3]), VIEW_CHANNEL);
+        assert_eq!(
+            guild.permissions(&overwrites, 5, &[3]),
+            VIEW_CHANNEL | SEND_MESSAGES
+        );
+        assert_eq!(guild.permissions(&overwrites, 6, &[4]), !0);
+        assert_eq!(guild.permissions(&overwrites, 2, &[]), !0);
+
+        let err = ensure(
+            VIEW_CHANNEL,
+            VIEW_CHANNEL | SEND_MESSAGES | EMBED_LINKS,
+            "blob-mirror is",
+        )
+        .expect_err("Permissions are missing");
+        assert_eq!(
+            err.to_string(),
+            "blob-mirror is missing permissions in the target channel: Send Messages, Embed Links"
+        );
+    }
+}
@@ -0,0 +1,136 @@
+//! Warnings for mirrors of repos without the GitHub App,
+//! and cleaning them up once the app is installed or the warning expires.
+
+use std::convert::TryInto;
+
+use futures::future;
+
+use common::{db, secret};
+
+use crate::chat::ChatSink;
+use crate::mirror::{self, Mirror};
+
+/// The reaction added to a `mirror` command whose repo has not installed the GitHub App
+pub const UNSEEN_REACTION: char = '🙈';
+
+/// The content of a warning whose mirror was abandoned
+const ABANDONED: &str = "⚠️  This mirror was abandoned because the blob-mirror GitHub App \
+    was not installed for the repo in time. The mirror messages have been removed.";
+
+/// Warns in the command channel if the GitHub App is not active for the repo of a new mirror.
+///
+/// The warning and the reaction on the command message are queued for removal
+/// once the app is installed.
+pub async fn warn(
+    chat: &impl ChatSink,
+    conn: &db::Conn,
+    limits: &secret::OnSeen,
+    mirror: Mirror,
+    command_channel_id: u64,
+    command_message_id: u64,
+) -> anyhow::Result<()> {
+    let warning = match conn.is_seen(mirror.repo_id).await {
+        Ok(db::SeenStatus::Installed(_)) => return Ok(()),
+        Ok(db::SeenStatus::NotInstalled) => format!(
+            "⚠️  I have never heard from this repo ({}/{}). \
+            Please contact the repo admin to install the blob-mirror GitHub App \
+            at https://github.com/apps/blob-mirror for this repo.\n\
+            This message will be deleted when I hear from the repo.",
+            mirror.user, mirror.repo
+        ),
+        Ok(db::SeenStatus::Suspended(info)) => format!(
+            "⚠️  The blob-mirror GitHub App is installed for this repo ({}/{}), \
+            but the installation on {} is suspended. \
+            Please contact the repo admin to unsuspend it.\n\
+            This message will be deleted when the installation is unsuspended.",
+            mirror.user, mirror.repo, info.account
+        ),
+        Err(err) => {
+            log::error!("Error checking seen status: {:?}", err);
+            return Ok(());
+        }
+    };
+
+    let heard = chat.send(command_channel_id, &warning).await?;
+    chat.react(command_channel_id, command_message_id, UNSEEN_REACTION)
+        .await?;
+
+    if let Err(err) = conn
+        .delete_on_seen(mirror.repo_id, command_channel_id, heard, limits)
+        .await
+    {
+        log::error!("Error scheduling seen message deletion: {:?}", err);
+    }
+    if let Err(err) = conn
+        .dereact_on_seen(
+            mirror.repo_id,
+            command_channel_id,
+            command_message_id,
+            limits,
+        )
+        .await
+    {
+        log::error!("Error scheduling seen message deletion: {:?}", err);
+    }
+    if limits.abandon {
+        let pending = db::PendingWarning {
+            repo_id: mirror.repo_id,
+            group: mirror.group,
+            channel_id: command_channel_id,
+            message_id: heard,
+        };
+        if let Err(err) = conn.expire_warning(&pending, limits.ttl).await {
+            log::error!("Error scheduling warning expiry: {:?}", err);
+        }
+    }
+
+    Ok(())
+}
+
+/// Deletes the warnings and reactions queued for a repo that was just seen.
+pub async fn handle_on_seen(chat: &impl ChatSink, on_seen: db::OnSeen) {
+    let deletions = on_seen.deletions.chunks_exact(2).map(|pair| {
+        let [channel_id, message_id]: [u64; 2] = pair.try_into().expect("chunks_exact(2)");
+        async move {
+            let _ = chat.delete(channel_id, message_id).await; // doesn't matter if we can't delete
+        }
+    });
+    let dereacts = on_seen.dereacts.chunks_exact(2).map(|pair| {
+        let [channel_id, message_id]: [u64; 2] = pair.try_into().expect("chunks_exact(2)");
+        async move {
+            // doesn't matter if we can't delete
+            let _ = chat.unreact(channel_id, message_id, UNSEEN_REACTION).await;
+        }
+    });
+
+    future::join(future::join_all(deletions), future::join_all(dereacts)).await;
+}
+
+/// Removes the mirrors whose repo did not install the GitHub App in time,
+/// editing their warning to say so.
+pub async fn abandon_expired_warnings(chat: &impl ChatSink, conn: &db::Conn) -> anyhow::Result<()> {
+    for warning in conn.take_expired_warnings().await? {
+        if let db::SeenStatus::Installed(_) = conn.is_seen(warning.repo_id).await? {
+            continue; // the warning was already deleted
+        }
+
+        log::info!(
+            "Abandoning mirror group {} of repo #{}",
+            &warning.group,
+            warning.repo_id
+        );
+        if conn.has_group(warning.repo_id, &warning.group).await? {
+            mirror::delete(chat, conn, warning.repo_id, &warning.group).await?;
+        }
+
+        if let Err(err) = chat
+            .edit(warning.channel_id, warning.message_id, ABANDONED)
+            .await
+        {
+            // the warning may have been deleted manually
+            log::warn!("Error editing abandoned warning: {}", err);
+        }
+    }
+
+    Ok(())
+}
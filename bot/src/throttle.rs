@@ -0,0 +1,136 @@
+//! Limits driven by the runtime settings.
+
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// A semaphore whose number of permits can be changed at runtime
+pub struct Concurrency {
+    semaphore: Arc<Semaphore>,
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    limit: usize,
+    /// Permits to retire when their holders release them, left over from shrinking
+    debt: usize,
+}
+
+/// A held permit of `Concurrency`, released or retired when dropped
+pub struct Permit {
+    permit: Option<OwnedSemaphorePermit>,
+    state: Arc<Mutex<State>>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().expect("Poisoned lock");
+        if state.debt > 0 {
+            state.debt -= 1;
+            if let Some(permit) = self.permit.take() {
+                permit.forget();
+            }
+        }
+    }
+}
+
+impl Concurrency {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(limit)),
+            state: Arc::new(Mutex::new(State { limit, debt: 0 })),
+        }
+    }
+
+    /// Waits until fewer than `limit` permits are held.
+    pub async fn acquire(&self) -> Permit {
+        let permit = Arc::clone(&self.semaphore)
+            .acquire_owned()
+            .await
+            .expect("Semaphore is never closed");
+        Permit {
+            permit: Some(permit),
+            state: Arc::clone(&self.state),
+        }
+    }
+
+    /// Changes the number of permits.
+    ///
+    /// When shrinking, idle permits are retired at once
+    /// and the rest as soon as their holders release them.
+    /// Growing pays off the permits still to be retired before adding new ones.
+    pub fn resize(&self, new: usize) {
+        let mut state = self.state.lock().expect("Poisoned lock");
+        let old = std::mem::replace(&mut state.limit, new);
+        if new > old {
+            let paid = cmp::min(state.debt, new - old);
+            state.debt -= paid;
+            self.semaphore.add_permits(new - old - paid);
+        } else {
+            let mut excess = old - new;
+            while excess > 0 {
+                match self.semaphore.try_acquire() {
+                    Ok(permit) => permit.forget(),
+                    Err(_) => break,
+                }
+                excess -= 1;
+            }
+            state.debt += excess;
+        }
+    }
+}
+
+/// Counts the commands of each guild in the last minute
+#[derive(Default)]
+pub struct CommandLimiter {
+    history: Mutex<HashMap<u64, VecDeque<Instant>>>,
+}
+
+impl CommandLimiter {
+    /// Records a command if the guild has issued fewer than `per_minute` commands in the last minute.
+    pub fn try_acquire(&self, guild_id: u64, per_minute: usize) -> bool {
+        let now = Instant::now();
+        let mut history = self.history.lock().expect("Poisoned lock");
+        let times = history.entry(guild_id).or_default();
+        while let Some(&time) = times.front() {
+            if now.duration_since(time) < Duration::from_secs(60) {
+                break;
+            }
+            times.pop_front();
+        }
+
+        if times.len() >= per_minute {
+            return false;
+        }
+        times.push_back(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn regrowing_pays_off_pending_shrink() {
+        let concurrency = Concurrency::new(2);
+        let first = concurrency.acquire().await;
+        let second = concurrency.acquire().await;
+
+        concurrency.resize(1);
+        concurrency.resize(3);
+        drop(first);
+        drop(second);
+        assert_eq!(concurrency.semaphore.available_permits(), 3);
+
+        concurrency.resize(1);
+        assert_eq!(concurrency.semaphore.available_permits(), 1);
+        let held = concurrency.acquire().await;
+        concurrency.resize(0);
+        drop(held);
+        assert_eq!(concurrency.semaphore.available_permits(), 0);
+    }
+}
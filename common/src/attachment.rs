@@ -0,0 +1,201 @@
+//! Files mirrored as message attachments, such as images and PDFs.
+//!
+//! Attachments cannot be edited, so the bot deletes and re-posts the message
+//! whenever the blob SHA of the file changes.
+
+use crate::format::Layout;
+use crate::render;
+
+/// Maximum size of an upload to a guild without boosts
+pub const MAX_BYTES: usize = 8 << 20;
+
+/// MIME types by file extension, for files that are mirrored as attachments
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("bmp", "image/bmp"),
+    ("ico", "image/x-icon"),
+    ("svg", "image/svg+xml"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("mp3", "audio/mpeg"),
+    ("ogg", "audio/ogg"),
+    ("wav", "audio/wav"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+];
+
+/// MIME types by magic number
+const SIGNATURES: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"%PDF-", "application/pdf"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"ID3", "audio/mpeg"),
+    (b"OggS", "audio/ogg"),
+    (b"\x1a\x45\xdf\xa3", "video/webm"),
+];
+
+fn extension(path: &str) -> Option<String> {
+    let file = path.rsplit('/').next()?;
+    let dot = file.rfind('.')?;
+    Some(file[dot + 1..].to_ascii_lowercase())
+}
+
+/// Returns the MIME type of a path if it is mirrored as an attachment by default.
+pub fn mime_by_extension(path: &str) -> Option<&'static str> {
+    let extension = extension(path)?;
+    EXTENSIONS
+        .iter()
+        .find(|&&(known, _)| known == extension)
+        .map(|&(_, mime)| mime)
+}
+
+/// Detects the MIME type of a file from its magic number.
+pub fn sniff(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.len() >= 12 && &bytes[..4] == b"RIFF" {
+        match &bytes[8..12] {
+            b"WEBP" => return Some("image/webp"),
+            b"WAVE" => return Some("audio/wav"),
+            _ => {}
+        }
+    }
+    if bytes.len() >= 8 && &bytes[4..8] == b"ftyp" {
+        return Some("video/mp4");
+    }
+    SIGNATURES
+        .iter()
+        .find(|(magic, _)| bytes.starts_with(magic))
+        .map(|&(_, mime)| mime)
+}
+
+/// A file uploaded as an attachment
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Attachment {
+    /// The name of the uploaded file, with an extension matching `mime`
+    pub filename: String,
+    pub mime: String,
+    /// Size in bytes
+    pub size: usize,
+    /// The git blob SHA of the file
+    pub sha: String,
+}
+
+impl Attachment {
+    /// Describes the contents of a file; `path` may be any path or URL ending with the file name.
+    pub fn new(path: &str, bytes: &[u8], sha: &str) -> Self {
+        let mime = sniff(bytes)
+            .or_else(|| mime_by_extension(path))
+            .unwrap_or_else(|| match std::str::from_utf8(bytes) {
+                Ok(_) => "text/plain",
+                Err(_) => "application/octet-stream",
+            });
+
+        let mut filename = path.rsplit('/').next().unwrap_or_default().to_string();
+        // Discord only previews files with the right extension
+        let matching = mime_by_extension(&filename) == Some(mime);
+        if !matching {
+            if let Some(&(extension, _)) = EXTENSIONS.iter().find(|&&(_, known)| known == mime) {
+                filename.push('.');
+                filename.push_str(extension);
+            }
+        }
+
+        Self {
+            filename,
+            mime: mime.to_string(),
+            size: bytes.len(),
+            sha: sha.to_string(),
+        }
+    }
+
+    /// Whether the file is within the upload limit.
+    pub fn fits(&self) -> bool {
+        self.size <= MAX_BYTES
+    }
+
+    /// Returns an error if the file exceeds the upload limit.
+    pub fn check_size(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.fits(),
+            "The file is {}, larger than the Discord upload limit of {}",
+            mebibytes(self.size),
+            mebibytes(MAX_BYTES)
+        );
+        Ok(())
+    }
+
+    /// Renders the content of the message that the file is attached to.
+    ///
+    /// Files over the upload limit are replaced with a link to the file.
+    pub fn caption(&self, layout: &Layout<'_>) -> String {
+        let content = if self.fits() {
+            String::new()
+        } else {
+            format!(
+                "\u{26a0}\u{fe0f} `{}` is {}, larger than the Discord upload limit of {}.\nSee <{}>",
+                self.filename,
+                mebibytes(self.size),
+                mebibytes(MAX_BYTES),
+                layout.url
+            )
+        };
+        layout
+            .frame
+            .apply(&content, layout.vars, 1, 1, render::MESSAGE_MAX_LENGTH)
+    }
+}
+
+fn mebibytes(bytes: usize) -> String {
+    format!("{:.1} MiB", bytes as f64 / (1 << 20) as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mime_prefers_magic_number() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        let attachment = Attachment::new("master/docs/logo", png, "sha");
+        assert_eq!(attachment.mime, "image/png");
+        assert_eq!(attachment.filename, "logo.png");
+
+        let attachment = Attachment::new("master/docs/logo.PNG", png, "sha");
+        assert_eq!(attachment.filename, "logo.PNG");
+
+        let attachment = Attachment::new("master/diagram.svg", b"<svg/>", "sha");
+        assert_eq!(attachment.mime, "image/svg+xml");
+
+        let attachment = Attachment::new("master/data.bin", &[0xff, 0xfe], "sha");
+        assert_eq!(attachment.mime, "application/octet-stream");
+        assert_eq!(attachment.filename, "data.bin");
+    }
+
+    #[test]
+    fn oversized_files_link_to_github() {
+        let mut attachment = Attachment::new("master/manual.pdf", b"%PDF-1.7", "sha");
+        assert!(attachment.check_size().is_ok());
+        attachment.size = MAX_BYTES * 2;
+        assert!(attachment.check_size().is_err());
+
+        let layout = Layout {
+            spec: &Default::default(),
+            embed: None,
+            frame: &Default::default(),
+            vars: &Default::default(),
+            url: "https://github.com/SOF3/blob-mirror/blob/master/manual.pdf",
+        };
+        assert_eq!(
+            attachment.caption(&layout),
+            "\u{26a0}\u{fe0f} `manual.pdf` is 16.0 MiB, larger than the Discord upload limit of 8.0 MiB.\nSee <https://github.com/SOF3/blob-mirror/blob/master/manual.pdf>"
+        );
+    }
+}
@@ -0,0 +1,99 @@
+//! The `check-config` subcommand shared by both binaries.
+
+use crate::contents::USER_AGENT;
+use crate::db;
+use crate::github_app::AppKey;
+use crate::secret::{self, Secret, Service};
+
+/// Validates the configuration of `service` and checks connectivity to the services it uses.
+///
+/// Every problem found is printed to stdout.
+pub async fn run(service: Service) -> anyhow::Result<()> {
+    println!("Checking configuration of {}", service);
+
+    let secret = match secret::check(service) {
+        Ok(secret) => {
+            println!("ok     configuration");
+            secret
+        }
+        Err(problems) => {
+            for problem in &problems {
+                println!("error  {}", problem);
+            }
+            anyhow::bail!("{} configuration problem(s) found", problems.len());
+        }
+    };
+
+    let mut failures = 0;
+    let mut report = |name: &str, result: anyhow::Result<String>| match result {
+        Ok(detail) => println!("ok     {}: {}", name, detail),
+        Err(err) => {
+            println!("error  {}: {:#}", name, err);
+            failures += 1;
+        }
+    };
+
+    report("redis", check_redis(&secret).await);
+    if let Some(token) = &secret.discord.token {
+        report("discord", check_discord(token.expose()).await);
+    }
+    if let (Some(key), Some(app_id)) = (&secret.github.private_key, secret.github.app_id) {
+        report(
+            "github",
+            check_github(&secret.github.api_url, key.expose(), app_id).await,
+        );
+    }
+
+    if failures > 0 {
+        anyhow::bail!("{} connectivity check(s) failed", failures);
+    }
+    Ok(())
+}
+
+async fn check_redis(secret: &Secret) -> anyhow::Result<String> {
+    let conn = db::Conn::new(secret).await?;
+    conn.ping().await?;
+    Ok(String::from("connected"))
+}
+
+#[derive(serde::Deserialize)]
+struct DiscordUser {
+    username: String,
+    discriminator: String,
+}
+
+async fn check_discord(token: &str) -> anyhow::Result<String> {
+    let user: DiscordUser = reqwest::Client::new()
+        .get("https://discord.com/api/v9/users/@me")
+        .header("Authorization", format!("Bot {}", token))
+        .header("User-Agent", USER_AGENT)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(format!(
+        "logged in as {}#{}",
+        user.username, user.discriminator
+    ))
+}
+
+#[derive(serde::Deserialize)]
+struct GithubApp {
+    slug: String,
+}
+
+async fn check_github(api_url: &str, pem: &str, app_id: u64) -> anyhow::Result<String> {
+    let jwt = AppKey::from_pem(pem)?.jwt(app_id)?;
+    let app: GithubApp = reqwest::Client::new()
+        .get(format!("{}/app", api_url))
+        .bearer_auth(jwt)
+        .header("Accept", "application/vnd.github.v3+json")
+        .header("User-Agent", USER_AGENT)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(format!("authenticated as app {}", app.slug))
+}
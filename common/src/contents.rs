@@ -0,0 +1,362 @@
+//! Fetching file contents through the GitHub contents and git blobs APIs.
+//!
+//! Contents are cached by blob SHA, so that groups mirroring the same file share one copy,
+//! and requests for a URL fetched before are sent with `If-None-Match`.
+//! Unchanged files then cost a `304 Not Modified`, which does not count against the rate limit.
+//!
+//! Requests for repos covered by the GitHub App are authenticated as its installation,
+//! so that private repos can be mirrored.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use anyhow::Context;
+use reqwest::header;
+use reqwest::StatusCode;
+
+use crate::github_app::App;
+use crate::{metrics, secret};
+
+/// Maximum total size of the cached contents in bytes
+const CACHE_BYTES: usize = 32 << 20;
+/// Maximum number of remembered ETags
+const MAX_ETAGS: usize = 4096;
+
+pub(crate) const USER_AGENT: &str = "blob-mirror/v0.1";
+
+/// The contents of a file
+#[derive(Debug, Clone)]
+pub enum Blob {
+    Text(Arc<str>),
+    /// Contents that are not valid UTF-8
+    Binary(Arc<[u8]>),
+}
+
+impl Blob {
+    fn new(bytes: Vec<u8>) -> Self {
+        match String::from_utf8(bytes) {
+            Ok(text) => Self::Text(text.into()),
+            Err(err) => Self::Binary(err.into_bytes().into()),
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Self::Text(text) => text.as_bytes(),
+            Self::Binary(bytes) => bytes,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.as_bytes().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A repo looked up through the repos API
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Repo {
+    pub id: u64,
+}
+
+/// A fetched file
+#[derive(Debug, Clone)]
+pub struct Fetched {
+    /// The git blob SHA of the file
+    pub sha: String,
+    pub blob: Blob,
+}
+
+/// Fetches and caches file contents
+pub struct Fetcher {
+    client: reqwest::Client,
+    /// Authenticates requests for repos with an installation; `None` without a private key
+    app: Option<App>,
+    cache: Mutex<Cache>,
+}
+
+#[derive(Default)]
+struct Cache {
+    /// ETag and blob SHA of the last response of each contents URL
+    etags: HashMap<String, (String, String)>,
+    /// Contents URLs in the order their ETags were stored
+    etag_order: VecDeque<String>,
+    /// Contents by blob SHA
+    blobs: HashMap<String, Blob>,
+    /// Blob SHAs in the order they were stored
+    blob_order: VecDeque<String>,
+    /// Total size of `blobs`
+    bytes: usize,
+}
+
+impl Cache {
+    /// Returns the ETag of a URL if its contents are still cached.
+    fn conditional(&self, url: &str) -> Option<(String, Fetched)> {
+        let (etag, sha) = self.etags.get(url)?;
+        let content = self.blobs.get(sha)?;
+        Some((
+            etag.clone(),
+            Fetched {
+                sha: sha.clone(),
+                blob: content.clone(),
+            },
+        ))
+    }
+
+    fn insert_etag(&mut self, url: &str, etag: String, sha: &str) {
+        if self
+            .etags
+            .insert(url.to_string(), (etag, sha.to_string()))
+            .is_none()
+        {
+            self.etag_order.push_back(url.to_string());
+        }
+        while self.etag_order.len() > MAX_ETAGS {
+            if let Some(url) = self.etag_order.pop_front() {
+                self.etags.remove(&url);
+            }
+        }
+    }
+
+    fn insert_blob(&mut self, sha: &str, content: Blob) {
+        if content.len() > CACHE_BYTES {
+            return;
+        }
+        self.bytes += content.len();
+        match self.blobs.insert(sha.to_string(), content) {
+            Some(old) => self.bytes -= old.len(),
+            None => self.blob_order.push_back(sha.to_string()),
+        }
+        while self.bytes > CACHE_BYTES {
+            match self.blob_order.pop_front() {
+                Some(sha) => {
+                    if let Some(content) = self.blobs.remove(&sha) {
+                        self.bytes -= content.len();
+                    }
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+/// A `GET /repos/{owner}/{repo}/contents/{path}` response
+#[derive(serde::Deserialize)]
+struct File {
+    #[serde(rename = "type")]
+    kind: String,
+    sha: String,
+    /// The contents, encoded as specified by `encoding`
+    #[serde(default)]
+    content: String,
+    /// `base64`, or `none` if the file is too large to be inlined
+    #[serde(default)]
+    encoding: String,
+    /// The git blobs API URL of the file
+    git_url: Option<String>,
+}
+
+/// A fetcher that only sends unauthenticated requests
+impl Default for Fetcher {
+    fn default() -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            app: None,
+            cache: Mutex::new(Cache::default()),
+        }
+    }
+}
+
+impl Fetcher {
+    /// Creates a fetcher authenticated as the GitHub App configured in `github`, if any.
+    pub fn new(github: &secret::Github) -> anyhow::Result<Self> {
+        let client = reqwest::Client::new();
+        Ok(Self {
+            app: App::new(github, client.clone())?,
+            client,
+            cache: Mutex::new(Cache::default()),
+        })
+    }
+
+    /// Returns the ID of the GitHub App installation covering a repo,
+    /// or `None` if the app is not installed or not configured.
+    pub async fn installation(&self, repo_name: &str) -> anyhow::Result<Option<u64>> {
+        match &self.app {
+            Some(app) => app.repo_installation(repo_name).await,
+            None => Ok(None),
+        }
+    }
+
+    /// Starts a `GET` request, authenticated as the installation if it is known.
+    ///
+    /// Requests fall back to unauthenticated if no access token can be created,
+    /// which still works for public repos.
+    async fn get(
+        &self,
+        url: &str,
+        accept: &str,
+        installation_id: Option<u64>,
+    ) -> reqwest::RequestBuilder {
+        let request = self
+            .client
+            .get(url)
+            .header(header::USER_AGENT, USER_AGENT)
+            .header(header::ACCEPT, accept);
+        let (app, installation_id) = match (&self.app, installation_id) {
+            (Some(app), Some(installation_id)) => (app, installation_id),
+            _ => return request,
+        };
+        match app.token(installation_id).await {
+            Ok(token) => request.header(header::AUTHORIZATION, format!("token {}", token)),
+            Err(err) => {
+                log::warn!(
+                    "Fetching {} without authentication: {:?}",
+                    url,
+                    err.context(format!("Installation {}", installation_id))
+                );
+                request
+            }
+        }
+    }
+
+    /// Fetches a text file from a contents API URL, e.g. built by `secret::Github::contents_url`.
+    pub async fn fetch(&self, url: &str, installation_id: Option<u64>) -> anyhow::Result<Arc<str>> {
+        match self.fetch_blob(url, installation_id).await?.blob {
+            Blob::Text(text) => Ok(text),
+            Blob::Binary(_) => anyhow::bail!("The file is not valid UTF-8"),
+        }
+    }
+
+    /// Fetches a text or binary file from a contents API URL.
+    pub async fn fetch_blob(
+        &self,
+        url: &str,
+        installation_id: Option<u64>,
+    ) -> anyhow::Result<Fetched> {
+        let conditional = self.cache.lock().expect("Poisoned lock").conditional(url);
+
+        let mut request = self
+            .get(url, "application/vnd.github.v3+json", installation_id)
+            .await;
+        if let Some((etag, _)) = &conditional {
+            request = request.header(header::IF_NONE_MATCH, etag);
+        }
+        let response = request.send().await.map_err(github_error)?;
+
+        if response.status() == StatusCode::NOT_MODIFIED {
+            if let Some((_, content)) = conditional {
+                return Ok(content);
+            }
+        }
+        let response = response.error_for_status().map_err(github_error)?;
+        let etag = response
+            .headers()
+            .get(header::ETAG)
+            .and_then(|etag| etag.to_str().ok())
+            .map(str::to_string);
+        let file: File = response
+            .json()
+            .await
+            .context("GitHub API is not working correctly")?;
+        anyhow::ensure!(file.kind == "file", "The path is not a file");
+
+        let cached = self
+            .cache
+            .lock()
+            .expect("Poisoned lock")
+            .blobs
+            .get(&file.sha)
+            .cloned();
+        let content = match cached {
+            Some(content) => content,
+            None => {
+                let bytes = if file.encoding == "base64" {
+                    let encoded: String = file
+                        .content
+                        .chars()
+                        .filter(|c| !c.is_whitespace())
+                        .collect();
+                    base64::decode(encoded).context("GitHub API is not working correctly")?
+                } else {
+                    let git_url = file
+                        .git_url
+                        .as_ref()
+                        .context("GitHub API did not return the blob URL")?;
+                    self.blob(git_url, installation_id).await?
+                };
+                let content = Blob::new(bytes);
+                self.cache
+                    .lock()
+                    .expect("Poisoned lock")
+                    .insert_blob(&file.sha, content.clone());
+                content
+            }
+        };
+
+        if let Some(etag) = etag {
+            self.cache
+                .lock()
+                .expect("Poisoned lock")
+                .insert_etag(url, etag, &file.sha);
+        }
+        Ok(Fetched {
+            sha: file.sha,
+            blob: content,
+        })
+    }
+
+    /// Looks up a repo from its API URL, e.g. built by `secret::Github::repo_url`.
+    pub async fn repo(&self, url: &str, installation_id: Option<u64>) -> anyhow::Result<Repo> {
+        self.get(url, "application/vnd.github.v3+json", installation_id)
+            .await
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(github_error)?
+            .json()
+            .await
+            .context("GitHub API is not working correctly")
+    }
+
+    /// Downloads a blob too large to be inlined in the contents API response.
+    async fn blob(&self, git_url: &str, installation_id: Option<u64>) -> anyhow::Result<Vec<u8>> {
+        let bytes = self
+            .get(git_url, "application/vnd.github.v3.raw", installation_id)
+            .await
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(github_error)?
+            .bytes()
+            .await
+            .map_err(github_error)?;
+        Ok(bytes.to_vec())
+    }
+}
+
+/// Counts a failed request to GitHub in the metrics.
+fn github_error(err: reqwest::Error) -> reqwest::Error {
+    metrics::GITHUB_FETCH_ERRORS.inc();
+    err
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache_evicts_oldest_blobs() {
+        let mut cache = Cache::default();
+        let big = Blob::new("x".repeat(CACHE_BYTES / 2).into_bytes());
+        cache.insert_blob("a", big.clone());
+        cache.insert_blob("b", big);
+        cache.insert_blob("c", Blob::new(vec![0xff, 0, 0, 0, 0]));
+        assert!(!cache.blobs.contains_key("a"));
+        assert!(cache.blobs.contains_key("b"));
+        assert!(cache.blobs.contains_key("c"));
+        assert_eq!(cache.bytes, CACHE_BYTES / 2 + 5);
+    }
+}
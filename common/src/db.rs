@@ -1,8 +1,10 @@
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
 use std::fmt;
 
 use anyhow::Context;
 use futures::future;
-use futures::stream::{FuturesUnordered, StreamExt};
+use futures::stream::StreamExt;
 use redis_async::{
     client::{self, pubsub::PubsubStream},
     resp::RespValue,
@@ -10,18 +12,24 @@ use redis_async::{
 };
 use tokio::sync::mpsc;
 
+use crate::attachment;
+use crate::format;
+use crate::metrics;
+use crate::render;
 use crate::secret::{self, Secret};
+use crate::template;
 
 pub fn subscriber<T>(secret: &Secret, topic: &'static str) -> anyhow::Result<mpsc::Receiver<T>>
 where
     T: fmt::Debug + Send + Sync + serde::de::DeserializeOwned + 'static,
 {
-    async fn read<'t, T>(tx: &mpsc::Sender<T>, pubsub: &mut PubsubStream) -> anyhow::Result<()>
+    async fn read<T>(tx: &mpsc::Sender<T>, pubsub: &mut PubsubStream) -> anyhow::Result<()>
     where
         T: fmt::Debug + Send + Sync + serde::de::DeserializeOwned + 'static,
     {
         let msg = pubsub.next().await.context("Connection broken")??;
         let payload: T = match msg {
+            RespValue::BulkString(bytes) => serde_json::from_slice(&bytes)?,
             RespValue::SimpleString(bs) => serde_json::from_str(&bs)?,
             _ => anyhow::bail!("Incorrect pubsub data type"),
         };
@@ -66,13 +74,303 @@ pub struct OnSeen {
 /// Schema of the `updates` pubsub topic
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct Update {
+    pub group: String,
     pub channel_id: u64,
     pub message_ids: Vec<u64>,
+    /// URL of the file on the GitHub web interface
     pub url: String,
+    /// URL to fetch the file from through the contents API;
+    /// empty in updates published by older versions, which fetched `url` directly
+    #[serde(default)]
+    pub contents_url: String,
+    /// SHA of the pushed commit that `contents_url` is pinned to,
+    /// or `None` if it follows the head of the branch
+    #[serde(default)]
+    pub commit: Option<String>,
+    /// The GitHub App installation to fetch the file as; `None` if the repo is not installed
+    #[serde(default)]
+    pub installation_id: Option<u64>,
+    /// The rendered content of each message in `message_ids`;
+    /// empty in updates published by older versions, which left fetching to the bot
+    #[serde(default)]
+    pub pages: Vec<String>,
+    /// The embed fields of each message in `message_ids`, if the file is rendered as fields
+    #[serde(default)]
+    pub fields: Vec<Vec<render::Field>>,
+    /// The embed that each page is the description of, if the group is in embed mode
+    #[serde(default)]
+    pub embed: Option<render::Embed>,
+    /// How the file is parsed
+    #[serde(default)]
+    pub format: format::Spec,
+    /// With `render::Split::Sections`, `pages` may differ in length from `message_ids`,
+    /// and the bot adds or removes messages to match.
+    #[serde(default)]
+    pub split: render::Split,
+    /// The header and footer of each page
+    #[serde(default)]
+    pub frame: template::Frame,
+    /// The values of the template variables of `frame`
+    #[serde(default)]
+    pub vars: template::Vars,
+    /// The file to upload if the group is in `format::Format::Attachment`;
+    /// `pages` then has the message content
+    #[serde(default)]
+    pub attachment: Option<attachment::Attachment>,
+    /// Unix timestamp in milliseconds when the update was published
+    #[serde(default)]
+    pub published_at: u64,
+}
+
+/// A commit pushed to a branch
+#[derive(Debug, Clone, Copy)]
+pub struct Push<'t> {
+    pub branch: &'t str,
+    pub commit: &'t str,
+    /// The name of the author of the head commit, if known
+    pub author: Option<&'t str>,
+}
+
+/// Schema of the `lifecycle` pubsub topic
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct Lifecycle {
+    pub repo_id: u64,
+    pub repo_name: String,
+    pub group: String,
+    pub channel_id: u64,
+    pub message_ids: Vec<u64>,
+    pub event: LifecycleEvent,
+    pub policy: LifecyclePolicy,
+}
+
+/// A change to a mirrored repo that stops it from being mirrorable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LifecycleEvent {
+    Deleted,
+    Archived,
+    Privatized,
+    /// The GitHub App was uninstalled from the repo.
+    Uninstalled,
+    /// The GitHub App installation covering the repo was suspended.
+    Suspended,
+}
+
+impl LifecycleEvent {
+    /// Whether the event only affects the GitHub App and can be undone by the repo admin.
+    ///
+    /// The `LifecyclePolicy` of a group is not applied for such events;
+    /// the mirror channel is only warned.
+    pub fn is_app_event(self) -> bool {
+        matches!(self, Self::Uninstalled | Self::Suspended)
+    }
+}
+
+impl fmt::Display for LifecycleEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Deleted => "deleted",
+            Self::Archived => "archived",
+            Self::Privatized => "made private",
+            Self::Uninstalled => "disconnected from the blob-mirror GitHub App",
+            Self::Suspended => "suspended from the blob-mirror GitHub App",
+        })
+    }
+}
+
+/// What a mirror group does when its repo receives a `LifecycleEvent`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LifecyclePolicy {
+    /// Keep the mirrored content and add a banner to it.
+    Freeze,
+    /// Send a message to the mirror channel.
+    #[default]
+    Notify,
+    /// Delete the mirrored messages and the mirror group.
+    Teardown,
+}
+
+impl LifecyclePolicy {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Freeze => "freeze",
+            Self::Notify => "notify",
+            Self::Teardown => "teardown",
+        }
+    }
+}
+
+impl std::str::FromStr for LifecyclePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s {
+            "freeze" => Self::Freeze,
+            "notify" => Self::Notify,
+            "teardown" => Self::Teardown,
+            _ => anyhow::bail!("Unknown lifecycle policy {:?}", s),
+        })
+    }
+}
+
+/// A "not installed" warning posted in reply to a mirror command
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct PendingWarning {
+    pub repo_id: u64,
+    /// The mirror group created by the command
+    pub group: String,
+    pub channel_id: u64,
+    pub message_id: u64,
+}
+
+/// An installation of the GitHub App covering a repo
+pub struct Installation<'t> {
+    pub id: u64,
+    /// Login of the user or organization that owns the repo
+    pub account: &'t str,
+    /// The permissions granted to the installation, if known
+    pub permissions: Option<&'t BTreeMap<String, String>>,
+}
+
+/// Whether a repo is covered by the GitHub App
+#[derive(Debug, serde::Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum SeenStatus {
+    NotInstalled,
+    Installed(RepoInstallation),
+    /// The installation covering the repo is suspended.
+    Suspended(RepoInstallation),
+}
+
+impl SeenStatus {
+    /// Returns the installation that can fetch the contents of the repo.
+    pub fn installation_id(&self) -> Option<u64> {
+        match self {
+            Self::Installed(info) => Some(info.installation_id),
+            // suspended installations cannot create access tokens
+            Self::Suspended(_) | Self::NotInstalled => None,
+        }
+    }
+}
+
+/// The stored installation metadata of a repo
+#[derive(Debug, serde::Serialize)]
+pub struct RepoInstallation {
+    pub installation_id: u64,
+    pub account: String,
+    pub permissions: BTreeMap<String, String>,
+    /// Unix timestamp of when the repo was first seen
+    pub first_seen: Option<u64>,
+    /// Unix timestamp of the last push to the repo
+    pub last_push: Option<u64>,
+}
+
+/// Schema of the `commands` pubsub topic
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Command {
+    /// Create a mirror group, as if the `mirror` command was sent in the channel.
+    Mirror {
+        guild_id: u64,
+        channel_id: u64,
+        url: String,
+        pages: usize,
+        lifecycle: LifecyclePolicy,
+        /// The render mode; the `default_render_mode` setting if absent
+        #[serde(default)]
+        mode: Option<render::Mode>,
+        /// With `render::Split::Sections`, `pages` is ignored.
+        #[serde(default)]
+        split: render::Split,
+        /// The file format; detected from the extension if absent
+        #[serde(default)]
+        format: Option<format::Format>,
+        #[serde(default)]
+        select: Option<String>,
+        /// Template shown above each page; empty for none
+        #[serde(default)]
+        header: String,
+        /// Template shown below each page; empty for none
+        #[serde(default)]
+        footer: String,
+    },
+    /// Delete the messages of a mirror group and the group itself.
+    Unmirror { repo_id: u64, group: String },
+}
+
+/// The stored state of a mirror group
+#[derive(Debug, serde::Serialize)]
+pub struct Group {
+    pub path: String,
+    pub channel_id: u64,
+    pub message_ids: Vec<u64>,
+    pub lifecycle: LifecyclePolicy,
+    pub mode: render::Mode,
+    pub format: format::Spec,
+    pub split: render::Split,
+    pub frame: template::Frame,
+    /// The git blob SHA of the file last uploaded as an attachment
+    pub blob: Option<String>,
+    /// The guild of the mirror channel; `None` for groups created before guilds were tracked.
+    pub guild_id: Option<u64>,
+    /// The repo of the group; `None` for groups created before repos were tracked.
+    pub repo_id: Option<u64>,
+    /// Unix timestamp of the last time the messages were rendered.
+    pub updated: Option<u64>,
+    /// Whether the group was frozen by its lifecycle policy and is no longer updated.
+    pub frozen: bool,
+}
+
+/// The parameters of a new mirror group
+pub struct NewGroup<'t> {
+    pub repo_id: u64,
+    pub repo_name: &'t str,
+    pub path: &'t str,
+    pub guild_id: u64,
+    pub channel_id: u64,
+    pub message_ids: &'t [u64],
+    pub lifecycle: LifecyclePolicy,
+    pub mode: render::Mode,
+    pub format: &'t format::Spec,
+    pub split: render::Split,
+    pub frame: &'t template::Frame,
+    /// The git blob SHA of the uploaded attachment
+    pub blob: Option<&'t str>,
+}
+
+/// Generates a random alphanumeric string.
+pub fn random_id(len: usize) -> String {
+    use rand::Rng;
+
+    rand::thread_rng()
+        .sample_iter(&rand::distributions::Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+fn parse_ids(strings: Vec<String>, what: &str) -> anyhow::Result<Vec<u64>> {
+    strings
+        .into_iter()
+        .map(|string| {
+            string
+                .parse::<u64>()
+                .with_context(|| format!("{} is not integer", what))
+        })
+        .collect()
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
 }
 
 pub struct Conn {
     conn: client::PairedConnection,
+    /// Base URLs of GitHub, used to build the URLs of mirrored files
+    github: secret::Github,
 }
 
 impl Conn {
@@ -81,37 +379,174 @@ impl Conn {
             conn: client::paired_connect(secret.redis.addr().await?)
                 .await
                 .context("Failed to connect to redis")?,
+            github: secret.github.clone(),
         })
     }
 
-    pub async fn seen_bool_multi(
+    /// Checks that the database is reachable.
+    pub async fn ping(&self) -> anyhow::Result<()> {
+        let _: String = self
+            .conn
+            .send(resp_array!["PING"])
+            .await
+            .context("Failed to ping redis")?;
+        Ok(())
+    }
+
+    /// Records that a repo is covered by an active installation of the GitHub App.
+    ///
+    /// If the repo was not installed or was suspended before,
+    /// its pending on-seen actions are published.
+    /// The stored permissions are only replaced if `installation.permissions` is set.
+    pub async fn mark_installed(
         &self,
-        repo_ids: impl IntoIterator<Item = u64>,
-        seen: bool,
+        repo_id: u64,
+        installation: &Installation<'_>,
     ) -> anyhow::Result<()> {
-        let mut futs = repo_ids
-            .into_iter()
-            .map(|id| self.seen_bool(id, seen))
-            .collect::<FuturesUnordered<_>>();
-        while let Some(ret) = futs.next().await {
-            ret?;
+        let key = format!("repo-info:{}", repo_id);
+
+        let suspended: Option<String> = self
+            .conn
+            .send(resp_array!["HGET", &key, "suspended"])
+            .await
+            .context("Error marking repo as installed")?;
+
+        let mut command = resp_array![
+            "HSET",
+            &key,
+            "installation",
+            installation.id.to_string(),
+            "account",
+            installation.account,
+            "suspended",
+            "0"
+        ];
+        if let Some(permissions) = installation.permissions {
+            command.push("permissions");
+            command.push(serde_json::to_string(permissions)?);
+        }
+        let _: usize = self
+            .conn
+            .send(command)
+            .await
+            .context("Error marking repo as installed")?;
+        let _: bool = self
+            .conn
+            .send(resp_array![
+                "HSETNX",
+                &key,
+                "first_seen",
+                unix_now().to_string()
+            ])
+            .await
+            .context("Error marking repo as installed")?;
+
+        if suspended.as_deref() != Some("0") {
+            self.publish_on_seen(repo_id).await?;
         }
         Ok(())
     }
 
-    pub async fn seen_bool(&self, repo_id: u64, seen: bool) -> anyhow::Result<()> {
-        if seen {
-            self.seen(repo_id).await
-        } else {
-            self.mark_unseen(repo_id).await.map(|_| ())
+    /// Records a push to a repo.
+    pub async fn mark_pushed(&self, repo_id: u64) -> anyhow::Result<()> {
+        let _: usize = self
+            .conn
+            .send(resp_array![
+                "HSET",
+                format!("repo-info:{}", repo_id),
+                "last_push",
+                unix_now().to_string()
+            ])
+            .await
+            .context("Error marking repo as pushed")?;
+        Ok(())
+    }
+
+    /// Records that the installation covering a repo was suspended.
+    pub async fn mark_suspended(&self, repo_id: u64) -> anyhow::Result<()> {
+        let key = format!("repo-info:{}", repo_id);
+        let exists: bool = self
+            .conn
+            .send(resp_array!["EXISTS", &key])
+            .await
+            .context("Error marking repo as suspended")?;
+        if exists {
+            let _: usize = self
+                .conn
+                .send(resp_array!["HSET", &key, "suspended", "1"])
+                .await
+                .context("Error marking repo as suspended")?;
         }
+        Ok(())
     }
 
-    async fn seen(&self, repo_id: u64) -> anyhow::Result<()> {
-        self.mark_seen(repo_id)
+    /// Records that a repo is no longer covered by any installation.
+    pub async fn mark_uninstalled(&self, repo_id: u64) -> anyhow::Result<()> {
+        let _: usize = self
+            .conn
+            .send(resp_array!["DEL", format!("repo-info:{}", repo_id)])
             .await
-            .context("Error marking seen")?;
+            .context("Error marking repo as uninstalled")?;
+        Ok(())
+    }
 
+    /// Takes the pending on-seen actions of a repo and publishes them to the `on_seen` topic.
+    ///
+    /// Nothing is published if no actions are pending.
+    pub async fn publish_on_seen(&self, repo_id: u64) -> anyhow::Result<()> {
+        let on_seen = OnSeen {
+            deletions: parse_ids(
+                self.take_list(&format!("delete-on-seen:{}", repo_id))
+                    .await
+                    .context("Failed taking deletion list")?,
+                "Deletion ID",
+            )?,
+            dereacts: parse_ids(
+                self.take_list(&format!("dereact-on-seen:{}", repo_id))
+                    .await
+                    .context("Failed taking dereact list")?,
+                "Dereact ID",
+            )?,
+        };
+        if on_seen.deletions.is_empty() && on_seen.dereacts.is_empty() {
+            return Ok(());
+        }
+
+        let json = serde_json::to_string(&on_seen)?;
+        let _: usize = self
+            .conn
+            .send(resp_array!["PUBLISH", "on_seen", json])
+            .await
+            .context("Failed to publish on_seen")?;
+
+        Ok(())
+    }
+
+    /// Atomically removes a list and returns its previous items.
+    ///
+    /// The list is renamed to a random key first,
+    /// so that concurrent consumers cannot take the same items twice.
+    async fn take_list(&self, key: &str) -> anyhow::Result<Vec<String>> {
+        let taken = format!("{}:taken:{}", key, random_id(16));
+        let renamed: Result<String, _> = self.conn.send(resp_array!["RENAME", key, &taken]).await;
+        match renamed {
+            Ok(_) => {}
+            Err(redis_async::error::Error::Remote(err)) if err.contains("no such key") => {
+                return Ok(Vec::new())
+            }
+            Err(err) => return Err(err.into()),
+        }
+
+        let items: Vec<String> = self
+            .conn
+            .send(resp_array!["LRANGE", &taken, "0", "-1"])
+            .await?;
+        let _: usize = self.conn.send(resp_array!["DEL", &taken]).await?;
+        Ok(items)
+    }
+
+    /// Returns the actions queued for when a repo is seen.
+    pub async fn pending_on_seen(&self, repo_id: u64) -> anyhow::Result<OnSeen> {
         let deletion_strings: Vec<String> = self
             .conn
             .send(resp_array![
@@ -133,182 +568,613 @@ impl Conn {
             .await
             .context("Failed fetching dereact list")?;
 
-        let mut deletions = Vec::with_capacity(deletion_strings.len());
-        for deletion in deletion_strings {
-            deletions.push(
-                deletion
-                    .parse::<u64>()
-                    .context("Deletion ID is not integer")?,
-            );
-        }
-        let mut dereacts = Vec::with_capacity(dereact_strings.len());
-        for dereact in dereact_strings {
-            dereacts.push(
-                dereact
-                    .parse::<u64>()
-                    .context("Dereact ID is not integer")?,
-            );
-        }
+        Ok(OnSeen {
+            deletions: parse_ids(deletion_strings, "Deletion ID")?,
+            dereacts: parse_ids(dereact_strings, "Dereact ID")?,
+        })
+    }
 
-        let on_seen = OnSeen {
-            deletions,
-            dereacts,
-        };
-        let json = serde_json::to_string(&on_seen)?;
-        self.conn
-            .send(resp_array!["PUBLISH", "on_seen", json])
+    /// Lists the repos granted to an installation of the GitHub App.
+    pub async fn installation_repos(&self, installation_id: u64) -> anyhow::Result<Vec<u64>> {
+        let repos: Vec<String> = self
+            .conn
+            .send(resp_array![
+                "SMEMBERS",
+                format!("installation:{}", installation_id)
+            ])
             .await
-            .context("Failed to publish on_seen")?;
+            .context("Could not fetch installation repos")?;
+        repos
+            .into_iter()
+            .map(|id| id.parse().context("Repo ID is not an integer"))
+            .collect()
+    }
 
+    pub async fn add_installation_repos(
+        &self,
+        installation_id: u64,
+        repo_ids: &[u64],
+    ) -> anyhow::Result<()> {
+        if repo_ids.is_empty() {
+            return Ok(());
+        }
+        let _: usize = self
+            .conn
+            .send(
+                resp_array!["SADD", format!("installation:{}", installation_id)]
+                    .append(repo_ids.iter().map(|id| id.to_string())),
+            )
+            .await
+            .context("Could not add installation repos")?;
         Ok(())
     }
 
-    async fn mark_seen(&self, repo_id: u64) -> anyhow::Result<bool> {
-        let changed: bool = self
+    pub async fn remove_installation_repos(
+        &self,
+        installation_id: u64,
+        repo_ids: &[u64],
+    ) -> anyhow::Result<()> {
+        if repo_ids.is_empty() {
+            return Ok(());
+        }
+        let _: usize = self
             .conn
-            .send(resp_array!["SADD", "seen", repo_id.to_string()])
+            .send(
+                resp_array!["SREM", format!("installation:{}", installation_id)]
+                    .append(repo_ids.iter().map(|id| id.to_string())),
+            )
             .await
-            .context("Error marking repo as seen")?;
-        Ok(changed)
+            .context("Could not remove installation repos")?;
+        Ok(())
     }
 
-    async fn mark_unseen(&self, repo_id: u64) -> anyhow::Result<bool> {
-        let changed: bool = self
+    pub async fn delete_installation(&self, installation_id: u64) -> anyhow::Result<()> {
+        let _: usize = self
             .conn
-            .send(resp_array!["SADD", "seen", repo_id.to_string()])
+            .send(resp_array![
+                "DEL",
+                format!("installation:{}", installation_id)
+            ])
             .await
-            .context("Error marking repo as seen")?;
-        Ok(changed)
+            .context("Could not delete installation")?;
+        Ok(())
     }
 
-    pub async fn is_seen(&self, repo_id: u64) -> anyhow::Result<bool> {
-        let found: Vec<bool> = self
+    /// Returns whether a repo is covered by the GitHub App.
+    pub async fn is_seen(&self, repo_id: u64) -> anyhow::Result<SeenStatus> {
+        let mut fields: HashMap<String, String> = self
             .conn
-            .send(resp_array!["SMISMEMBER", "seen", repo_id.to_string()])
+            .send(resp_array!["HGETALL", format!("repo-info:{}", repo_id)])
             .await
             .context("Error checking repo seen status")?;
-        Ok(*found
-            .get(0)
-            .expect("SMISMEMBER ret count = param count - 1"))
-    }
 
-    pub async fn on_repo_update(&self, repo_id: u64, user: &str, repo: &str) -> anyhow::Result<()> {
-        for update in self.repo_updates(repo_id, user, repo).await? {
-            let json = serde_json::to_string(&update)?;
-            self.conn
-                .send(resp_array!["PUBLISH", "updates", json])
-                .await?;
-        }
-        Ok(())
+        let installation_id = match fields.remove("installation") {
+            Some(id) => id.parse().context("Installation ID is not an integer")?,
+            None => return Ok(SeenStatus::NotInstalled),
+        };
+        let parse_time = |value: Option<String>| {
+            value
+                .map(|value| value.parse::<u64>())
+                .transpose()
+                .context("Timestamp is not an integer")
+        };
+        let info = RepoInstallation {
+            installation_id,
+            account: fields.remove("account").unwrap_or_default(),
+            permissions: match fields.remove("permissions") {
+                Some(json) => {
+                    serde_json::from_str(&json).context("Permissions has incorrect format")?
+                }
+                None => BTreeMap::new(),
+            },
+            first_seen: parse_time(fields.remove("first_seen"))?,
+            last_push: parse_time(fields.remove("last_push"))?,
+        };
+
+        Ok(
+            if fields.get("suspended").map(String::as_str) == Some("1") {
+                SeenStatus::Suspended(info)
+            } else {
+                SeenStatus::Installed(info)
+            },
+        )
     }
 
-    async fn repo_updates(
+    /// Records the repo name and returns an update for each mirror group of the pushed branch,
+    /// pinned to the pushed commit and not rendered yet.
+    ///
+    /// Frozen groups are skipped.
+    pub async fn on_repo_update(
         &self,
         repo_id: u64,
         user: &str,
         repo: &str,
+        push: Push<'_>,
     ) -> anyhow::Result<Vec<Update>> {
-        #[allow(clippy::unnecessary_wraps)]
-        fn ok<T>(t: T) -> anyhow::Result<T> {
-            Ok(t)
+        self.set_repo_name(repo_id, &format!("{}/{}", user, repo))
+            .await?;
+        self.repo_updates(repo_id, user, repo, push).await
+    }
+
+    pub async fn publish_update(&self, update: &Update) -> anyhow::Result<()> {
+        let json = serde_json::to_string(update)?;
+        let _: usize = self
+            .conn
+            .send(resp_array!["PUBLISH", "updates", json])
+            .await
+            .context("Failed to publish update")?;
+        metrics::UPDATES_PUBLISHED.inc();
+        Ok(())
+    }
+
+    /// Lists the IDs of all repos with at least one mirror group.
+    pub async fn repos(&self) -> anyhow::Result<Vec<u64>> {
+        let mut repos = Vec::new();
+        let mut cursor = String::from("0");
+        loop {
+            let (next, keys): (String, Vec<String>) = self
+                .conn
+                .send(resp_array![
+                    "SCAN", cursor, "MATCH", "repo:*", "COUNT", "100"
+                ])
+                .await
+                .context("Could not scan repos")?;
+            for key in keys {
+                let id = key
+                    .strip_prefix("repo:")
+                    .and_then(|id| id.parse().ok())
+                    .context("Repo key has incorrect format")?;
+                repos.push(id);
+            }
+            if next == "0" {
+                break;
+            }
+            cursor = next;
         }
+        repos.sort_unstable();
+        repos.dedup();
+        Ok(repos)
+    }
 
+    /// Returns the last known `user/repo` name of a repo.
+    pub async fn repo_name(&self, repo_id: u64) -> anyhow::Result<Option<String>> {
+        let name: Option<String> = self
+            .conn
+            .send(resp_array!["GET", format!("repo-name:{}", repo_id)])
+            .await
+            .context("Could not fetch repo name")?;
+        Ok(name)
+    }
+
+    async fn set_repo_name(&self, repo_id: u64, name: &str) -> anyhow::Result<()> {
+        let _: String = self
+            .conn
+            .send(resp_array!["SET", format!("repo-name:{}", repo_id), name])
+            .await
+            .context("Could not store repo name")?;
+        Ok(())
+    }
+
+    pub async fn repo_groups(&self, repo_id: u64) -> anyhow::Result<Vec<String>> {
         let groups: Vec<String> = self
             .conn
             .send(resp_array!["SMEMBERS", format!("repo:{}", repo_id)])
             .await
             .context("Could not fetch repo mirror groups")?;
+        Ok(groups)
+    }
 
-        let updates = groups.iter().map(|id| async move {
-            let path = async move {
-                let path_key = format!("mirror-group:{}:path", id);
-                let value: String = self
-                    .conn
-                    .send(resp_array!["GET", path_key])
-                    .await
-                    .context("Could not fetch mirrored file path")?;
-                ok(value)
-            };
-            let channel_id = async move {
-                let channel_key = format!("mirror-group:{}:channel", id);
-                let value: String = self
-                    .conn
-                    .send(resp_array!["GET", channel_key])
-                    .await
-                    .context("Could not fetch mirror channel ID")?;
-                let value = value
-                    .parse::<u64>()
-                    .context("Channel ID is not an integer")?;
-                ok(value)
-            };
-            let message_ids = async move {
-                let messages_key = format!("mirror-group:{}:messages", id);
-                let value: Vec<String> = self
-                    .conn
-                    .send(resp_array!["LANGE", messages_key])
-                    .await
-                    .context("Could not fetch mirror message list")?;
-                let value: Vec<u64> = value
-                    .into_iter()
-                    .map(|s| s.parse().context("Message ID has incorrect format"))
-                    .collect::<anyhow::Result<_>>()?;
-                ok(value)
-            };
+    pub async fn has_group(&self, repo_id: u64, id: &str) -> anyhow::Result<bool> {
+        let found: bool = self
+            .conn
+            .send(resp_array!["SISMEMBER", format!("repo:{}", repo_id), id])
+            .await
+            .context("Could not check mirror group")?;
+        Ok(found)
+    }
+
+    pub async fn group(&self, id: &str) -> anyhow::Result<Group> {
+        fn parse<T: std::str::FromStr>(
+            value: Option<String>,
+            what: &str,
+        ) -> anyhow::Result<Option<T>> {
+            value
+                .map(|value| value.parse::<T>())
+                .transpose()
+                .map_err(|_| anyhow::anyhow!("{} has incorrect format", what))
+        }
+
+        let keys = [
+            "path",
+            "channel",
+            "lifecycle",
+            "mode",
+            "format",
+            "select",
+            "split",
+            "header",
+            "footer",
+            "blob",
+            "guild",
+            "repo",
+            "updated",
+            "frozen",
+        ]
+        .iter()
+        .map(|field| format!("mirror-group:{}:{}", id, field));
+        let values: Vec<Option<String>> = self
+            .conn
+            .send(resp_array!["MGET"].append(keys))
+            .await
+            .context("Could not fetch mirror group")?;
+        let [path, channel_id, lifecycle, mode, file_format, select, split, header, footer, blob, guild_id, repo_id, updated, frozen]: [Option<String>; 14] =
+            values
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("MGET ret count = param count - 1"))?;
+
+        let message_ids: Vec<String> = self
+            .conn
+            .send(resp_array![
+                "LRANGE",
+                format!("mirror-group:{}:messages", id),
+                "0",
+                "-1"
+            ])
+            .await
+            .context("Could not fetch mirror message list")?;
+        let message_ids: Vec<u64> = message_ids
+            .into_iter()
+            .map(|s| s.parse().context("Message ID has incorrect format"))
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Group {
+            path: path.context("Could not fetch mirrored file path")?,
+            channel_id: parse(channel_id, "Channel ID")?
+                .context("Could not fetch mirror channel ID")?,
+            message_ids,
+            lifecycle: parse(lifecycle, "Lifecycle policy")?.unwrap_or_default(),
+            mode: parse(mode, "Render mode")?.unwrap_or_default(),
+            format: format::Spec {
+                format: parse(file_format, "File format")?.unwrap_or_default(),
+                select,
+            },
+            split: parse(split, "Split strategy")?.unwrap_or_default(),
+            frame: template::Frame {
+                header: header.unwrap_or_default(),
+                footer: footer.unwrap_or_default(),
+            },
+            blob,
+            guild_id: parse(guild_id, "Guild ID")?,
+            repo_id: parse(repo_id, "Repo ID")?,
+            updated: parse(updated, "Update timestamp")?,
+            frozen: frozen.is_some(),
+        })
+    }
+
+    /// Lists the IDs of the mirror groups posted in a guild.
+    pub async fn guild_groups(&self, guild_id: u64) -> anyhow::Result<Vec<String>> {
+        let groups: Vec<String> = self
+            .conn
+            .send(resp_array!["SMEMBERS", format!("guild:{}", guild_id)])
+            .await
+            .context("Could not fetch guild mirror groups")?;
+        Ok(groups)
+    }
+
+    /// Records that the messages of a mirror group were last rendered now.
+    pub async fn mark_group_updated(&self, id: &str) -> anyhow::Result<()> {
+        let _: String = self
+            .conn
+            .send(resp_array![
+                "SET",
+                format!("mirror-group:{}:updated", id),
+                unix_now().to_string()
+            ])
+            .await
+            .context("Could not store mirror update time")?;
+        Ok(())
+    }
+
+    /// Marks a mirror group as frozen, so that pushes no longer update it.
+    ///
+    /// Returns whether the group was not frozen before.
+    pub async fn freeze_group(&self, id: &str) -> anyhow::Result<bool> {
+        let set: Option<String> = self
+            .conn
+            .send(resp_array![
+                "SET",
+                format!("mirror-group:{}:frozen", id),
+                unix_now().to_string(),
+                "NX"
+            ])
+            .await
+            .context("Could not freeze mirror group")?;
+        Ok(set.is_some())
+    }
 
-            let (path, channel_id, message_ids) =
-                future::try_join3(path, channel_id, message_ids).await?;
+    /// Records the git blob SHA of the file attached to the messages of a mirror group.
+    pub async fn set_group_blob(&self, id: &str, sha: &str) -> anyhow::Result<()> {
+        let _: String = self
+            .conn
+            .send(resp_array!["SET", format!("mirror-group:{}:blob", id), sha])
+            .await
+            .context("Could not store mirror attachment SHA")?;
+        Ok(())
+    }
+
+    /// Appends messages to a mirror group.
+    pub async fn push_group_messages(&self, id: &str, message_ids: &[u64]) -> anyhow::Result<()> {
+        if message_ids.is_empty() {
+            return Ok(());
+        }
+        let _: usize = self
+            .conn
+            .send(
+                resp_array!["RPUSH", format!("mirror-group:{}:messages", id)]
+                    .append(message_ids.iter().map(|id| id.to_string())),
+            )
+            .await
+            .context("Could not add mirror messages")?;
+        for &message_id in message_ids {
+            let _: String = self
+                .conn
+                .send(resp_array![
+                    "SET",
+                    format!("mirror-group-rev:{}", message_id),
+                    id
+                ])
+                .await
+                .context("Could not store mirror message owner")?;
+        }
+        Ok(())
+    }
 
-            ok(Update {
-                channel_id,
-                message_ids,
-                url: format!(
-                    "https://raw.githubusercontent.com/{}/{}/{}",
-                    user, repo, path
+    /// Removes messages from the end of a mirror group.
+    pub async fn pop_group_messages(&self, id: &str, message_ids: &[u64]) -> anyhow::Result<()> {
+        if message_ids.is_empty() {
+            return Ok(());
+        }
+        let end = -(message_ids.len() as i64) - 1;
+        let _: String = self
+            .conn
+            .send(resp_array![
+                "LTRIM",
+                format!("mirror-group:{}:messages", id),
+                "0",
+                end.to_string()
+            ])
+            .await
+            .context("Could not remove mirror messages")?;
+        let _: usize = self
+            .conn
+            .send(
+                resp_array!["DEL"].append(
+                    message_ids
+                        .iter()
+                        .map(|message_id| format!("mirror-group-rev:{}", message_id)),
                 ),
-            })
+            )
+            .await
+            .context("Could not delete mirror message owners")?;
+        Ok(())
+    }
+
+    async fn repo_updates(
+        &self,
+        repo_id: u64,
+        user: &str,
+        repo: &str,
+        push: Push<'_>,
+    ) -> anyhow::Result<Vec<Update>> {
+        let groups = self.repo_groups(repo_id).await?;
+        let installation_id = self.is_seen(repo_id).await?.installation_id();
+        let name = format!("{}/{}", user, repo);
+        let name = &name;
+        let prefix = format!("{}/", push.branch);
+        let prefix = &prefix;
+
+        let updates = groups.iter().map(|id| async move {
+            let group = self.group(id).await?;
+            if group.frozen {
+                return Ok(None);
+            }
+            // matching the branch as a prefix also handles branch names containing `/`
+            let file = match group.path.strip_prefix(prefix.as_str()) {
+                Some(file) => file,
+                None => return Ok(None),
+            };
+            let url = self.github.blob_url(name, &group.path);
+            Ok::<_, anyhow::Error>(Some(Update {
+                group: id.clone(),
+                channel_id: group.channel_id,
+                embed: group
+                    .mode
+                    .embed(repo_id, name, &group.path, &url, Some(push.commit)),
+                vars: template::Vars::new(name, &group.path, Some(push.commit), push.author),
+                attachment: None,
+                url,
+                contents_url: self.github.contents_url(name, file, push.commit),
+                commit: Some(push.commit.to_string()),
+                installation_id,
+                pages: Vec::new(),
+                fields: Vec::new(),
+                format: group.format,
+                split: group.split,
+                frame: group.frame,
+                message_ids: group.message_ids,
+                published_at: metrics::unix_millis(),
+            }))
         });
         let updates = future::try_join_all(updates).await?;
 
-        Ok(updates)
+        Ok(updates.into_iter().flatten().collect())
     }
 
-    pub async fn add_update(
+    /// Returns an update of a single mirror group from the head of its branch, not rendered yet.
+    pub async fn group_update(&self, repo_id: u64, id: &str) -> anyhow::Result<Update> {
+        let name = self
+            .repo_name(repo_id)
+            .await?
+            .context("The repo name is unknown")?;
+        let group = self.group(id).await?;
+        let installation_id = self.is_seen(repo_id).await?.installation_id();
+        let url = self.github.blob_url(&name, &group.path);
+        Ok(Update {
+            group: id.to_string(),
+            channel_id: group.channel_id,
+            embed: group.mode.embed(repo_id, &name, &group.path, &url, None),
+            vars: template::Vars::new(&name, &group.path, None, None),
+            attachment: None,
+            url,
+            contents_url: self.github.branch_contents_url(&name, &group.path),
+            message_ids: group.message_ids,
+            commit: None,
+            installation_id,
+            pages: Vec::new(),
+            fields: Vec::new(),
+            format: group.format,
+            split: group.split,
+            frame: group.frame,
+            published_at: metrics::unix_millis(),
+        })
+    }
+
+    pub async fn on_repo_lifecycle(
         &self,
         repo_id: u64,
-        path: &str,
-        channel: u64,
-        message_ids: &[u64],
+        repo_name: &str,
+        event: LifecycleEvent,
     ) -> anyhow::Result<()> {
-        use rand::Rng;
+        let groups = self.repo_groups(repo_id).await?;
 
-        let id: String = rand::thread_rng()
-            .sample_iter(&rand::distributions::Alphanumeric)
-            .take(16)
-            .map(char::from)
-            .collect();
+        let lifecycles = groups.into_iter().map(|id| async move {
+            let group = self.group(&id).await?;
+            Ok::<_, anyhow::Error>(Lifecycle {
+                repo_id,
+                repo_name: repo_name.to_string(),
+                group: id,
+                channel_id: group.channel_id,
+                message_ids: group.message_ids,
+                event,
+                policy: group.lifecycle,
+            })
+        });
+        for lifecycle in future::try_join_all(lifecycles).await? {
+            let json = serde_json::to_string(&lifecycle)?;
+            let _: usize = self
+                .conn
+                .send(resp_array!["PUBLISH", "lifecycle", json])
+                .await
+                .context("Failed to publish lifecycle")?;
+        }
+        Ok(())
+    }
+
+    pub async fn delete_group(&self, repo_id: u64, id: &str) -> anyhow::Result<()> {
+        let group = self.group(id).await?;
+
+        let _: usize = self
+            .conn
+            .send(resp_array!["SREM", format!("repo:{}", repo_id), id])
+            .await
+            .context("Could not remove mirror group from repo")?;
+        if let Some(guild_id) = group.guild_id {
+            let _: usize = self
+                .conn
+                .send(resp_array!["SREM", format!("guild:{}", guild_id), id])
+                .await
+                .context("Could not remove mirror group from guild")?;
+        }
+        let _: usize = self
+            .conn
+            .send(
+                resp_array![
+                    "DEL",
+                    format!("mirror-group:{}:path", id),
+                    format!("mirror-group:{}:channel", id),
+                    format!("mirror-group:{}:messages", id),
+                    format!("mirror-group:{}:lifecycle", id),
+                    format!("mirror-group:{}:mode", id),
+                    format!("mirror-group:{}:format", id),
+                    format!("mirror-group:{}:select", id),
+                    format!("mirror-group:{}:split", id),
+                    format!("mirror-group:{}:header", id),
+                    format!("mirror-group:{}:footer", id),
+                    format!("mirror-group:{}:blob", id),
+                    format!("mirror-group:{}:guild", id),
+                    format!("mirror-group:{}:repo", id),
+                    format!("mirror-group:{}:updated", id),
+                    format!("mirror-group:{}:frozen", id)
+                ]
+                .append(
+                    group
+                        .message_ids
+                        .iter()
+                        .map(|message_id| format!("mirror-group-rev:{}", message_id)),
+                ),
+            )
+            .await
+            .context("Could not delete mirror group")?;
+        Ok(())
+    }
+
+    /// Stores a new mirror group and returns its ID.
+    pub async fn add_update(&self, new: &NewGroup<'_>) -> anyhow::Result<String> {
+        let id = random_id(16);
         let id = id.as_str();
         let changed: bool = self
             .conn
-            .send(resp_array!["SADD", format!("repo:{}", repo_id), id])
+            .send(resp_array!["SADD", format!("repo:{}", new.repo_id), id])
             .await
             .context("Could not add mirror group to repo")?;
         assert!(changed, "Duplicate ID???");
+        self.set_repo_name(new.repo_id, new.repo_name).await?;
 
-        let path_future = self.conn.send(resp_array![
-            "SET",
-            format!("mirror-group:{}:path", id),
-            path
-        ]);
-        let channel_future = self.conn.send(resp_array![
-            "SET",
-            format!("mirror-group:{}:channel", id),
-            channel.to_string()
-        ]);
+        // optional fields are absent rather than empty
+        let optional = [
+            ("select", new.format.select.as_deref().unwrap_or_default()),
+            ("header", new.frame.header.as_str()),
+            ("footer", new.frame.footer.as_str()),
+            ("blob", new.blob.unwrap_or_default()),
+        ];
+        let optional = optional
+            .iter()
+            .filter(|(_, value)| !value.is_empty())
+            .flat_map(|&(field, value)| {
+                vec![format!("mirror-group:{}:{}", id, field), value.to_string()]
+            });
+        let fields_future = self.conn.send(
+            resp_array![
+                "MSET",
+                format!("mirror-group:{}:path", id),
+                new.path,
+                format!("mirror-group:{}:channel", id),
+                new.channel_id.to_string(),
+                format!("mirror-group:{}:lifecycle", id),
+                new.lifecycle.as_str(),
+                format!("mirror-group:{}:mode", id),
+                new.mode.as_str(),
+                format!("mirror-group:{}:format", id),
+                new.format.format.as_str(),
+                format!("mirror-group:{}:split", id),
+                new.split.as_str(),
+                format!("mirror-group:{}:guild", id),
+                new.guild_id.to_string(),
+                format!("mirror-group:{}:repo", id),
+                new.repo_id.to_string(),
+                format!("mirror-group:{}:updated", id),
+                unix_now().to_string()
+            ]
+            .append(optional),
+        );
+        let guild_future =
+            self.conn
+                .send(resp_array!["SADD", format!("guild:{}", new.guild_id), id]);
         let messages_future = self.conn.send(
             resp_array!["RPUSH", format!("mirror-group:{}:messages", id)]
-                .append(message_ids.iter().map(|id| id.to_string())),
+                .append(new.message_ids.iter().map(|id| id.to_string())),
         );
-        let rev_futures = message_ids.iter().map(|&message_id| async move {
+        let rev_futures = new.message_ids.iter().map(|&message_id| async move {
             let _: String = self
                 .conn
                 .send(resp_array![
@@ -320,45 +1186,254 @@ impl Conn {
             Ok(())
         });
         let rev_future = future::try_join_all(rev_futures);
-        let _: (String, String, usize, _) =
-            future::try_join4(path_future, channel_future, messages_future, rev_future).await?;
+        let _: (String, usize, usize, _) =
+            future::try_join4(fields_future, guild_future, messages_future, rev_future).await?;
 
-        Ok(())
+        Ok(id.to_string())
     }
 
+    /// Queues a message to delete when a repo becomes installed.
     pub async fn delete_on_seen(
         &self,
         repo_id: u64,
         channel_id: u64,
         message_id: u64,
+        limits: &secret::OnSeen,
     ) -> anyhow::Result<()> {
-        let _: bool = self
-            .conn
-            .send(resp_array![
-                "RPUSH",
-                format!("delete-on-seen:{}", repo_id),
-                channel_id.to_string(),
-                message_id.to_string(),
-            ])
-            .await?;
-        Ok(())
+        self.push_on_seen(
+            &format!("delete-on-seen:{}", repo_id),
+            channel_id,
+            message_id,
+            limits,
+        )
+        .await
     }
 
+    /// Queues a message to remove reactions from when a repo becomes installed.
     pub async fn dereact_on_seen(
         &self,
         repo_id: u64,
         channel_id: u64,
         message_id: u64,
+        limits: &secret::OnSeen,
+    ) -> anyhow::Result<()> {
+        self.push_on_seen(
+            &format!("dereact-on-seen:{}", repo_id),
+            channel_id,
+            message_id,
+            limits,
+        )
+        .await
+    }
+
+    /// Appends a channel + message pair to an on-seen queue,
+    /// dropping the oldest pairs beyond `limits.max_len` and refreshing the expiry.
+    async fn push_on_seen(
+        &self,
+        key: &str,
+        channel_id: u64,
+        message_id: u64,
+        limits: &secret::OnSeen,
     ) -> anyhow::Result<()> {
         let _: usize = self
             .conn
             .send(resp_array![
                 "RPUSH",
-                format!("dereact-on-seen:{}", repo_id),
+                key,
                 channel_id.to_string(),
                 message_id.to_string(),
             ])
             .await?;
+        let _: String = self
+            .conn
+            .send(resp_array![
+                "LTRIM",
+                key,
+                (-2 * limits.max_len as i64).to_string(),
+                "-1"
+            ])
+            .await?;
+        let _: bool = self
+            .conn
+            .send(resp_array!["EXPIRE", key, limits.ttl.to_string()])
+            .await?;
+        Ok(())
+    }
+
+    /// Schedules a "not installed" warning to be abandoned after `ttl` seconds.
+    pub async fn expire_warning(&self, warning: &PendingWarning, ttl: u64) -> anyhow::Result<()> {
+        let _: usize = self
+            .conn
+            .send(resp_array![
+                "ZADD",
+                "pending-warnings",
+                (unix_now() + ttl).to_string(),
+                serde_json::to_string(warning)?
+            ])
+            .await
+            .context("Error scheduling warning expiry")?;
+        Ok(())
+    }
+
+    /// Removes and returns the warnings whose expiry has passed.
+    pub async fn take_expired_warnings(&self) -> anyhow::Result<Vec<PendingWarning>> {
+        let members: Vec<String> = self
+            .conn
+            .send(resp_array![
+                "ZRANGEBYSCORE",
+                "pending-warnings",
+                "-inf",
+                unix_now().to_string()
+            ])
+            .await
+            .context("Error fetching expired warnings")?;
+
+        let mut warnings = Vec::new();
+        for member in members {
+            // another bot instance may have taken the same warning
+            let removed: usize = self
+                .conn
+                .send(resp_array!["ZREM", "pending-warnings", &member])
+                .await
+                .context("Error removing expired warning")?;
+            if removed == 0 {
+                continue;
+            }
+            match serde_json::from_str(&member) {
+                Ok(warning) => warnings.push(warning),
+                Err(err) => log::error!("Invalid pending warning {:?}: {}", member, err),
+            }
+        }
+        Ok(warnings)
+    }
+
+    pub async fn publish_command(&self, command: &Command) -> anyhow::Result<()> {
+        let json = serde_json::to_string(command)?;
+        let _: usize = self
+            .conn
+            .send(resp_array!["PUBLISH", "commands", json])
+            .await
+            .context("Failed to publish command")?;
+        Ok(())
+    }
+
+    /// Records a webhook delivery ID, returning `false` if it was already recorded.
+    pub async fn claim_delivery(&self, id: &str, ttl: u64) -> anyhow::Result<bool> {
+        let set: Option<String> = self
+            .conn
+            .send(resp_array![
+                "SET",
+                format!("delivery:{}", id),
+                "1",
+                "NX",
+                "EX",
+                ttl.to_string()
+            ])
+            .await
+            .context("Could not record delivery")?;
+        Ok(set.is_some())
+    }
+
+    /// Forgets a webhook delivery ID so that a retry of it is handled again.
+    pub async fn release_delivery(&self, id: &str) -> anyhow::Result<()> {
+        let _: usize = self
+            .conn
+            .send(resp_array!["DEL", format!("delivery:{}", id)])
+            .await
+            .context("Could not release delivery")?;
+        Ok(())
+    }
+
+    pub async fn put_oauth_state(&self, state: &str, ttl: u64) -> anyhow::Result<()> {
+        let _: String = self
+            .conn
+            .send(resp_array![
+                "SET",
+                format!("oauth-state:{}", state),
+                "1",
+                "EX",
+                ttl.to_string()
+            ])
+            .await
+            .context("Could not store OAuth state")?;
+        Ok(())
+    }
+
+    /// Consumes an OAuth state, returning whether it was valid.
+    pub async fn take_oauth_state(&self, state: &str) -> anyhow::Result<bool> {
+        let deleted: usize = self
+            .conn
+            .send(resp_array!["DEL", format!("oauth-state:{}", state)])
+            .await
+            .context("Could not check OAuth state")?;
+        Ok(deleted > 0)
+    }
+
+    pub async fn put_session<T: serde::Serialize>(
+        &self,
+        id: &str,
+        session: &T,
+        ttl: u64,
+    ) -> anyhow::Result<()> {
+        let json = serde_json::to_string(session)?;
+        let _: String = self
+            .conn
+            .send(resp_array![
+                "SET",
+                format!("session:{}", id),
+                json,
+                "EX",
+                ttl.to_string()
+            ])
+            .await
+            .context("Could not store session")?;
+        Ok(())
+    }
+
+    pub async fn session<T: serde::de::DeserializeOwned>(
+        &self,
+        id: &str,
+    ) -> anyhow::Result<Option<T>> {
+        let json: Option<String> = self
+            .conn
+            .send(resp_array!["GET", format!("session:{}", id)])
+            .await
+            .context("Could not fetch session")?;
+        json.map(|json| serde_json::from_str(&json).context("Session has incorrect format"))
+            .transpose()
+    }
+
+    pub async fn delete_session(&self, id: &str) -> anyhow::Result<()> {
+        let _: usize = self
+            .conn
+            .send(resp_array!["DEL", format!("session:{}", id)])
+            .await
+            .context("Could not delete session")?;
+        Ok(())
+    }
+
+    /// Returns the fields of the runtime settings hash.
+    pub async fn settings(&self) -> anyhow::Result<HashMap<String, String>> {
+        let settings = self
+            .conn
+            .send(resp_array!["HGETALL", "settings"])
+            .await
+            .context("Could not fetch settings")?;
+        Ok(settings)
+    }
+
+    /// Overwrites the fields of the runtime settings hash.
+    pub async fn set_settings(&self, fields: &[(&str, String)]) -> anyhow::Result<()> {
+        let command = resp_array!["HSET", "settings"].append(
+            fields
+                .iter()
+                .flat_map(|(name, value)| vec![name.to_string(), value.clone()]),
+        );
+        let _: usize = self
+            .conn
+            .send(command)
+            .await
+            .context("Could not store settings")?;
         Ok(())
     }
 }
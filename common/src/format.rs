@@ -0,0 +1,676 @@
+//! Format-aware rendering of structured data files.
+//!
+//! JSON, YAML and TOML files are parsed, optionally narrowed to a sub-tree,
+//! and pretty-printed in a code block or, in `render::Mode::Fields`, as embed fields.
+//! Files that fail to parse are shown as raw text with a warning.
+
+use std::fmt;
+
+use crate::attachment;
+use crate::markdown;
+use crate::render::{self, Embed, Field};
+use crate::template::{self, Frame, Vars};
+
+/// Maximum length of an embed field name in bytes
+const FIELD_NAME_MAX_LENGTH: usize = 256;
+/// Maximum length of an embed field value in bytes
+const FIELD_VALUE_MAX_LENGTH: usize = 1024;
+/// Maximum number of fields in an embed
+const MAX_FIELDS: usize = 25;
+
+/// The format that a file is parsed as
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    /// Shown as is.
+    #[default]
+    Raw,
+    Json,
+    Yaml,
+    Toml,
+    /// Transformed into Discord markdown, see `markdown::transform`.
+    Markdown,
+    /// Uploaded as a message attachment, see `attachment::Attachment`.
+    Attachment,
+}
+
+impl Format {
+    /// Detects the format from the extension of a path.
+    pub fn detect(path: &str) -> Self {
+        let extension = path.rsplit('/').next().and_then(|file| {
+            let dot = file.rfind('.')?;
+            Some(file[dot + 1..].to_ascii_lowercase())
+        });
+        match extension.as_deref() {
+            Some("json") => Self::Json,
+            Some("yaml") | Some("yml") => Self::Yaml,
+            Some("toml") => Self::Toml,
+            Some("md") | Some("markdown") => Self::Markdown,
+            _ if attachment::mime_by_extension(path).is_some() => Self::Attachment,
+            _ => Self::Raw,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Raw => "raw",
+            Self::Json => "json",
+            Self::Yaml => "yaml",
+            Self::Toml => "toml",
+            Self::Markdown => "markdown",
+            Self::Attachment => "attachment",
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Raw => "text",
+            Self::Json => "JSON",
+            Self::Yaml => "YAML",
+            Self::Toml => "TOML",
+            Self::Markdown => "markdown",
+            Self::Attachment => "attachment",
+        }
+    }
+}
+
+impl std::str::FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s {
+            "raw" => Self::Raw,
+            "json" => Self::Json,
+            "yaml" => Self::Yaml,
+            "toml" => Self::Toml,
+            "markdown" => Self::Markdown,
+            "attachment" => Self::Attachment,
+            _ => anyhow::bail!("Unknown file format {:?}", s),
+        })
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// How the file of a mirror group is parsed
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Spec {
+    #[serde(default)]
+    pub format: Format,
+    /// The sub-tree to show, as a JSON pointer (`/rules/0`) or a key path (`rules.0`)
+    #[serde(default)]
+    pub select: Option<String>,
+}
+
+/// A parsed file
+enum Tree {
+    Json(serde_json::Value),
+    Yaml(serde_yaml::Value),
+    Toml(toml::Value),
+}
+
+impl Tree {
+    fn parse(text: &str, format: Format) -> anyhow::Result<Option<Self>> {
+        Ok(Some(match format {
+            Format::Raw | Format::Markdown | Format::Attachment => return Ok(None),
+            Format::Json => Self::Json(serde_json::from_str(text)?),
+            Format::Yaml => Self::Yaml(serde_yaml::from_str(text)?),
+            Format::Toml => Self::Toml(toml::from_str(text)?),
+        }))
+    }
+
+    fn format(&self) -> Format {
+        match self {
+            Self::Json(_) => Format::Json,
+            Self::Yaml(_) => Format::Yaml,
+            Self::Toml(_) => Format::Toml,
+        }
+    }
+
+    /// Returns the child of a map key or an array index.
+    fn child(self, key: &str) -> Option<Self> {
+        let index = key.parse::<usize>();
+        Some(match self {
+            Self::Json(serde_json::Value::Object(mut map)) => Self::Json(map.remove(key)?),
+            Self::Json(serde_json::Value::Array(mut items)) => {
+                let index = index.ok().filter(|&index| index < items.len())?;
+                Self::Json(items.swap_remove(index))
+            }
+            Self::Yaml(serde_yaml::Value::Mapping(mut map)) => {
+                Self::Yaml(map.remove(&serde_yaml::Value::String(key.to_string()))?)
+            }
+            Self::Yaml(serde_yaml::Value::Sequence(mut items)) => {
+                let index = index.ok().filter(|&index| index < items.len())?;
+                Self::Yaml(items.swap_remove(index))
+            }
+            Self::Toml(toml::Value::Table(mut table)) => Self::Toml(table.remove(key)?),
+            Self::Toml(toml::Value::Array(mut items)) => {
+                let index = index.ok().filter(|&index| index < items.len())?;
+                Self::Toml(items.swap_remove(index))
+            }
+            _ => return None,
+        })
+    }
+
+    fn pretty(&self) -> anyhow::Result<String> {
+        Ok(match self {
+            Self::Json(value) => serde_json::to_string_pretty(value)?,
+            Self::Yaml(value) => {
+                let yaml = serde_yaml::to_string(value)?;
+                yaml.strip_prefix("---\n")
+                    .unwrap_or(&yaml)
+                    .trim_end()
+                    .to_string()
+            }
+            // TOML documents must be tables, so other values are shown as inline values
+            Self::Toml(toml::Value::Table(table)) => {
+                toml::to_string_pretty(table)?.trim_end().to_string()
+            }
+            Self::Toml(value) => value.to_string(),
+        })
+    }
+
+    /// Returns the scalar value as plain text, or `None` for maps and arrays.
+    fn scalar(&self) -> Option<String> {
+        match self {
+            Self::Json(serde_json::Value::String(s)) => Some(s.clone()),
+            Self::Json(serde_json::Value::Object(_)) | Self::Json(serde_json::Value::Array(_)) => {
+                None
+            }
+            Self::Json(value) => Some(value.to_string()),
+            Self::Yaml(serde_yaml::Value::String(s)) => Some(s.clone()),
+            Self::Yaml(serde_yaml::Value::Mapping(_))
+            | Self::Yaml(serde_yaml::Value::Sequence(_)) => None,
+            Self::Yaml(value) => self.pretty().ok().filter(|_| !value.is_null()),
+            Self::Toml(toml::Value::String(s)) => Some(s.clone()),
+            Self::Toml(toml::Value::Table(_)) | Self::Toml(toml::Value::Array(_)) => None,
+            Self::Toml(value) => Some(value.to_string()),
+        }
+    }
+
+    /// Returns the entries of a map or the items of an array with their keys,
+    /// or the tree itself if it is a scalar.
+    fn entries(self) -> Result<Vec<(String, Self)>, Self> {
+        fn indexed<T>(items: Vec<T>, wrap: fn(T) -> Tree) -> Vec<(String, Tree)> {
+            items
+                .into_iter()
+                .enumerate()
+                .map(|(index, item)| (index.to_string(), wrap(item)))
+                .collect()
+        }
+
+        Ok(match self {
+            Self::Json(serde_json::Value::Object(map)) => map
+                .into_iter()
+                .map(|(key, value)| (key, Self::Json(value)))
+                .collect(),
+            Self::Json(serde_json::Value::Array(items)) => indexed(items, Self::Json),
+            Self::Yaml(serde_yaml::Value::Mapping(map)) => map
+                .into_iter()
+                .map(|(key, value)| {
+                    let key = Self::Yaml(key).scalar().unwrap_or_default();
+                    (key, Self::Yaml(value))
+                })
+                .collect(),
+            Self::Yaml(serde_yaml::Value::Sequence(items)) => indexed(items, Self::Yaml),
+            Self::Toml(toml::Value::Table(table)) => table
+                .into_iter()
+                .map(|(key, value)| (key, Self::Toml(value)))
+                .collect(),
+            Self::Toml(toml::Value::Array(items)) => indexed(items, Self::Toml),
+            scalar => return Err(scalar),
+        })
+    }
+}
+
+/// Splits a JSON pointer or a dot-separated key path into keys.
+fn parse_path(select: &str) -> Vec<String> {
+    match select.strip_prefix('/') {
+        Some(pointer) => pointer
+            .split('/')
+            .map(|key| key.replace("~1", "/").replace("~0", "~"))
+            .collect(),
+        None => select
+            .split('.')
+            .filter(|key| !key.is_empty())
+            .map(str::to_string)
+            .collect(),
+    }
+}
+
+/// The text of a file prepared for pagination
+enum Document<'t> {
+    Raw {
+        text: &'t str,
+        /// Shown before the text if the file could not be parsed
+        warning: Option<String>,
+    },
+    Code {
+        text: String,
+        lang: &'static str,
+    },
+    Fields(Vec<Field>),
+    /// Sections that each start a new page
+    Sections(Vec<String>),
+}
+
+impl<'t> Document<'t> {
+    fn parse(text: &'t str, spec: &Spec, fields: bool, url: &str) -> Self {
+        if spec.format == Format::Markdown {
+            return Self::Sections(markdown::transform(text, url));
+        }
+
+        match Self::try_parse(text, spec, fields) {
+            Ok(Some(document)) => document,
+            Ok(None) => Self::Raw {
+                text,
+                warning: None,
+            },
+            Err(err) => {
+                log::warn!("Showing file as raw text: {:#}", err);
+                Self::Raw {
+                    text,
+                    warning: Some(format!(
+                        "\u{26a0}\u{fe0f} Could not parse the file as {}: {}\n",
+                        spec.format.name(),
+                        err
+                    )),
+                }
+            }
+        }
+    }
+
+    fn try_parse(text: &str, spec: &Spec, fields: bool) -> anyhow::Result<Option<Self>> {
+        let mut tree = match Tree::parse(text, spec.format)? {
+            Some(tree) => tree,
+            None => return Ok(None),
+        };
+        if let Some(select) = &spec.select {
+            for key in parse_path(select) {
+                tree = match tree.child(&key) {
+                    Some(tree) => tree,
+                    None => anyhow::bail!("{:?} does not exist", select),
+                };
+            }
+        }
+
+        if fields {
+            tree = match tree.entries() {
+                Ok(entries) => {
+                    return Ok(Some(Self::Fields(
+                        entries
+                            .into_iter()
+                            .map(|(name, value)| field(name, &value))
+                            .collect::<anyhow::Result<_>>()?,
+                    )))
+                }
+                Err(scalar) => scalar,
+            };
+        }
+        let format = tree.format();
+        Ok(Some(Self::Code {
+            text: tree.pretty()?,
+            lang: format.as_str(),
+        }))
+    }
+}
+
+/// Renders an entry as an embed field, inline if it is a scalar.
+fn field(mut name: String, value: &Tree) -> anyhow::Result<Field> {
+    truncate(&mut name, FIELD_NAME_MAX_LENGTH);
+    if name.is_empty() {
+        name = String::from("\u{200b}"); // field names must not be empty
+    }
+    let (value, inline) = match value.scalar() {
+        Some(scalar) if !scalar.contains('\n') => (scalar, true),
+        Some(scalar) => (scalar, false),
+        None => {
+            let lang = value.format().as_str();
+            let fence = format!("```{}\n", lang).len() + "\n```".len();
+            let mut code = value.pretty()?;
+            truncate(&mut code, FIELD_VALUE_MAX_LENGTH - fence);
+            (format!("```{}\n{}\n```", lang, code), false)
+        }
+    };
+    let mut value = if value.is_empty() {
+        String::from("\u{200b}")
+    } else {
+        value
+    };
+    truncate(&mut value, FIELD_VALUE_MAX_LENGTH);
+    Ok(Field {
+        name,
+        value,
+        inline,
+    })
+}
+
+/// Truncates `text` to at most `max_len` bytes, ending with an ellipsis if it was cut.
+fn truncate(text: &mut String, max_len: usize) {
+    if text.len() <= max_len {
+        return;
+    }
+    let mut end = max_len - '\u{2026}'.len_utf8();
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    text.truncate(end);
+    text.push('\u{2026}');
+}
+
+/// The rendered pages of a mirror group
+#[derive(Debug, Default, PartialEq)]
+pub struct Pages {
+    /// The content or embed description of each message
+    pub pages: Vec<String>,
+    /// The embed fields of each message; empty unless the file is rendered as fields
+    pub fields: Vec<Vec<Field>>,
+}
+
+/// Everything that decides how a file is rendered, apart from its contents
+#[derive(Debug, Clone, Copy)]
+pub struct Layout<'t> {
+    pub spec: &'t Spec,
+    /// The embed of the group; `None` in text mode
+    pub embed: Option<&'t Embed>,
+    /// The header and footer of each page
+    pub frame: &'t Frame,
+    pub vars: &'t Vars,
+    /// The file on the GitHub web interface
+    pub url: &'t str,
+}
+
+impl<'t> Layout<'t> {
+    fn fields(&self) -> bool {
+        matches!(self.embed, Some(embed) if embed.fields)
+    }
+
+    /// Returns the length available to the content of a page.
+    ///
+    /// Frames are checked by `Frame::validate` when the group is created,
+    /// but variables like the commit author can still grow the frame past the limit,
+    /// in which case `Frame::apply` shortens the frame to leave the minimum to the content.
+    fn max_length(&self) -> usize {
+        render::max_length(self.embed)
+            .saturating_sub(self.frame.reserved(self.vars))
+            .max(template::MIN_CONTENT_LENGTH)
+    }
+}
+
+/// Returns the number of messages needed to show a file in full.
+pub fn page_count(text: &str, layout: &Layout<'_>) -> usize {
+    let max_len = layout.max_length();
+    match Document::parse(text, layout.spec, layout.fields(), layout.url) {
+        Document::Raw { text, warning } => {
+            let text = format!("{}{}", warning.unwrap_or_default(), text);
+            render::split(&text, max_len).len()
+        }
+        Document::Code { text, lang } => render::split(&text, code_length(lang, max_len)).len(),
+        Document::Fields(fields) => field_chunks(fields, max_len).len().max(1),
+        Document::Sections(sections) => render::split_sections(&sections, max_len).len(),
+    }
+}
+
+/// Renders a file into exactly `count` messages.
+///
+/// Unused messages are filled with `render::RESERVED`.
+/// If the file does not fit, it is truncated with a link to the file.
+/// The header and footer are added to every page that is not reserved.
+pub fn pages(text: &str, layout: &Layout<'_>, count: usize) -> Pages {
+    let mut pages = unframed_pages(text, layout, count);
+    if !layout.frame.is_empty() {
+        let total = pages
+            .pages
+            .iter()
+            .filter(|page| *page != render::RESERVED)
+            .count();
+        for (i, page) in pages.pages.iter_mut().enumerate() {
+            if page != render::RESERVED {
+                *page = layout.frame.apply(
+                    page,
+                    layout.vars,
+                    i + 1,
+                    total,
+                    render::max_length(layout.embed),
+                );
+            }
+        }
+    }
+    pages
+}
+
+fn unframed_pages(text: &str, layout: &Layout<'_>, count: usize) -> Pages {
+    let url = layout.url;
+    let max_len = layout.max_length();
+    match Document::parse(text, layout.spec, layout.fields(), url) {
+        Document::Raw { text, warning } => {
+            let text = format!("{}{}", warning.unwrap_or_default(), text);
+            Pages {
+                pages: render::pages(&text, count, max_len, url),
+                fields: Vec::new(),
+            }
+        }
+        Document::Code { text, lang } => {
+            let mut chunks = render::split(&text, code_length(lang, max_len));
+            let mut more = None;
+            if chunks.len() > count {
+                chunks.truncate(count);
+                let note = format!("\n\u{2026}\nSee <{}> for more", url);
+                if let Some(last) = chunks.last_mut() {
+                    let end = code_length(lang, max_len.saturating_sub(note.len()));
+                    *last = &last[..render::floor_char_boundary(last, end)];
+                }
+                more = Some(note);
+            }
+            let mut pages: Vec<String> = chunks
+                .into_iter()
+                .map(|chunk| format!("```{}\n{}\n```", lang, chunk))
+                .collect();
+            if let (Some(last), Some(more)) = (pages.last_mut(), more) {
+                last.push_str(&more);
+            }
+            pages.resize(count, String::from(render::RESERVED));
+            Pages {
+                pages,
+                fields: Vec::new(),
+            }
+        }
+        Document::Sections(sections) => Pages {
+            pages: render::section_pages(&sections, count, max_len, url),
+            fields: Vec::new(),
+        },
+        Document::Fields(fields) => {
+            let mut chunks = field_chunks(fields, max_len);
+            let mut pages = vec![String::new(); chunks.len().min(count)];
+            if chunks.len() > count {
+                chunks.truncate(count);
+                if let Some(last) = pages.last_mut() {
+                    *last = format!("\u{2026}\nSee <{}> for more", url);
+                }
+            }
+            if chunks.is_empty() && count > 0 {
+                // embeds without fields need a description
+                chunks.push(Vec::new());
+                pages.push(String::from("*(empty)*"));
+            }
+            chunks.resize(count, Vec::new());
+            pages.resize(count, String::from(render::RESERVED));
+            Pages {
+                pages,
+                fields: chunks,
+            }
+        }
+    }
+}
+
+/// Returns the maximum length of the code in a page with a code block.
+fn code_length(lang: &str, max_len: usize) -> usize {
+    let fence = format!("```{}\n", lang).len() + "\n```".len();
+    max_len.saturating_sub(fence)
+}
+
+/// Groups fields into embeds within the count and total length limits.
+fn field_chunks(fields: Vec<Field>, max_len: usize) -> Vec<Vec<Field>> {
+    // leave room for the "See ... for more" description
+    let budget = max_len.saturating_sub(FIELD_NAME_MAX_LENGTH);
+
+    let mut chunks: Vec<Vec<Field>> = Vec::new();
+    let mut used = 0;
+    for field in fields {
+        let len = field.name.len() + field.value.len();
+        match chunks.last_mut() {
+            Some(chunk) if chunk.len() < MAX_FIELDS && used + len <= budget => {
+                used += len;
+                chunk.push(field);
+            }
+            _ => {
+                used = len;
+                chunks.push(vec![field]);
+            }
+        }
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(format: Format, select: Option<&str>) -> Spec {
+        Spec {
+            format,
+            select: select.map(str::to_string),
+        }
+    }
+
+    fn render(text: &str, spec: &Spec, embed: Option<&Embed>, count: usize) -> Pages {
+        let layout = Layout {
+            spec,
+            embed,
+            frame: &Frame::default(),
+            vars: &Vars::default(),
+            url: "url",
+        };
+        pages(text, &layout, count)
+    }
+
+    #[test]
+    fn detect_by_extension() {
+        assert_eq!(Format::detect("master/servers.JSON"), Format::Json);
+        assert_eq!(Format::detect("master/config/rules.yml"), Format::Yaml);
+        assert_eq!(Format::detect("master/Cargo.toml"), Format::Toml);
+        assert_eq!(Format::detect("master/README.md"), Format::Markdown);
+        assert_eq!(Format::detect("master/LICENSE"), Format::Raw);
+        assert_eq!(Format::detect("master/docs/logo.png"), Format::Attachment);
+        assert_eq!(Format::detect("v1.json/LICENSE"), Format::Raw);
+    }
+
+    #[test]
+    fn select_pretty_prints_sub_tree() {
+        let text = r#"{"servers": [{"name": "a", "port": 1}]}"#;
+        let rendered = render(text, &spec(Format::Json, Some("/servers/0")), None, 1);
+        assert_eq!(
+            rendered.pages,
+            vec!["```json\n{\n  \"name\": \"a\",\n  \"port\": 1\n}\n```"]
+        );
+
+        let text = "rules:\n  conduct: be nice\n";
+        let rendered = render(text, &spec(Format::Yaml, Some("rules")), None, 1);
+        assert_eq!(rendered.pages, vec!["```yaml\nconduct: be nice\n```"]);
+    }
+
+    #[test]
+    fn parse_failure_falls_back_to_raw() {
+        let rendered = render("not = [toml", &spec(Format::Toml, None), None, 1);
+        assert!(
+            rendered.pages[0].starts_with("\u{26a0}\u{fe0f} Could not parse the file as TOML: "),
+            "{}",
+            rendered.pages[0]
+        );
+        assert!(rendered.pages[0].ends_with("\nnot = [toml"));
+    }
+
+    #[test]
+    fn fields_mode_lists_entries() {
+        let mut embed = Embed::new(42, "SOF3/blob-mirror", "master/a.toml", "url", None);
+        embed.fields = true;
+        let text = "name = \"blob-mirror\"\n[limits]\npages = 3\n";
+        let rendered = render(text, &spec(Format::Toml, None), Some(&embed), 2);
+        assert_eq!(
+            rendered.pages,
+            vec![String::new(), String::from(render::RESERVED)]
+        );
+        assert_eq!(
+            rendered.fields,
+            vec![
+                vec![
+                    Field {
+                        name: String::from("limits"),
+                        value: String::from("```toml\npages = 3\n```"),
+                        inline: false,
+                    },
+                    Field {
+                        name: String::from("name"),
+                        value: String::from("blob-mirror"),
+                        inline: true,
+                    },
+                ],
+                Vec::new(),
+            ]
+        );
+    }
+
+    #[test]
+    fn frame_surrounds_content_pages() {
+        let frame = Frame {
+            header: String::from("Synced from {{repo}}"),
+            footer: String::from("{{page}}/{{pages}}"),
+        };
+        let vars = Vars::new("SOF3/blob-mirror", "master/LICENSE", None, None);
+        let text = "a".repeat(3000);
+        let layout = Layout {
+            spec: &Spec::default(),
+            embed: None,
+            frame: &frame,
+            vars: &vars,
+            url: "url",
+        };
+        let count = page_count(&text, &layout);
+        assert_eq!(count, 2);
+        let rendered = pages(&text, &layout, count + 1);
+        assert!(rendered.pages[0].starts_with("Synced from SOF3/blob-mirror\na"));
+        assert!(rendered.pages[1].ends_with("a\n2/2"));
+        assert_eq!(rendered.pages[2], render::RESERVED);
+        assert!(rendered
+            .pages
+            .iter()
+            .all(|page| page.len() <= render::MESSAGE_MAX_LENGTH));
+    }
+
+    #[test]
+    fn oversized_frame_leaves_minimum_content() {
+        // the author is only known at push time, so it can exceed what `Frame::validate` allows
+        let frame = Frame {
+            header: String::new(),
+            footer: String::from("by {{author}}"),
+        };
+        let author = "x".repeat(render::MESSAGE_MAX_LENGTH);
+        let vars = Vars::new("SOF3/blob-mirror", "master/LICENSE", None, Some(&author));
+        let text = "a".repeat(1000);
+        let layout = Layout {
+            spec: &Spec::default(),
+            embed: None,
+            frame: &frame,
+            vars: &vars,
+            url: "url",
+        };
+        assert_eq!(page_count(&text, &layout), 2);
+        let rendered = pages(&text, &layout, 1);
+        assert!(rendered.pages[0].starts_with(&"a".repeat(100)));
+        assert!(rendered.pages[0].len() <= render::MESSAGE_MAX_LENGTH);
+    }
+}
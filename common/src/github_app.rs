@@ -0,0 +1,183 @@
+//! Authentication as the GitHub App.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use reqwest::{header, StatusCode};
+use ring::{rand, signature};
+
+use crate::contents::USER_AGENT;
+use crate::secret;
+
+/// How long installation access tokens are reused;
+/// they expire after an hour, so they are renewed 10 minutes early
+const TOKEN_TTL: Duration = Duration::from_secs(50 * 60);
+
+/// The private key of the GitHub App
+pub struct AppKey {
+    key_pair: signature::RsaKeyPair,
+}
+
+impl AppKey {
+    /// Parses a PEM-encoded RSA private key in PKCS#1 or PKCS#8 format.
+    pub fn from_pem(pem: &str) -> anyhow::Result<Self> {
+        let mut label = None;
+        let mut body = String::new();
+        for line in pem.lines().map(str::trim) {
+            if let Some(begin) = line.strip_prefix("-----BEGIN ") {
+                label = Some(begin.trim_end_matches('-').to_string());
+            } else if line.starts_with("-----END ") {
+                break;
+            } else if label.is_some() {
+                body += line;
+            }
+        }
+        let der = base64::decode(&body).context("Private key is not valid PEM")?;
+
+        let key_pair = match label.as_deref() {
+            Some("RSA PRIVATE KEY") => signature::RsaKeyPair::from_der(&der)
+                .map_err(|err| anyhow::anyhow!("Invalid PKCS#1 private key: {}", err))?,
+            Some("PRIVATE KEY") => signature::RsaKeyPair::from_pkcs8(&der)
+                .map_err(|err| anyhow::anyhow!("Invalid PKCS#8 private key: {}", err))?,
+            Some(label) => anyhow::bail!("Unsupported PEM block {:?}", label),
+            None => anyhow::bail!("Private key is not PEM-encoded"),
+        };
+        Ok(Self { key_pair })
+    }
+
+    /// Creates a JWT to authenticate as the app, valid for 9 minutes.
+    pub fn jwt(&self, app_id: u64) -> anyhow::Result<String> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .context("System clock is before 1970")?
+            .as_secs();
+        let header = serde_json::json!({"alg": "RS256", "typ": "JWT"});
+        // backdated to allow for clock drift
+        let claims = serde_json::json!({
+            "iat": now - 60,
+            "exp": now + 9 * 60,
+            "iss": app_id.to_string(),
+        });
+
+        let message = format!(
+            "{}.{}",
+            base64::encode_config(header.to_string(), base64::URL_SAFE_NO_PAD),
+            base64::encode_config(claims.to_string(), base64::URL_SAFE_NO_PAD),
+        );
+        let mut signature = vec![0; self.key_pair.public_modulus_len()];
+        self.key_pair
+            .sign(
+                &signature::RSA_PKCS1_SHA256,
+                &rand::SystemRandom::new(),
+                message.as_bytes(),
+                &mut signature,
+            )
+            .map_err(|_| anyhow::anyhow!("Failed to sign JWT"))?;
+
+        Ok(format!(
+            "{}.{}",
+            message,
+            base64::encode_config(&signature, base64::URL_SAFE_NO_PAD)
+        ))
+    }
+}
+
+/// The GitHub App, authenticating API requests as its installations
+pub struct App {
+    id: u64,
+    key: AppKey,
+    api_url: String,
+    client: reqwest::Client,
+    /// Installation access tokens and when they are renewed, by installation ID
+    tokens: Mutex<HashMap<u64, (String, Instant)>>,
+}
+
+impl App {
+    /// Returns the app configured in `github`, or `None` if it has no private key.
+    pub fn new(github: &secret::Github, client: reqwest::Client) -> anyhow::Result<Option<Self>> {
+        let pem = match &github.private_key {
+            Some(pem) => pem,
+            None => return Ok(None),
+        };
+        Ok(Some(Self {
+            id: github.app_id.context("Checked by secret::load")?,
+            key: AppKey::from_pem(pem.expose())?,
+            api_url: github.api_url.clone(),
+            client,
+            tokens: Mutex::default(),
+        }))
+    }
+
+    /// Returns an access token of an installation, minting a new one if the last one is old.
+    pub async fn token(&self, installation_id: u64) -> anyhow::Result<String> {
+        #[derive(serde::Deserialize)]
+        struct AccessToken {
+            token: String,
+        }
+
+        if let Some((token, renew)) = self
+            .tokens
+            .lock()
+            .expect("Poisoned lock")
+            .get(&installation_id)
+        {
+            if Instant::now() < *renew {
+                return Ok(token.clone());
+            }
+        }
+
+        let renew = Instant::now() + TOKEN_TTL;
+        let token: AccessToken = self
+            .client
+            .post(format!(
+                "{}/app/installations/{}/access_tokens",
+                &self.api_url, installation_id
+            ))
+            .bearer_auth(self.key.jwt(self.id)?)
+            .header(header::USER_AGENT, USER_AGENT)
+            .header(header::ACCEPT, "application/vnd.github.v3+json")
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .context("Failed to create installation access token")?
+            .json()
+            .await
+            .context("GitHub API is not working correctly")?;
+        self.tokens
+            .lock()
+            .expect("Poisoned lock")
+            .insert(installation_id, (token.token.clone(), renew));
+        Ok(token.token)
+    }
+
+    /// Returns the ID of the installation covering a repo, or `None` if the app is not installed.
+    pub async fn repo_installation(&self, repo_name: &str) -> anyhow::Result<Option<u64>> {
+        #[derive(serde::Deserialize)]
+        struct Installation {
+            id: u64,
+        }
+
+        let response = self
+            .client
+            .get(format!(
+                "{}/repos/{}/installation",
+                &self.api_url, repo_name
+            ))
+            .bearer_auth(self.key.jwt(self.id)?)
+            .header(header::USER_AGENT, USER_AGENT)
+            .header(header::ACCEPT, "application/vnd.github.v3+json")
+            .send()
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let installation: Installation = response
+            .error_for_status()?
+            .json()
+            .await
+            .context("GitHub API is not working correctly")?;
+        Ok(Some(installation.id))
+    }
+}
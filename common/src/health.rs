@@ -0,0 +1,52 @@
+//! Liveness, readiness and metrics endpoints shared by the bot and the web service.
+
+use std::future::Future;
+
+use warp::http::StatusCode;
+use warp::reply::{self, Reply};
+use warp::Filter;
+
+use crate::{db, metrics};
+
+/// Returns the `/healthz`, `/readyz` and `/metrics` routes.
+///
+/// `ready` is the readiness check of the service,
+/// failing with the reason shown in the `503 Service Unavailable` response.
+pub fn routes<F, Fut>(
+    ready: F,
+) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone
+where
+    F: Fn() -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = Result<(), &'static str>> + Send,
+{
+    let healthz = warp::path!("healthz").map(|| "OK");
+
+    let readyz = warp::path!("readyz").and_then(move || {
+        let ready = ready.clone();
+        async move {
+            let output = match ready().await {
+                Ok(()) => reply::with_status("OK", StatusCode::OK),
+                Err(reason) => reply::with_status(reason, StatusCode::SERVICE_UNAVAILABLE),
+            };
+            Ok::<_, warp::Rejection>(output)
+        }
+    });
+
+    let metrics = warp::path!("metrics").map(|| {
+        reply::with_header(
+            metrics::render(),
+            "Content-Type",
+            "text/plain; version=0.0.4",
+        )
+    });
+
+    warp::get().and(healthz.or(readyz).or(metrics))
+}
+
+/// The readiness check of the database, shared by both services.
+pub async fn check_redis(conn: &db::Conn) -> Result<(), &'static str> {
+    conn.ping().await.map_err(|err| {
+        log::warn!("Readiness check failed: {:?}", err);
+        "Redis unreachable"
+    })
+}
@@ -1,2 +1,13 @@
+pub mod attachment;
+pub mod check;
+pub mod contents;
 pub mod db;
+pub mod format;
+pub mod github_app;
+pub mod health;
+pub mod markdown;
+pub mod metrics;
+pub mod render;
 pub mod secret;
+pub mod settings;
+pub mod template;
@@ -0,0 +1,447 @@
+//! Transforming GitHub-flavored markdown into markdown that Discord renders.
+//!
+//! Discord only understands a subset of markdown, so
+//! - relative links and images are resolved against the mirrored file on GitHub,
+//!   and images become links since Discord cannot show them inline,
+//! - tables become aligned code blocks,
+//! - HTML comments are removed and `<details>` blocks are flattened,
+//! - headings beyond `###` become bold lines,
+//! - `#` and `##` headings start a new section, which starts a new message.
+//!
+//! Fenced code blocks are left as is.
+
+use std::fmt::Write;
+
+/// Resolves links relative to a file on the GitHub web interface
+struct Links<'t> {
+    /// The file URL, e.g. `https://github.com/SOF3/blob-mirror/blob/master/docs/a.md`
+    file: &'t str,
+    /// The root of the tree, e.g. `https://github.com/SOF3/blob-mirror/blob/master/`
+    root: &'t str,
+    /// The path of the file from the root, e.g. `docs/a.md`
+    path: &'t str,
+}
+
+impl<'t> Links<'t> {
+    /// Splits a `{web_url}/{user}/{repo}/blob/{ref}/{path}` URL.
+    ///
+    /// The ref is assumed to contain no `/`,
+    /// so root-relative links of branches containing `/` may resolve incorrectly.
+    fn new(file: &'t str) -> Self {
+        let split = file.find("/blob/").and_then(|blob| {
+            let after_ref = blob + "/blob/".len() + file[blob + "/blob/".len()..].find('/')? + 1;
+            Some(after_ref)
+        });
+        match split {
+            Some(index) => Self {
+                file,
+                root: &file[..index],
+                path: &file[index..],
+            },
+            None => Self {
+                file,
+                root: file,
+                path: "",
+            },
+        }
+    }
+
+    fn resolve(&self, dest: &str, image: bool) -> String {
+        let dest = dest.trim_start_matches('<').trim_end_matches('>');
+        if dest.contains("://") || dest.starts_with("mailto:") {
+            return dest.to_string();
+        }
+        if dest.starts_with('#') {
+            return format!("{}{}", self.file, dest);
+        }
+
+        let mut segments: Vec<&str> = match dest.strip_prefix('/') {
+            Some(_) => Vec::new(),
+            None => {
+                let mut dir: Vec<&str> = self.path.split('/').collect();
+                dir.pop(); // the file name
+                dir
+            }
+        };
+        for segment in dest.trim_start_matches('/').split('/') {
+            match segment {
+                "" | "." => {}
+                ".." => {
+                    segments.pop();
+                }
+                segment => segments.push(segment),
+            }
+        }
+        let root = if image {
+            // the raw view of the web interface redirects to the file itself
+            self.root.replacen("/blob/", "/raw/", 1)
+        } else {
+            self.root.to_string()
+        };
+        format!("{}{}", root, segments.join("/"))
+    }
+}
+
+/// How links are written
+#[derive(Clone, Copy, PartialEq)]
+enum LinkStyle {
+    /// Masked links with absolute URLs
+    Masked,
+    /// Only the link text, for places where markdown is not rendered
+    Plain,
+}
+
+/// Transforms a markdown file into Discord markdown, one string per section.
+///
+/// `url` is the file on the GitHub web interface, against which relative links are resolved.
+pub fn transform(text: &str, url: &str) -> Vec<String> {
+    let links = Links::new(url);
+    let lines: Vec<&str> = text.lines().collect();
+
+    let mut sections = vec![String::new()];
+    let mut fence: Option<&'static str> = None;
+    let mut in_comment = false;
+    let mut index = 0;
+    while index < lines.len() {
+        let line = lines[index];
+        index += 1;
+        let out = sections.last_mut().expect("sections is never empty");
+
+        if let Some(marker) = fence {
+            out.push_str(line);
+            out.push('\n');
+            if line.trim_start().starts_with(marker) {
+                fence = None;
+            }
+            continue;
+        }
+
+        let line = match strip_comments(line, &mut in_comment) {
+            Some(line) => line,
+            None => continue, // the line was entirely a comment
+        };
+        let trimmed = line.trim_start();
+
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            fence = Some(if trimmed.starts_with("```") {
+                "```"
+            } else {
+                "~~~"
+            });
+            out.push_str(&line);
+            out.push('\n');
+            continue;
+        }
+
+        if is_table_row(&line) && matches!(lines.get(index), Some(next) if is_delimiter_row(next)) {
+            let mut rows = vec![line.to_string()];
+            index += 1; // the delimiter row
+            while index < lines.len() && is_table_row(lines[index]) {
+                rows.push(lines[index].to_string());
+                index += 1;
+            }
+            table(out, &rows);
+            continue;
+        }
+
+        let level = trimmed.chars().take_while(|&c| c == '#').count();
+        if (1..=6).contains(&level) && trimmed[level..].starts_with(' ') {
+            let title = rewrite_links(trimmed[level..].trim(), &links, LinkStyle::Masked);
+            if level <= 2 {
+                if !out.trim().is_empty() {
+                    sections.push(String::new());
+                }
+                let out = sections.last_mut().expect("sections is never empty");
+                let _ = writeln!(out, "{} {}", &trimmed[..level], title);
+            } else if level == 3 {
+                let _ = writeln!(out, "### {}", title);
+            } else {
+                let _ = writeln!(out, "**{}**", title);
+            }
+            continue;
+        }
+
+        let lower = trimmed.to_ascii_lowercase();
+        if lower.starts_with("<details") || lower.starts_with("</details>") {
+            continue;
+        }
+        if let Some(summary) = lower.strip_prefix("<summary>") {
+            let end = summary.find("</summary>").unwrap_or(summary.len());
+            let start = trimmed.len() - summary.len();
+            let summary = &trimmed[start..start + end];
+            let _ = writeln!(
+                out,
+                "**{}**",
+                rewrite_links(summary, &links, LinkStyle::Masked)
+            );
+            continue;
+        }
+
+        out.push_str(&rewrite_links(&line, &links, LinkStyle::Masked));
+        out.push('\n');
+    }
+
+    let sections: Vec<String> = sections
+        .into_iter()
+        .map(|section| section.trim_matches('\n').to_string())
+        .filter(|section| !section.trim().is_empty())
+        .collect();
+    if sections.is_empty() {
+        vec![String::new()]
+    } else {
+        sections
+    }
+}
+
+/// Removes HTML comments from a line, tracking comments spanning multiple lines.
+///
+/// Returns `None` if nothing but comments and whitespace remains of a line that had comments.
+fn strip_comments(line: &str, in_comment: &mut bool) -> Option<String> {
+    let mut out = String::new();
+    let mut rest = line;
+    let mut had_comment = *in_comment;
+    loop {
+        if *in_comment {
+            match rest.find("-->") {
+                Some(end) => {
+                    rest = &rest[end + "-->".len()..];
+                    *in_comment = false;
+                }
+                None => break,
+            }
+        } else {
+            match rest.find("<!--") {
+                Some(start) => {
+                    out.push_str(&rest[..start]);
+                    rest = &rest[start + "<!--".len()..];
+                    *in_comment = true;
+                    had_comment = true;
+                }
+                None => {
+                    out.push_str(rest);
+                    break;
+                }
+            }
+        }
+    }
+    if had_comment && out.trim().is_empty() {
+        None
+    } else {
+        Some(out)
+    }
+}
+
+/// Rewrites the links and images of a line outside inline code spans.
+fn rewrite_links(line: &str, links: &Links, style: LinkStyle) -> String {
+    line.split('`')
+        .enumerate()
+        .map(|(index, segment)| {
+            if index % 2 == 0 {
+                rewrite_segment(segment, links, style)
+            } else {
+                segment.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("`")
+}
+
+fn rewrite_segment(text: &str, links: &Links, style: LinkStyle) -> String {
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('[') {
+        let after = &rest[start + 1..];
+        let close = match matching_bracket(after) {
+            Some(close) => close,
+            None => break,
+        };
+        let label = &after[..close];
+        let tail = &after[close + 1..];
+        let end = match tail.strip_prefix('(').and_then(|target| target.find(')')) {
+            Some(end) => end + 1,
+            None => {
+                // not a link
+                out.push_str(&rest[..start + 1]);
+                rest = after;
+                continue;
+            }
+        };
+        let dest = tail[1..end].split_whitespace().next().unwrap_or_default();
+
+        let image = rest[..start].ends_with('!');
+        out.push_str(&rest[..if image { start - 1 } else { start }]);
+        // images nested in link texts are shown as their alt text
+        let label = rewrite_segment(label, links, LinkStyle::Plain);
+        match (style, image) {
+            (LinkStyle::Plain, _) => out.push_str(&label),
+            (LinkStyle::Masked, false) => {
+                let _ = write!(out, "[{}]({})", label, links.resolve(dest, false));
+            }
+            (LinkStyle::Masked, true) => {
+                let label = if label.is_empty() { "image" } else { &label };
+                let _ = write!(
+                    out,
+                    "[\u{1f5bc}\u{fe0f} {}]({})",
+                    label,
+                    links.resolve(dest, true)
+                );
+            }
+        }
+        rest = &tail[end + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Returns the index of the `]` closing a `[` just before `text`.
+fn matching_bracket(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (index, c) in text.char_indices() {
+        match c {
+            '[' => depth += 1,
+            ']' if depth == 0 => return Some(index),
+            ']' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+fn is_table_row(line: &str) -> bool {
+    line.contains('|') && !line.trim().is_empty()
+}
+
+/// Whether a line is the `| --- | :-: |` row below a table header.
+fn is_delimiter_row(line: &str) -> bool {
+    let cells = cells(line);
+    !cells.is_empty()
+        && cells.iter().all(|cell| {
+            let cell = cell.trim_start_matches(':').trim_end_matches(':');
+            !cell.is_empty() && cell.chars().all(|c| c == '-')
+        })
+}
+
+/// Splits a table row into trimmed cells.
+fn cells(line: &str) -> Vec<String> {
+    let line = line.trim();
+    let line = line.strip_prefix('|').unwrap_or(line);
+    let line = line.strip_suffix('|').unwrap_or(line);
+
+    let mut cells = vec![String::new()];
+    let mut escaped = false;
+    for c in line.chars() {
+        let cell = cells.last_mut().expect("cells is never empty");
+        match c {
+            '|' if !escaped => cells.push(String::new()),
+            '\\' if !escaped => escaped = true,
+            c => {
+                if escaped && c != '|' {
+                    cell.push('\\');
+                }
+                escaped = false;
+                cell.push(c);
+            }
+        }
+    }
+    cells.iter().map(|cell| cell.trim().to_string()).collect()
+}
+
+/// Writes a table as a code block with aligned columns.
+fn table(out: &mut String, rows: &[String]) {
+    let links = Links::new("");
+    let rows: Vec<Vec<String>> = rows
+        .iter()
+        .map(|row| {
+            cells(row)
+                .iter()
+                .map(|cell| rewrite_links(cell, &links, LinkStyle::Plain).replace('`', ""))
+                .collect()
+        })
+        .collect();
+    let columns = rows.iter().map(Vec::len).max().unwrap_or(0);
+    let widths: Vec<usize> = (0..columns)
+        .map(|column| {
+            rows.iter()
+                .filter_map(|row| row.get(column))
+                .map(|cell| cell.chars().count())
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+
+    let write_row = |out: &mut String, row: &[String]| {
+        let line: Vec<String> = widths
+            .iter()
+            .enumerate()
+            .map(|(column, &width)| {
+                let cell = row.get(column).map_or("", String::as_str);
+                format!("{:width$}", cell, width = width)
+            })
+            .collect();
+        out.push_str(line.join(" | ").trim_end());
+        out.push('\n');
+    };
+
+    out.push_str("```\n");
+    if let Some((header, body)) = rows.split_first() {
+        write_row(out, header);
+        let rule: Vec<String> = widths.iter().map(|&width| "-".repeat(width)).collect();
+        out.push_str(&rule.join("-+-"));
+        out.push('\n');
+        for row in body {
+            write_row(out, row);
+        }
+    }
+    out.push_str("```\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URL: &str = "https://github.com/SOF3/blob-mirror/blob/master/docs/RULES.md";
+
+    #[test]
+    fn links_resolve_against_file() {
+        let text = "See [the FAQ](../FAQ.md#bans), [home](/README.md), [top](#rules), \
+            [site](https://example.com) and `[code](x)`.\n\
+            [![badge](badge.svg)](https://ci.example.com) ![logo](img/logo.png)";
+        assert_eq!(
+            transform(text, URL),
+            vec![
+                "See [the FAQ](https://github.com/SOF3/blob-mirror/blob/master/FAQ.md#bans), \
+                [home](https://github.com/SOF3/blob-mirror/blob/master/README.md), \
+                [top](https://github.com/SOF3/blob-mirror/blob/master/docs/RULES.md#rules), \
+                [site](https://example.com) and `[code](x)`.\n\
+                [badge](https://ci.example.com) \
+                [\u{1f5bc}\u{fe0f} logo](https://github.com/SOF3/blob-mirror/raw/master/docs/img/logo.png)"
+            ]
+        );
+    }
+
+    #[test]
+    fn tables_become_code_blocks() {
+        let text =
+            "| Rule | Penalty |\n|:-----|--:|\n| [Spam](spam.md) | Mute |\n| Raids | Ban |\nafter";
+        assert_eq!(
+            transform(text, URL),
+            vec!["```\nRule  | Penalty\n------+--------\nSpam  | Mute\nRaids | Ban\n```\nafter"]
+        );
+    }
+
+    #[test]
+    fn headings_and_html() {
+        let text = "<!-- generated -->\nintro\n# Conduct\nbe nice <!-- or else -->\n\
+            #### Details\n<details>\n<summary>Why?</summary>\n<!--\nhidden\n-->\nbecause\n</details>\n\
+            ```\n# not a heading\n<!-- kept -->\n```\n## Channels\n### Memes";
+        assert_eq!(
+            transform(text, URL),
+            vec![
+                "intro",
+                "# Conduct\nbe nice \n**Details**\n**Why?**\nbecause\n\
+                 ```\n# not a heading\n<!-- kept -->\n```",
+                "## Channels\n### Memes",
+            ]
+        );
+    }
+}
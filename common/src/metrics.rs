@@ -0,0 +1,101 @@
+//! Prometheus metrics shared by the bot and the web service.
+//!
+//! Each binary only updates the metrics relevant to it,
+//! but all metrics are registered in both so that the exported schema is the same.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_histogram, register_int_counter, register_int_counter_vec, Encoder, Histogram,
+    IntCounter, IntCounterVec, TextEncoder,
+};
+
+/// Webhook events received, by event type and outcome (`ok`, `error` or `ignored`)
+pub static WEBHOOK_EVENTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "blob_mirror_webhook_events_total",
+        "Webhook events received",
+        &["event", "outcome"]
+    )
+    .expect("Metric is valid")
+});
+
+/// Messages published to the `updates` topic
+pub static UPDATES_PUBLISHED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "blob_mirror_updates_published_total",
+        "Updates published to the bot"
+    )
+    .expect("Metric is valid")
+});
+
+/// Messages from the `updates` topic handled by the bot, by outcome (`ok` or `error`)
+pub static UPDATES_PROCESSED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "blob_mirror_updates_processed_total",
+        "Updates processed by the bot",
+        &["outcome"]
+    )
+    .expect("Metric is valid")
+});
+
+/// Time taken by each Discord message edit
+pub static DISCORD_EDIT_SECONDS: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "blob_mirror_discord_edit_seconds",
+        "Latency of Discord message edits"
+    )
+    .expect("Metric is valid")
+});
+
+/// Discord message edits that failed
+pub static DISCORD_EDIT_FAILURES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "blob_mirror_discord_edit_failures_total",
+        "Failed Discord message edits"
+    )
+    .expect("Metric is valid")
+});
+
+/// Requests to GitHub that failed
+pub static GITHUB_FETCH_ERRORS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "blob_mirror_github_fetch_errors_total",
+        "Failed requests to GitHub"
+    )
+    .expect("Metric is valid")
+});
+
+/// Time between publishing an update and the bot receiving it
+pub static PUBSUB_LAG_SECONDS: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "blob_mirror_pubsub_lag_seconds",
+        "Delay between publishing and receiving an update"
+    )
+    .expect("Metric is valid")
+});
+
+/// Renders all metrics in the Prometheus text format.
+pub fn render() -> String {
+    Lazy::force(&WEBHOOK_EVENTS);
+    Lazy::force(&UPDATES_PUBLISHED);
+    Lazy::force(&UPDATES_PROCESSED);
+    Lazy::force(&DISCORD_EDIT_SECONDS);
+    Lazy::force(&DISCORD_EDIT_FAILURES);
+    Lazy::force(&GITHUB_FETCH_ERRORS);
+    Lazy::force(&PUBSUB_LAG_SECONDS);
+
+    let mut buf = Vec::new();
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buf)
+        .expect("Text encoding does not fail");
+    String::from_utf8(buf).expect("Text encoding is UTF-8")
+}
+
+/// The current time in milliseconds since the unix epoch, for measuring pubsub lag.
+pub fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_millis() as u64)
+}
@@ -0,0 +1,320 @@
+//! Splitting mirrored files into Discord messages.
+
+use std::fmt;
+
+/// Maximum length of a Discord message in bytes
+pub const MESSAGE_MAX_LENGTH: usize = 2000;
+/// Maximum length of an embed title in bytes
+pub const EMBED_TITLE_MAX_LENGTH: usize = 256;
+/// Maximum length of an embed description in bytes
+pub const EMBED_DESCRIPTION_MAX_LENGTH: usize = 4096;
+/// Maximum total length of the texts in an embed in bytes
+pub const EMBED_TOTAL_MAX_LENGTH: usize = 6000;
+
+/// The content of messages beyond the end of the file
+pub const RESERVED: &str = "*(message reserved for expansion)*";
+
+/// How the pages of a mirror group are displayed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    /// Each page is the content of a message.
+    #[default]
+    Text,
+    /// Each page is the description of an embed.
+    Embed,
+    /// Like `Embed`, but the entries of structured data files are embed fields.
+    Fields,
+}
+
+impl Mode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Text => "text",
+            Self::Embed => "embed",
+            Self::Fields => "fields",
+        }
+    }
+
+    /// Returns the embed of a mirrored file, or `None` in text mode; see `Embed::new`.
+    pub fn embed(
+        self,
+        repo_id: u64,
+        repo_name: &str,
+        path: &str,
+        url: &str,
+        commit: Option<&str>,
+    ) -> Option<Embed> {
+        match self {
+            Self::Text => None,
+            Self::Embed | Self::Fields => Some(Embed {
+                fields: self == Self::Fields,
+                ..Embed::new(repo_id, repo_name, path, url, commit)
+            }),
+        }
+    }
+}
+
+impl std::str::FromStr for Mode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s {
+            "text" => Self::Text,
+            "embed" => Self::Embed,
+            "fields" => Self::Fields,
+            _ => anyhow::bail!("Unknown render mode {:?}", s),
+        })
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// How a file is split into the messages of a mirror group
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Split {
+    /// A fixed number of messages, chosen when the group is created.
+    #[default]
+    Pages,
+    /// A message per section, added or removed as sections change.
+    Sections,
+}
+
+impl Split {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Pages => "pages",
+            Self::Sections => "sections",
+        }
+    }
+}
+
+impl std::str::FromStr for Split {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s {
+            "pages" => Self::Pages,
+            "sections" => Self::Sections,
+            _ => anyhow::bail!("Unknown split strategy {:?}", s),
+        })
+    }
+}
+
+impl fmt::Display for Split {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The parts of an embed shared by all pages of a group; the page is the description.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Embed {
+    /// The mirrored file, e.g. `SOF3/blob-mirror: README.md`
+    pub title: String,
+    /// Link to the file on the GitHub web interface
+    pub url: String,
+    /// RGB color derived from the repo ID
+    pub color: u32,
+    /// The commit SHA, or the branch if the commit is unknown
+    pub footer: String,
+    /// RFC 3339 time when the page was rendered
+    pub timestamp: String,
+    /// Whether the entries of structured data files are rendered as fields
+    #[serde(default)]
+    pub fields: bool,
+}
+
+/// A field of an embed
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Field {
+    pub name: String,
+    pub value: String,
+    pub inline: bool,
+}
+
+impl Embed {
+    /// Describes a mirrored file, where `path` is the branch followed by the path in the repo.
+    pub fn new(repo_id: u64, repo_name: &str, path: &str, url: &str, commit: Option<&str>) -> Self {
+        let mut split = path.splitn(2, '/');
+        let branch = split.next().unwrap_or_default();
+        let file = split.next().unwrap_or(path);
+
+        let mut title = format!("{}: {}", repo_name, file);
+        title.truncate(floor_char_boundary(&title, EMBED_TITLE_MAX_LENGTH));
+        let footer = match commit {
+            Some(commit) => format!("Commit {}", commit.get(..7).unwrap_or(commit)),
+            None => format!("Branch {}", branch),
+        };
+        Self {
+            title,
+            url: url.to_string(),
+            color: repo_color(repo_id),
+            footer,
+            timestamp: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            fields: false,
+        }
+    }
+
+    /// Returns the maximum description length that keeps the embed within the total limit.
+    pub fn max_description_length(&self) -> usize {
+        let used = self.title.len() + self.footer.len();
+        EMBED_DESCRIPTION_MAX_LENGTH.min(EMBED_TOTAL_MAX_LENGTH.saturating_sub(used))
+    }
+}
+
+/// Derives a stable, reasonably saturated color from a repo ID.
+fn repo_color(repo_id: u64) -> u32 {
+    let hash = repo_id.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    (hash >> 40) as u32 | 0x40_40_40
+}
+
+/// Returns the maximum page length of a group.
+pub fn max_length(embed: Option<&Embed>) -> usize {
+    embed.map_or(MESSAGE_MAX_LENGTH, Embed::max_description_length)
+}
+
+/// Splits `text` into chunks of at most `max_len` bytes on character boundaries.
+///
+/// Empty text still takes one chunk.
+/// A character longer than `max_len` takes a chunk of its own.
+pub fn split(text: &str, max_len: usize) -> Vec<&str> {
+    debug_assert!(max_len > 0, "Cannot split into empty chunks");
+    let mut chunks = Vec::new();
+    let mut rest = text;
+    while !rest.is_empty() {
+        let mut end = floor_char_boundary(rest, max_len);
+        if end == 0 {
+            // always make progress, even if the first character does not fit
+            end = rest.chars().next().map_or(rest.len(), char::len_utf8);
+        }
+        let (chunk, tail) = rest.split_at(end);
+        chunks.push(chunk);
+        rest = tail;
+    }
+    if chunks.is_empty() {
+        chunks.push("");
+    }
+    chunks
+}
+
+/// Renders `text` into exactly `count` pages of at most `max_len` bytes.
+///
+/// Unused pages are filled with `RESERVED`.
+/// If the text does not fit, it is truncated with a link to `url`.
+pub fn pages(text: &str, count: usize, max_len: usize, url: &str) -> Vec<String> {
+    fit(split(text, max_len), count, max_len, url)
+}
+
+/// Splits each section into chunks of at most `max_len` bytes, so that each section
+/// starts a new chunk.
+pub fn split_sections<S: AsRef<str>>(sections: &[S], max_len: usize) -> Vec<&str> {
+    let chunks: Vec<&str> = sections
+        .iter()
+        .map(AsRef::as_ref)
+        .filter(|section| !section.is_empty())
+        .flat_map(|section| split(section, max_len))
+        .collect();
+    if chunks.is_empty() {
+        vec![""]
+    } else {
+        chunks
+    }
+}
+
+/// Renders sections into exactly `count` pages like `pages`, each section starting a new page.
+pub fn section_pages<S: AsRef<str>>(
+    sections: &[S],
+    count: usize,
+    max_len: usize,
+    url: &str,
+) -> Vec<String> {
+    fit(split_sections(sections, max_len), count, max_len, url)
+}
+
+fn fit(chunks: Vec<&str>, count: usize, max_len: usize, url: &str) -> Vec<String> {
+    let mut chunks: Vec<String> = chunks.into_iter().map(str::to_string).collect();
+
+    if chunks.len() > count {
+        chunks.truncate(count);
+        if let Some(last) = chunks.last_mut() {
+            let more = format!("\u{2026}\nSee <{}> for more", url);
+            let end = floor_char_boundary(last, max_len.saturating_sub(more.len()));
+            last.truncate(end);
+            last.push_str(&more);
+        }
+    }
+    chunks.resize(count, String::from(RESERVED));
+    chunks
+}
+
+/// Returns the largest character boundary of `text` not after `index`.
+pub(crate) fn floor_char_boundary(text: &str, index: usize) -> usize {
+    if index >= text.len() {
+        return text.len();
+    }
+    let mut end = index;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    end
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_respects_char_boundaries() {
+        let text = "é".repeat(MESSAGE_MAX_LENGTH);
+        let chunks = split(&text, MESSAGE_MAX_LENGTH);
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks.iter().all(|chunk| chunk.len() <= MESSAGE_MAX_LENGTH));
+        assert_eq!(chunks.concat(), text);
+    }
+
+    #[test]
+    fn split_makes_progress_on_wide_characters() {
+        assert_eq!(split("éé", 1), vec!["é", "é"]);
+        assert_eq!(split("aé", 1), vec!["a", "é"]);
+    }
+
+    #[test]
+    fn pages_pad_and_truncate() {
+        assert_eq!(
+            pages("short", 2, MESSAGE_MAX_LENGTH, "url"),
+            vec!["short", RESERVED]
+        );
+
+        let text = "a".repeat(MESSAGE_MAX_LENGTH * 2);
+        let rendered = pages(&text, 1, MESSAGE_MAX_LENGTH, "https://example.com");
+        assert_eq!(rendered.len(), 1);
+        assert!(rendered[0].len() <= MESSAGE_MAX_LENGTH);
+        assert!(rendered[0].ends_with("See <https://example.com> for more"));
+    }
+
+    #[test]
+    fn embed_pages_fit_total_limit() {
+        let embed = Embed::new(
+            42,
+            "SOF3/blob-mirror",
+            "master/README.md",
+            "https://github.com/SOF3/blob-mirror/blob/master/README.md",
+            Some("0123456789abcdef"),
+        );
+        assert_eq!(embed.title, "SOF3/blob-mirror: README.md");
+        assert_eq!(embed.footer, "Commit 0123456");
+
+        let max_len = max_length(Some(&embed));
+        assert_eq!(max_len, EMBED_DESCRIPTION_MAX_LENGTH);
+        let text = "a".repeat(EMBED_TOTAL_MAX_LENGTH);
+        for page in pages(&text, 2, max_len, &embed.url) {
+            assert!(page.len() + embed.title.len() + embed.footer.len() <= EMBED_TOTAL_MAX_LENGTH);
+        }
+    }
+}
@@ -1,37 +1,176 @@
+use std::env;
+use std::fmt;
+use std::fs;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 
 use anyhow::Context;
 
-#[derive(serde::Deserialize)]
+#[derive(Debug, serde::Deserialize)]
 pub struct Secret {
     pub discord: Discord,
+    #[serde(default)]
     pub github: Github,
+    #[serde(default)]
     pub web: Web,
+    #[serde(default)]
+    pub bot: Bot,
     pub redis: Redis,
 }
 
-#[derive(serde::Deserialize)]
+#[derive(Debug, serde::Deserialize)]
 pub struct Discord {
     pub client_id: u64,
-    pub client_secret: String,
-    pub token: String,
+    /// Required by the dashboard.
+    pub client_secret: Option<Redacted>,
+    /// Required by the bot and the dashboard.
+    pub token: Option<Redacted>,
 }
 
-#[derive(serde::Deserialize)]
+#[derive(Debug, Clone, serde::Deserialize)]
 pub struct Github {
-    pub client_id: String,
-    pub client_secret: String,
-    pub slug: String,
-    pub app_id: u64,
-    pub webhook_secret: String,
+    pub slug: Option<String>,
+    /// Required to verify `private_key`.
+    pub app_id: Option<u64>,
+    /// Required by the web service.
+    pub webhook_secret: Option<Redacted>,
+    /// PEM-encoded private key of the GitHub App,
+    /// read from the file at `github.private_key_path` if that is set instead.
+    pub private_key: Option<Redacted>,
+    /// Base URL of the REST API, without trailing slash; defaults to `https://api.github.com`.
+    #[serde(default = "default_api_url")]
+    pub api_url: String,
+    /// Base URL of raw file contents, without trailing slash;
+    /// defaults to `https://raw.githubusercontent.com`.
+    #[serde(default = "default_raw_url")]
+    pub raw_url: String,
+    /// Base URL of the web interface, without trailing slash; defaults to `https://github.com`.
+    #[serde(default = "default_web_url")]
+    pub web_url: String,
 }
 
-#[derive(serde::Deserialize)]
+impl Default for Github {
+    fn default() -> Self {
+        Self {
+            slug: None,
+            app_id: None,
+            webhook_secret: None,
+            private_key: None,
+            api_url: default_api_url(),
+            raw_url: default_raw_url(),
+            web_url: default_web_url(),
+        }
+    }
+}
+
+fn default_api_url() -> String {
+    String::from("https://api.github.com")
+}
+
+fn default_raw_url() -> String {
+    String::from("https://raw.githubusercontent.com")
+}
+
+fn default_web_url() -> String {
+    String::from("https://github.com")
+}
+
+impl Github {
+    /// Returns the API URL of a repo.
+    pub fn repo_url(&self, repo_name: &str) -> String {
+        format!("{}/repos/{}", &self.api_url, repo_name)
+    }
+
+    /// Returns the contents API URL of `file` at a branch, tag or commit.
+    pub fn contents_url(&self, repo_name: &str, file: &str, git_ref: &str) -> String {
+        format!(
+            "{}/repos/{}/contents/{}?ref={}",
+            &self.api_url, repo_name, file, git_ref
+        )
+    }
+
+    /// Returns the contents API URL of a file at the head of its branch,
+    /// where `path` is the branch followed by the path in the repo, e.g. `master/README.md`.
+    pub fn branch_contents_url(&self, repo_name: &str, path: &str) -> String {
+        let (branch, file) = split_branch(path);
+        self.contents_url(repo_name, file, branch)
+    }
+
+    /// Returns the URL of a file on the web interface, in the same format as `branch_contents_url`.
+    pub fn blob_url(&self, repo_name: &str, path: &str) -> String {
+        format!("{}/{}/blob/{}", &self.web_url, repo_name, path)
+    }
+}
+
+/// Splits a group path into the branch and the path in the repo.
+///
+/// Branches containing `/` cannot be told apart from directories,
+/// so the branch is assumed to be the first segment.
+fn split_branch(path: &str) -> (&str, &str) {
+    let mut split = path.splitn(2, '/');
+    let branch = split.next().unwrap_or_default();
+    (branch, split.next().unwrap_or_default())
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
 pub struct Web {
-    pub port: u16,
+    /// Required by the web service.
+    pub port: Option<u16>,
+    /// Bearer token for the admin API; the API is disabled if unset.
+    pub admin_token: Option<Redacted>,
+    /// Seconds to remember webhook delivery IDs for deduplication; defaults to 3 days.
+    #[serde(default = "default_delivery_ttl")]
+    pub delivery_ttl: u64,
+    /// The public base URL of the web service, used as the OAuth redirect target;
+    /// the dashboard is disabled if unset.
+    pub public_url: Option<String>,
 }
 
-#[derive(Clone, serde::Deserialize)]
+fn default_delivery_ttl() -> u64 {
+    3 * 86400
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+pub struct Bot {
+    /// Port of the health and metrics listener of the bot; the listener is disabled if unset.
+    pub health_port: Option<u16>,
+    #[serde(default)]
+    pub on_seen: OnSeen,
+}
+
+/// Limits of the actions queued until a repo installs the GitHub App
+#[derive(Debug, serde::Deserialize)]
+pub struct OnSeen {
+    /// Seconds to keep queued actions after the last one was added; defaults to 7 days.
+    #[serde(default = "default_on_seen_ttl")]
+    pub ttl: u64,
+    /// Maximum number of queued messages per repo and action; defaults to 50.
+    #[serde(default = "default_on_seen_max_len")]
+    pub max_len: usize,
+    /// Whether to remove the mirror and edit the warning when the queue expires.
+    #[serde(default)]
+    pub abandon: bool,
+}
+
+impl Default for OnSeen {
+    fn default() -> Self {
+        Self {
+            ttl: default_on_seen_ttl(),
+            max_len: default_on_seen_max_len(),
+            abandon: false,
+        }
+    }
+}
+
+fn default_on_seen_ttl() -> u64 {
+    7 * 86400
+}
+
+fn default_on_seen_max_len() -> usize {
+    50
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
 pub struct Redis {
     addr: String,
 }
@@ -43,9 +182,333 @@ impl Redis {
     }
 }
 
-pub fn load() -> anyhow::Result<Secret> {
+/// A secret string, redacted in `Debug` output so that it cannot leak into logs
+#[derive(Clone, serde::Deserialize)]
+#[serde(transparent)]
+pub struct Redacted(String);
+
+impl Redacted {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Redacted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("[redacted]")
+    }
+}
+
+/// The binary loading the configuration
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Service {
+    Bot,
+    Web,
+}
+
+impl fmt::Display for Service {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Bot => "the bot",
+            Self::Web => "the web service",
+        })
+    }
+}
+
+/// A missing or invalid configuration field
+pub struct Problem {
+    /// Dotted path of the field, empty if the problem is not specific to a field
+    pub path: String,
+    pub message: String,
+}
+
+impl Problem {
+    fn new(path: &str, message: impl Into<String>) -> Self {
+        Self {
+            path: path.to_string(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "{}", &self.message)
+        } else {
+            write!(f, "{}: {}", &self.path, &self.message)
+        }
+    }
+}
+
+enum Kind {
+    Integer,
+    Port,
+    Bool,
+    String,
+}
+
+/// Every field read from the configuration, checked one by one
+/// so that all invalid values can be reported together.
+const FIELDS: &[(&str, Kind, bool)] = &[
+    ("discord.client_id", Kind::Integer, true),
+    ("discord.client_secret", Kind::String, false),
+    ("discord.token", Kind::String, false),
+    ("github.slug", Kind::String, false),
+    ("github.app_id", Kind::Integer, false),
+    ("github.webhook_secret", Kind::String, false),
+    ("github.private_key", Kind::String, false),
+    ("github.private_key_path", Kind::String, false),
+    ("github.api_url", Kind::String, false),
+    ("github.raw_url", Kind::String, false),
+    ("github.web_url", Kind::String, false),
+    ("web.port", Kind::Port, false),
+    ("web.admin_token", Kind::String, false),
+    ("web.delivery_ttl", Kind::Integer, false),
+    ("web.public_url", Kind::String, false),
+    ("bot.health_port", Kind::Port, false),
+    ("bot.on_seen.ttl", Kind::Integer, false),
+    ("bot.on_seen.max_len", Kind::Integer, false),
+    ("bot.on_seen.abandon", Kind::Bool, false),
+    ("redis.addr", Kind::String, true),
+];
+
+impl Secret {
+    /// Returns the problems with the fields required by `service`
+    /// and the features enabled in the configuration.
+    pub fn problems(&self, service: Service) -> Vec<Problem> {
+        let mut problems = Vec::new();
+        let mut require = |present: bool, path: &str, reason: &dyn fmt::Display| {
+            if !present {
+                problems.push(Problem::new(
+                    path,
+                    format!("missing, required by {}", reason),
+                ));
+            }
+        };
+
+        match service {
+            Service::Bot => {
+                require(self.discord.token.is_some(), "discord.token", &service);
+            }
+            Service::Web => {
+                require(self.web.port.is_some(), "web.port", &service);
+                require(
+                    self.github.webhook_secret.is_some(),
+                    "github.webhook_secret",
+                    &service,
+                );
+                if self.web.public_url.is_some() {
+                    let reason = "the dashboard (web.public_url)";
+                    require(
+                        self.discord.client_secret.is_some(),
+                        "discord.client_secret",
+                        &reason,
+                    );
+                    require(self.discord.token.is_some(), "discord.token", &reason);
+                }
+            }
+        }
+        if self.github.private_key.is_some() {
+            require(
+                self.github.app_id.is_some(),
+                "github.app_id",
+                &"github.private_key",
+            );
+        }
+        if self.bot.on_seen.max_len == 0 {
+            // `LTRIM key 0 -1` would keep the whole queue
+            problems.push(Problem::new("bot.on_seen.max_len", "must be at least 1"));
+        }
+
+        problems
+    }
+}
+
+/// Environment variable naming the configuration file; defaults to `secret.toml` in the working directory
+const CONFIG_ENV: &str = "BLOB_MIRROR_CONFIG";
+
+/// Returns the environment variable overriding a field,
+/// e.g. `BLOB_MIRROR_DISCORD_TOKEN` for `discord.token`.
+fn env_name(path: &str) -> String {
+    format!("BLOB_MIRROR_{}", path.replace('.', "_").to_uppercase())
+}
+
+/// Reads the configuration file and applies the environment overrides.
+///
+/// Each field can be set by its environment variable,
+/// or read from the file named by the same variable suffixed with `_FILE`,
+/// as used by Docker and Kubernetes secret mounts.
+fn sources(problems: &mut Vec<Problem>) -> Result<config::Config, config::ConfigError> {
     let mut config = config::Config::new();
-    config.merge(config::Environment::with_prefix("BLOB_MIRROR"))?;
-    config.merge(config::File::with_name("secret"))?;
-    Ok(config.try_into()?)
+    match env::var_os(CONFIG_ENV) {
+        Some(path) => config.merge(config::File::from(PathBuf::from(path)))?,
+        None => config.merge(config::File::with_name("secret").required(false))?,
+    };
+
+    for (path, _, _) in FIELDS {
+        let name = env_name(path);
+        let file_name = format!("{}_FILE", &name);
+
+        let value = match (env::var(&name), env::var_os(&file_name)) {
+            (Ok(_), Some(_)) => {
+                problems.push(Problem::new(
+                    path,
+                    format!("both {} and {} are set", &name, &file_name),
+                ));
+                continue;
+            }
+            (Ok(value), None) => value,
+            (Err(env::VarError::NotUnicode(_)), _) => {
+                problems.push(Problem::new(path, format!("{} is not valid UTF-8", &name)));
+                continue;
+            }
+            (Err(env::VarError::NotPresent), Some(file)) => match fs::read_to_string(&file) {
+                Ok(value) => value.trim_end_matches(&['\r', '\n'][..]).to_string(),
+                Err(err) => {
+                    problems.push(Problem::new(
+                        path,
+                        format!("cannot read {} ({:?}): {}", &file_name, file, err),
+                    ));
+                    continue;
+                }
+            },
+            (Err(env::VarError::NotPresent), None) => continue,
+        };
+        config.set(path, value)?;
+    }
+
+    Ok(config)
+}
+
+/// Reads `github.private_key_path` into `github.private_key`.
+fn read_private_key(config: &mut config::Config) -> Result<(), Problem> {
+    const PATH: &str = "github.private_key_path";
+
+    let file = match config.get::<String>(PATH) {
+        Ok(file) => file,
+        Err(config::ConfigError::NotFound(_)) => return Ok(()),
+        Err(err) => return Err(Problem::new(PATH, err.to_string())),
+    };
+    if config.get::<String>("github.private_key").is_ok() {
+        return Err(Problem::new(PATH, "conflicts with github.private_key"));
+    }
+    let pem = fs::read_to_string(&file)
+        .map_err(|err| Problem::new(PATH, format!("cannot read {:?}: {}", &file, err)))?;
+    config
+        .set("github.private_key", pem)
+        .map_err(|err| Problem::new(PATH, err.to_string()))?;
+    Ok(())
+}
+
+/// Loads the configuration, returning every problem found for `service`.
+pub fn check(service: Service) -> Result<Secret, Vec<Problem>> {
+    let mut problems = Vec::new();
+    let mut config = match sources(&mut problems) {
+        Ok(config) => config,
+        Err(err) => return Err(vec![Problem::new("", err.to_string())]),
+    };
+    if let Err(problem) = read_private_key(&mut config) {
+        problems.push(problem);
+    }
+
+    for (path, kind, required) in FIELDS {
+        let result = match kind {
+            Kind::Integer => config.get::<u64>(path).map(|_| true),
+            // the config crate truncates out-of-range integers
+            Kind::Port => config
+                .get::<i64>(path)
+                .map(|port| (0..=i64::from(u16::MAX)).contains(&port)),
+            Kind::Bool => config.get::<bool>(path).map(|_| true),
+            Kind::String => config.get::<String>(path).map(|_| true),
+        };
+        match result {
+            Ok(true) => {}
+            Ok(false) => problems.push(Problem::new(path, "not a valid port number")),
+            Err(config::ConfigError::NotFound(_)) => {
+                if *required {
+                    problems.push(Problem::new(path, "missing"));
+                }
+            }
+            Err(err) => problems.push(Problem::new(path, err.to_string())),
+        }
+    }
+    if !problems.is_empty() {
+        return Err(problems);
+    }
+
+    let secret: Secret = match config.try_into() {
+        Ok(secret) => secret,
+        Err(err) => return Err(vec![Problem::new("", err.to_string())]),
+    };
+    let problems = secret.problems(service);
+    if problems.is_empty() {
+        Ok(secret)
+    } else {
+        Err(problems)
+    }
+}
+
+/// Loads the configuration, failing if any field required by `service` is missing or invalid.
+pub fn load(service: Service) -> anyhow::Result<Secret> {
+    check(service).map_err(|problems| {
+        let list: Vec<String> = problems.iter().map(ToString::to_string).collect();
+        anyhow::anyhow!("Invalid configuration:\n{}", list.join("\n"))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn env_names_are_uppercase_paths() {
+        assert_eq!(env_name("discord.token"), "BLOB_MIRROR_DISCORD_TOKEN");
+        assert_eq!(env_name("bot.on_seen.ttl"), "BLOB_MIRROR_BOT_ON_SEEN_TTL");
+    }
+
+    #[test]
+    fn debug_redacts_secrets() {
+        let discord = Discord {
+            client_id: 1,
+            client_secret: Some(Redacted(String::from("hunter2"))),
+            token: None,
+        };
+        let debug = format!("{:?}", discord);
+        assert!(!debug.contains("hunter2"), "{}", debug);
+        assert!(debug.contains("[redacted]"), "{}", debug);
+    }
+
+    #[test]
+    fn zero_on_seen_max_len_is_rejected() {
+        let secret: Secret = serde_json::from_value(serde_json::json!({
+            "discord": { "client_id": 1, "token": "token" },
+            "bot": { "on_seen": { "max_len": 0 } },
+            "redis": { "addr": "localhost:6379" },
+        }))
+        .unwrap();
+        let problems: Vec<String> = secret
+            .problems(Service::Bot)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(problems, vec!["bot.on_seen.max_len: must be at least 1"]);
+    }
+
+    #[test]
+    fn file_urls_split_branch() {
+        let github = Github {
+            api_url: String::from("https://ghes.example.com/api/v3"),
+            web_url: String::from("https://ghes.example.com"),
+            ..Github::default()
+        };
+        assert_eq!(
+            github.branch_contents_url("SOF3/blob-mirror", "master/docs/db.md"),
+            "https://ghes.example.com/api/v3/repos/SOF3/blob-mirror/contents/docs/db.md?ref=master"
+        );
+        assert_eq!(
+            github.blob_url("SOF3/blob-mirror", "master/docs/db.md"),
+            "https://ghes.example.com/SOF3/blob-mirror/blob/master/docs/db.md"
+        );
+    }
 }
@@ -0,0 +1,243 @@
+//! Runtime settings that can be changed without restarting.
+//!
+//! Settings are stored in the `settings` Redis hash and polled by both binaries.
+//! Unlike `secret`, they contain no credentials.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use tokio::sync::watch;
+
+use crate::db;
+use crate::render;
+
+/// How often to poll the `settings` hash
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// Maximum number of mirror updates rendered by the bot at the same time
+    pub concurrency: usize,
+    /// Maximum number of `mirror` commands per guild per minute
+    pub commands_per_minute: usize,
+    /// Number of messages of a new mirror if the command does not specify it
+    pub default_pages: usize,
+    /// Render mode of a new mirror if the command does not specify it
+    pub default_render_mode: render::Mode,
+    /// Maximum number of messages of a mirror; longer files split by sections are truncated
+    pub max_messages: usize,
+    /// Guilds allowed to create mirrors; all guilds are allowed if empty
+    pub allowed_guilds: Vec<u64>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            concurrency: 8,
+            commands_per_minute: 10,
+            default_pages: 1,
+            default_render_mode: render::Mode::Text,
+            max_messages: 20,
+            allowed_guilds: Vec::new(),
+        }
+    }
+}
+
+impl Settings {
+    /// Whether mirrors may be created in the guild.
+    pub fn allows_guild(&self, guild_id: u64) -> bool {
+        self.allowed_guilds.is_empty() || self.allowed_guilds.contains(&guild_id)
+    }
+
+    /// Parses the fields of the `settings` hash.
+    ///
+    /// Missing fields take their default value.
+    /// Invalid fields are logged and also take their default value,
+    /// so that a typo does not stop the other settings from applying.
+    pub fn from_hash(hash: &HashMap<String, String>) -> Self {
+        let mut settings = Self::default();
+        parse_count(hash, "concurrency", &mut settings.concurrency);
+        parse_field(
+            hash,
+            "commands_per_minute",
+            &mut settings.commands_per_minute,
+        );
+        parse_count(hash, "default_pages", &mut settings.default_pages);
+        parse_field(
+            hash,
+            "default_render_mode",
+            &mut settings.default_render_mode,
+        );
+        parse_count(hash, "max_messages", &mut settings.max_messages);
+        if let Some(value) = hash.get("allowed_guilds") {
+            let guilds: Result<Vec<u64>, _> = value
+                .split(',')
+                .map(str::trim)
+                .filter(|id| !id.is_empty())
+                .map(str::parse)
+                .collect();
+            match guilds {
+                Ok(guilds) => settings.allowed_guilds = guilds,
+                Err(err) => log::error!("Invalid setting allowed_guilds = {:?}: {}", value, err),
+            }
+        }
+        settings
+    }
+
+    /// Checks the fields that must not be zero, which `from_hash` replaces with their default.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for (name, value) in [
+            ("concurrency", self.concurrency),
+            ("default_pages", self.default_pages),
+            ("max_messages", self.max_messages),
+        ] {
+            anyhow::ensure!(value > 0, "Setting {} must be positive", name);
+        }
+        Ok(())
+    }
+
+    /// Formats the settings as the fields of the `settings` hash.
+    pub fn to_hash(&self) -> Vec<(&'static str, String)> {
+        let guilds: Vec<String> = self.allowed_guilds.iter().map(u64::to_string).collect();
+        vec![
+            ("concurrency", self.concurrency.to_string()),
+            ("commands_per_minute", self.commands_per_minute.to_string()),
+            ("default_pages", self.default_pages.to_string()),
+            ("default_render_mode", self.default_render_mode.to_string()),
+            ("max_messages", self.max_messages.to_string()),
+            ("allowed_guilds", guilds.join(",")),
+        ]
+    }
+
+    /// Describes the fields that differ from `old`, e.g. `concurrency: 8 -> 16`.
+    pub fn diff(&self, old: &Self) -> Vec<String> {
+        old.to_hash()
+            .into_iter()
+            .zip(self.to_hash())
+            .filter(|((_, old), (_, new))| old != new)
+            .map(|((name, old), (_, new))| format!("{}: {:?} -> {:?}", name, old, new))
+            .collect()
+    }
+}
+
+fn parse_field<T>(hash: &HashMap<String, String>, name: &str, field: &mut T)
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    if let Some(value) = hash.get(name) {
+        match value.trim().parse() {
+            Ok(value) => *field = value,
+            Err(err) => log::error!("Invalid setting {} = {:?}: {}", name, value, err),
+        }
+    }
+}
+
+/// Like `parse_field`, but also rejects zero,
+/// which would stall updates or create mirrors without messages.
+fn parse_count(hash: &HashMap<String, String>, name: &str, field: &mut usize) {
+    let mut value = *field;
+    parse_field(hash, name, &mut value);
+    if value == 0 {
+        log::error!("Invalid setting {} = 0: must be positive", name);
+    } else {
+        *field = value;
+    }
+}
+
+/// Loads the settings and keeps them up to date in the background,
+/// logging every change.
+pub async fn watch(conn: db::Conn) -> anyhow::Result<watch::Receiver<Arc<Settings>>> {
+    let initial = Settings::from_hash(&conn.settings().await.context("Error loading settings")?);
+    log::info!("Loaded settings: {:?}", &initial);
+
+    let (tx, rx) = watch::channel(Arc::new(initial));
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+            let settings = match conn.settings().await {
+                Ok(hash) => Settings::from_hash(&hash),
+                Err(err) => {
+                    log::error!("Error polling settings: {:?}", err);
+                    continue;
+                }
+            };
+
+            let changes = settings.diff(&tx.borrow());
+            if changes.is_empty() {
+                continue;
+            }
+            log::info!("Settings changed: {}", changes.join(", "));
+            if tx.send(Arc::new(settings)).is_err() {
+                return; // all receivers dropped
+            }
+        }
+    });
+
+    Ok(rx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(fields: &[(&str, &str)]) -> HashMap<String, String> {
+        fields
+            .iter()
+            .map(|&(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn invalid_fields_keep_default() {
+        let settings = Settings::from_hash(&hash(&[
+            ("concurrency", "many"),
+            ("default_pages", "3"),
+            ("default_render_mode", "embed"),
+            ("max_messages", "-1"),
+            ("allowed_guilds", "1, 2,"),
+        ]));
+        assert_eq!(
+            settings,
+            Settings {
+                default_pages: 3,
+                default_render_mode: render::Mode::Embed,
+                allowed_guilds: vec![1, 2],
+                ..Settings::default()
+            }
+        );
+    }
+
+    #[test]
+    fn zero_counts_keep_default() {
+        let settings = Settings::from_hash(&hash(&[
+            ("concurrency", "0"),
+            ("default_pages", "0"),
+            ("max_messages", "0"),
+        ]));
+        assert_eq!(settings, Settings::default());
+        assert!(Settings {
+            concurrency: 0,
+            ..Settings::default()
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn diff_lists_changed_fields() {
+        let old = Settings::default();
+        let new = Settings {
+            concurrency: 16,
+            ..Settings::default()
+        };
+        assert_eq!(new.diff(&old), vec![r#"concurrency: "8" -> "16""#]);
+        assert!(old.diff(&old).is_empty());
+    }
+}
@@ -0,0 +1,303 @@
+//! Header and footer templates of mirror groups.
+//!
+//! Templates are text with `{{ variable }}` placeholders, a small subset of handlebars.
+//! The variables are listed in `VARIABLES`.
+
+use std::cmp;
+use std::fmt::Write;
+
+use anyhow::Context;
+
+use crate::render;
+
+/// The variables that templates may use
+pub const VARIABLES: &[&str] = &[
+    "repo",
+    "path",
+    "branch",
+    "commit",
+    "author",
+    "timestamp",
+    "page",
+    "pages",
+];
+
+/// Page numbers that templates are measured with, so that the space reserved for them
+/// is enough for up to 999 messages
+const WIDEST_PAGE: usize = 100;
+
+/// The length that the header and footer must leave to the content of each page
+pub const MIN_CONTENT_LENGTH: usize = 500;
+
+/// The templates of a mirror group; empty templates are not shown
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Frame {
+    #[serde(default)]
+    pub header: String,
+    #[serde(default)]
+    pub footer: String,
+}
+
+/// The values of the template variables except the page numbers
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Vars {
+    /// `user/repo`
+    pub repo: String,
+    /// The path of the file in the repo
+    pub path: String,
+    pub branch: String,
+    /// The SHA of the pushed commit; `None` when rendering the head of the branch
+    pub commit: Option<String>,
+    /// The author of the pushed commit
+    pub author: Option<String>,
+    /// RFC 3339 time of the render
+    pub timestamp: String,
+}
+
+impl Vars {
+    /// Describes a file, where `path` is the branch followed by the path in the repo.
+    pub fn new(repo: &str, path: &str, commit: Option<&str>, author: Option<&str>) -> Self {
+        let mut split = path.splitn(2, '/');
+        let branch = split.next().unwrap_or_default();
+        let file = split.next().unwrap_or_default();
+        Self {
+            repo: repo.to_string(),
+            path: file.to_string(),
+            branch: branch.to_string(),
+            commit: commit.map(str::to_string),
+            author: author.map(str::to_string),
+            timestamp: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        }
+    }
+
+    /// Returns values as long as real variables usually get,
+    /// to check frames before the file is known.
+    fn widest() -> Self {
+        Self {
+            // GitHub user names and repo names have at most 39 and 100 characters
+            repo: "x".repeat(140),
+            path: "x".repeat(100),
+            branch: "x".repeat(40),
+            commit: Some("x".repeat(40)),
+            author: Some("x".repeat(40)),
+            timestamp: String::from("2000-01-01T00:00:00Z"),
+        }
+    }
+
+    fn get(&self, name: &str, page: usize, pages: usize) -> Option<String> {
+        Some(match name {
+            "repo" => self.repo.clone(),
+            "path" => self.path.clone(),
+            "branch" => self.branch.clone(),
+            // the branch is the best description of an unknown commit
+            "commit" => self.commit.clone().unwrap_or_else(|| self.branch.clone()),
+            "author" => self.author.clone().unwrap_or_default(),
+            "timestamp" => self.timestamp.clone(),
+            "page" => page.to_string(),
+            "pages" => pages.to_string(),
+            _ => return None,
+        })
+    }
+}
+
+/// Splits a template into literal text and variable names.
+fn parse(template: &str) -> anyhow::Result<Vec<(&str, Option<&str>)>> {
+    let mut parts = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let end = rest[start..]
+            .find("}}")
+            .context("A template placeholder is missing `}}`")?;
+        let name = rest[start + 2..start + end].trim();
+        parts.push((&rest[..start], Some(name)));
+        rest = &rest[start + end + 2..];
+    }
+    parts.push((rest, None));
+    Ok(parts)
+}
+
+/// Checks that a template only uses known variables.
+pub fn validate(template: &str) -> anyhow::Result<()> {
+    for (_, name) in parse(template)? {
+        if let Some(name) = name {
+            anyhow::ensure!(
+                VARIABLES.contains(&name),
+                "Unknown template variable {:?}; available variables are {}",
+                name,
+                VARIABLES.join(", ")
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Fills in a template; unknown variables are left as is.
+pub fn render(template: &str, vars: &Vars, page: usize, pages: usize) -> String {
+    let parts = match parse(template) {
+        Ok(parts) => parts,
+        Err(_) => return template.to_string(),
+    };
+    let mut out = String::new();
+    for (text, name) in parts {
+        out.push_str(text);
+        if let Some(name) = name {
+            match vars.get(name, page, pages) {
+                Some(value) => out.push_str(&value),
+                None => {
+                    let _ = write!(out, "{{{{{}}}}}", name);
+                }
+            }
+        }
+    }
+    out
+}
+
+impl Frame {
+    pub fn is_empty(&self) -> bool {
+        self.header.is_empty() && self.footer.is_empty()
+    }
+
+    /// Checks that the templates only use known variables
+    /// and leave at least `MIN_CONTENT_LENGTH` of each message to the content.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        validate(&self.header).context("Invalid header")?;
+        validate(&self.footer).context("Invalid footer")?;
+        anyhow::ensure!(
+            self.reserved(&Vars::widest()) + MIN_CONTENT_LENGTH <= render::MESSAGE_MAX_LENGTH,
+            "The header and footer are too long; together they must leave {} characters to the file",
+            MIN_CONTENT_LENGTH
+        );
+        Ok(())
+    }
+
+    /// Returns the length that the header and footer may take in a page.
+    pub fn reserved(&self, vars: &Vars) -> usize {
+        // each template is followed or preceded by a newline
+        [&self.header, &self.footer]
+            .iter()
+            .filter(|template| !template.is_empty())
+            .map(|template| render(template, vars, WIDEST_PAGE, WIDEST_PAGE).len() + 1)
+            .sum()
+    }
+
+    /// Surrounds a page with the header and footer, one per line.
+    ///
+    /// The header and footer are shortened if the page would exceed `max_len`,
+    /// since variables like the commit author are only known when the page is rendered.
+    pub fn apply(
+        &self,
+        content: &str,
+        vars: &Vars,
+        page: usize,
+        pages: usize,
+        max_len: usize,
+    ) -> String {
+        let mut header = render(&self.header, vars, page, pages);
+        let mut footer = render(&self.footer, vars, page, pages);
+        let newlines = [&header, content, &footer]
+            .iter()
+            .filter(|part| !part.is_empty())
+            .count()
+            .saturating_sub(1);
+        let room = max_len.saturating_sub(content.len() + newlines);
+        if header.len() + footer.len() > room {
+            // each keeps its full length if the other leaves enough room, and half otherwise
+            let header_room = header
+                .len()
+                .min(cmp::max(room / 2, room.saturating_sub(footer.len())));
+            truncate(&mut header, header_room);
+            truncate(&mut footer, room - header_room);
+        }
+
+        let mut parts = Vec::with_capacity(3);
+        if !self.header.is_empty() {
+            parts.push(header);
+        }
+        if !content.is_empty() {
+            parts.push(content.to_string());
+        }
+        if !self.footer.is_empty() {
+            parts.push(footer);
+        }
+        parts.join("\n")
+    }
+}
+
+/// Shortens `text` to at most `max_len` bytes, ending with an ellipsis if anything was cut.
+fn truncate(text: &mut String, max_len: usize) {
+    const ELLIPSIS: &str = "\u{2026}";
+
+    if text.len() <= max_len {
+        return;
+    }
+    if max_len < ELLIPSIS.len() {
+        text.clear();
+        return;
+    }
+    let end = render::floor_char_boundary(text, max_len - ELLIPSIS.len());
+    text.truncate(end);
+    text.push_str(ELLIPSIS);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_fills_variables() {
+        let frame = Frame {
+            header: String::from("Synced from {{ repo }}/{{path}}@{{commit}}, do not edit"),
+            footer: String::from("Page {{page}}/{{pages}} by {{ author }}"),
+        };
+        let vars = Vars::new("SOF3/blob-mirror", "master/RULES.md", None, Some("SOFe"));
+        assert_eq!(
+            frame.apply("content", &vars, 1, 2, render::MESSAGE_MAX_LENGTH),
+            "Synced from SOF3/blob-mirror/RULES.md@master, do not edit\ncontent\nPage 1/2 by SOFe"
+        );
+        assert!(
+            frame
+                .apply("x", &vars, 9, 9, render::MESSAGE_MAX_LENGTH)
+                .len()
+                <= frame.reserved(&vars) + 1
+        );
+        assert_eq!(
+            frame.apply("", &vars, 1, 1, render::MESSAGE_MAX_LENGTH),
+            "Synced from SOF3/blob-mirror/RULES.md@master, do not edit\nPage 1/1 by SOFe"
+        );
+    }
+
+    #[test]
+    fn validate_rejects_unknown_variables() {
+        assert!(validate("{{repo}} {{ page }}").is_ok());
+        assert!(validate("{{ secret }}").is_err());
+        assert!(validate("{{repo").is_err());
+    }
+
+    #[test]
+    fn frame_validate_rejects_long_templates() {
+        let mut frame = Frame {
+            header: String::from("Synced from {{repo}}/{{path}}"),
+            footer: String::from("{{page}}/{{pages}} at {{commit}} by {{author}}"),
+        };
+        assert!(frame.validate().is_ok());
+        frame.footer = "x".repeat(render::MESSAGE_MAX_LENGTH);
+        assert!(frame.validate().is_err());
+        frame.footer = String::from("{{secret}}");
+        assert!(frame.validate().is_err());
+    }
+
+    #[test]
+    fn apply_shortens_long_variables() {
+        let frame = Frame {
+            header: String::from("{{repo}}"),
+            footer: String::from("by {{author}}"),
+        };
+        let author = "é".repeat(render::MESSAGE_MAX_LENGTH);
+        let vars = Vars::new("SOF3/blob-mirror", "master/LICENSE", None, Some(&author));
+        let content = "a".repeat(1000);
+        let page = frame.apply(&content, &vars, 1, 1, render::MESSAGE_MAX_LENGTH);
+        assert!(page.len() <= render::MESSAGE_MAX_LENGTH);
+        assert!(page.starts_with("SOF3/blob-mirror\na"));
+        assert!(page.ends_with('\u{2026}'));
+    }
+}
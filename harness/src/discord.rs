@@ -0,0 +1,381 @@
+//! A fake Discord that records the operations of the bot logic.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use anyhow::Context as _;
+
+use bot::chat::{self, ChatSink, ThreadArchived};
+use bot::permissions;
+use common::render::{Embed, Field};
+
+/// The user ID of the bot on the fake Discord
+pub const BOT_USER_ID: u64 = 1;
+
+/// An operation performed on the fake Discord
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op {
+    Send {
+        channel_id: u64,
+        message_id: u64,
+        content: String,
+    },
+    SendFile {
+        channel_id: u64,
+        message_id: u64,
+        content: String,
+        filename: String,
+        data: Vec<u8>,
+    },
+    Edit {
+        channel_id: u64,
+        message_id: u64,
+        content: String,
+    },
+    SendEmbed {
+        channel_id: u64,
+        message_id: u64,
+        embed: Embed,
+        description: String,
+        fields: Vec<Field>,
+    },
+    EditEmbed {
+        channel_id: u64,
+        message_id: u64,
+        embed: Embed,
+        description: String,
+        fields: Vec<Field>,
+    },
+    Delete {
+        channel_id: u64,
+        message_id: u64,
+    },
+    React {
+        channel_id: u64,
+        message_id: u64,
+        emoji: char,
+    },
+    Unreact {
+        channel_id: u64,
+        message_id: u64,
+        emoji: char,
+    },
+    CreatePost {
+        forum_id: u64,
+        thread_id: u64,
+        title: String,
+        content: String,
+    },
+    Unarchive {
+        channel_id: u64,
+    },
+}
+
+/// A `ChatSink` that keeps messages in memory
+#[derive(Default)]
+pub struct RecordingChat {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    ops: Vec<Op>,
+    next_id: u64,
+    messages: Vec<Message>,
+    /// Threads that reject edits until unarchived
+    archived: Vec<u64>,
+    channels: HashMap<u64, chat::Channel>,
+    guilds: HashMap<u64, permissions::Guild>,
+    /// Role IDs by guild and user ID
+    members: HashMap<(u64, u64), Vec<u64>>,
+}
+
+struct Message {
+    id: u64,
+    channel_id: u64,
+    content: String,
+    reactions: Vec<char>,
+}
+
+impl State {
+    fn find(&mut self, channel_id: u64, message_id: u64) -> anyhow::Result<&mut Message> {
+        match self
+            .messages
+            .iter_mut()
+            .find(|message| message.id == message_id && message.channel_id == channel_id)
+        {
+            Some(message) => Ok(message),
+            None => anyhow::bail!("Unknown message {} in channel {}", message_id, channel_id),
+        }
+    }
+
+    fn ensure_unarchived(&self, channel_id: u64) -> anyhow::Result<()> {
+        if self.archived.contains(&channel_id) {
+            return Err(ThreadArchived.into());
+        }
+        Ok(())
+    }
+
+    /// Sending a message unarchives a thread, like on Discord.
+    fn bump(&mut self, channel_id: u64) {
+        self.archived.retain(|&archived| archived != channel_id);
+    }
+}
+
+impl RecordingChat {
+    /// Returns all operations so far, oldest first.
+    pub fn ops(&self) -> Vec<Op> {
+        self.state.lock().expect("Poisoned lock").ops.clone()
+    }
+
+    /// Adds a message as if posted by a user, returning its ID.
+    pub fn post(&self, channel_id: u64, content: &str) -> u64 {
+        let mut state = self.state.lock().expect("Poisoned lock");
+        state.next_id += 1;
+        let id = state.next_id;
+        state.messages.push(Message {
+            id,
+            channel_id,
+            content: content.to_string(),
+            reactions: Vec::new(),
+        });
+        id
+    }
+
+    /// Archives a thread, e.g. after it was inactive for a while.
+    pub fn archive(&self, channel_id: u64) {
+        let mut state = self.state.lock().expect("Poisoned lock");
+        state.archived.push(channel_id);
+    }
+
+    /// Adds a channel that the bot can look up.
+    pub fn add_channel(&self, channel: chat::Channel) {
+        let mut state = self.state.lock().expect("Poisoned lock");
+        state.channels.insert(channel.id, channel);
+    }
+
+    /// Adds a guild that the bot can look up.
+    pub fn add_guild(&self, guild: permissions::Guild) {
+        let mut state = self.state.lock().expect("Poisoned lock");
+        state.guilds.insert(guild.id, guild);
+    }
+
+    /// Adds a member with roles to a guild; the bot is a member as `BOT_USER_ID`.
+    pub fn add_member(&self, guild_id: u64, user_id: u64, roles: Vec<u64>) {
+        let mut state = self.state.lock().expect("Poisoned lock");
+        state.members.insert((guild_id, user_id), roles);
+    }
+
+    /// Returns the current content of a message, or `None` if it does not exist.
+    pub fn content(&self, message_id: u64) -> Option<String> {
+        let state = self.state.lock().expect("Poisoned lock");
+        state
+            .messages
+            .iter()
+            .find(|message| message.id == message_id)
+            .map(|message| message.content.clone())
+    }
+
+    /// Returns the current reactions of a message.
+    pub fn reactions(&self, message_id: u64) -> Vec<char> {
+        let state = self.state.lock().expect("Poisoned lock");
+        state
+            .messages
+            .iter()
+            .find(|message| message.id == message_id)
+            .map(|message| message.reactions.clone())
+            .unwrap_or_default()
+    }
+}
+
+#[async_trait::async_trait]
+impl ChatSink for RecordingChat {
+    async fn send(&self, channel_id: u64, content: &str) -> anyhow::Result<u64> {
+        let message_id = self.post(channel_id, content);
+        let mut state = self.state.lock().expect("Poisoned lock");
+        state.bump(channel_id);
+        state.ops.push(Op::Send {
+            channel_id,
+            message_id,
+            content: content.to_string(),
+        });
+        Ok(message_id)
+    }
+
+    async fn send_file(
+        &self,
+        channel_id: u64,
+        content: &str,
+        filename: &str,
+        data: &[u8],
+    ) -> anyhow::Result<u64> {
+        let message_id = self.post(channel_id, content);
+        let mut state = self.state.lock().expect("Poisoned lock");
+        state.bump(channel_id);
+        state.ops.push(Op::SendFile {
+            channel_id,
+            message_id,
+            content: content.to_string(),
+            filename: filename.to_string(),
+            data: data.to_vec(),
+        });
+        Ok(message_id)
+    }
+
+    async fn edit(&self, channel_id: u64, message_id: u64, content: &str) -> anyhow::Result<()> {
+        let mut state = self.state.lock().expect("Poisoned lock");
+        state.ensure_unarchived(channel_id)?;
+        state.find(channel_id, message_id)?.content = content.to_string();
+        state.ops.push(Op::Edit {
+            channel_id,
+            message_id,
+            content: content.to_string(),
+        });
+        Ok(())
+    }
+
+    async fn send_embed(
+        &self,
+        channel_id: u64,
+        embed: &Embed,
+        description: &str,
+        fields: &[Field],
+    ) -> anyhow::Result<u64> {
+        // the fake message content is the embed description
+        let message_id = self.post(channel_id, description);
+        let mut state = self.state.lock().expect("Poisoned lock");
+        state.bump(channel_id);
+        state.ops.push(Op::SendEmbed {
+            channel_id,
+            message_id,
+            embed: embed.clone(),
+            description: description.to_string(),
+            fields: fields.to_vec(),
+        });
+        Ok(message_id)
+    }
+
+    async fn edit_embed(
+        &self,
+        channel_id: u64,
+        message_id: u64,
+        embed: &Embed,
+        description: &str,
+        fields: &[Field],
+    ) -> anyhow::Result<()> {
+        let mut state = self.state.lock().expect("Poisoned lock");
+        state.ensure_unarchived(channel_id)?;
+        state.find(channel_id, message_id)?.content = description.to_string();
+        state.ops.push(Op::EditEmbed {
+            channel_id,
+            message_id,
+            embed: embed.clone(),
+            description: description.to_string(),
+            fields: fields.to_vec(),
+        });
+        Ok(())
+    }
+
+    async fn delete(&self, channel_id: u64, message_id: u64) -> anyhow::Result<()> {
+        let mut state = self.state.lock().expect("Poisoned lock");
+        state.ensure_unarchived(channel_id)?;
+        state.find(channel_id, message_id)?;
+        state.messages.retain(|message| message.id != message_id);
+        state.ops.push(Op::Delete {
+            channel_id,
+            message_id,
+        });
+        Ok(())
+    }
+
+    async fn react(&self, channel_id: u64, message_id: u64, emoji: char) -> anyhow::Result<()> {
+        let mut state = self.state.lock().expect("Poisoned lock");
+        state.find(channel_id, message_id)?.reactions.push(emoji);
+        state.ops.push(Op::React {
+            channel_id,
+            message_id,
+            emoji,
+        });
+        Ok(())
+    }
+
+    async fn unreact(&self, channel_id: u64, message_id: u64, emoji: char) -> anyhow::Result<()> {
+        let mut state = self.state.lock().expect("Poisoned lock");
+        state
+            .find(channel_id, message_id)?
+            .reactions
+            .retain(|&reaction| reaction != emoji);
+        state.ops.push(Op::Unreact {
+            channel_id,
+            message_id,
+            emoji,
+        });
+        Ok(())
+    }
+
+    async fn fetch(&self, channel_id: u64, message_id: u64) -> anyhow::Result<String> {
+        let mut state = self.state.lock().expect("Poisoned lock");
+        Ok(state.find(channel_id, message_id)?.content.clone())
+    }
+
+    async fn create_post(&self, forum_id: u64, title: &str, content: &str) -> anyhow::Result<u64> {
+        let mut state = self.state.lock().expect("Poisoned lock");
+        // the first message of a post has the ID of the post thread
+        state.next_id += 1;
+        let thread_id = state.next_id;
+        state.messages.push(Message {
+            id: thread_id,
+            channel_id: thread_id,
+            content: content.to_string(),
+            reactions: Vec::new(),
+        });
+        state.ops.push(Op::CreatePost {
+            forum_id,
+            thread_id,
+            title: title.to_string(),
+            content: content.to_string(),
+        });
+        Ok(thread_id)
+    }
+
+    async fn unarchive(&self, channel_id: u64) -> anyhow::Result<bool> {
+        let mut state = self.state.lock().expect("Poisoned lock");
+        if !state.archived.contains(&channel_id) {
+            return Ok(false);
+        }
+        state.bump(channel_id);
+        state.ops.push(Op::Unarchive { channel_id });
+        Ok(true)
+    }
+
+    async fn channel(&self, channel_id: u64) -> anyhow::Result<chat::Channel> {
+        let state = self.state.lock().expect("Poisoned lock");
+        state
+            .channels
+            .get(&channel_id)
+            .cloned()
+            .with_context(|| format!("Unknown channel {}", channel_id))
+    }
+
+    async fn guild(&self, guild_id: u64) -> anyhow::Result<permissions::Guild> {
+        let state = self.state.lock().expect("Poisoned lock");
+        state
+            .guilds
+            .get(&guild_id)
+            .cloned()
+            .with_context(|| format!("Unknown guild {}", guild_id))
+    }
+
+    async fn member_roles(&self, guild_id: u64, user_id: u64) -> anyhow::Result<Vec<u64>> {
+        let state = self.state.lock().expect("Poisoned lock");
+        state
+            .members
+            .get(&(guild_id, user_id))
+            .cloned()
+            .with_context(|| format!("Unknown member {} in guild {}", user_id, guild_id))
+    }
+
+    async fn current_user_id(&self) -> anyhow::Result<u64> {
+        Ok(BOT_USER_ID)
+    }
+}
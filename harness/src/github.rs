@@ -0,0 +1,386 @@
+//! A mock of the GitHub endpoints used by the bot and the web service.
+//!
+//! The REST API is served under `/api`, matching `github.api_url`.
+//! `github.web_url` and `github.raw_url` point to `/web` and `/raw`,
+//! which are only used to recognise file links.
+//!
+//! Repos with an installation behave like private repos:
+//! they are only served with an access token of that installation.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use hmac::{Hmac, Mac, NewMac};
+use sha2::{Digest, Sha256};
+use warp::http::header::{HeaderValue, ETAG};
+use warp::http::StatusCode;
+use warp::{Filter, Reply};
+
+/// A GitHub server listening on an ephemeral local port
+pub struct GitHub {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Repo IDs by full name
+    repos: HashMap<String, u64>,
+    /// Installation IDs by repo full name
+    installations: HashMap<String, u64>,
+    /// Number of installation access tokens created
+    tokens: usize,
+    /// File contents by `{full_name}/{branch or commit}/{path}`
+    files: HashMap<String, Vec<u8>>,
+    /// Number of commits created by `commit`
+    commits: u64,
+    /// Number of contents API responses with a body
+    full_responses: usize,
+    /// Number of contents API responses that were `304 Not Modified`
+    not_modified: usize,
+    /// Number of upcoming contents API requests that fail with `502 Bad Gateway`
+    failures: usize,
+}
+
+impl State {
+    /// Whether a request may read a repo, like GitHub does for private repos.
+    fn authorized(&self, full_name: &str, authorization: Option<&str>) -> bool {
+        match self.installations.get(full_name) {
+            Some(&installation_id) => authorization == Some(&token_header(installation_id)),
+            None => true,
+        }
+    }
+}
+
+fn token_header(installation_id: u64) -> String {
+    format!("token installation-{}-token", installation_id)
+}
+
+impl GitHub {
+    pub fn start() -> Self {
+        let state = Arc::new(Mutex::new(State::default()));
+
+        let contents = {
+            let state = Arc::clone(&state);
+            warp::get()
+                .and(warp::path!(
+                    "api" / "repos" / String / String / "contents" / ..
+                ))
+                .and(warp::path::tail())
+                .and(warp::query::<HashMap<String, String>>())
+                .and(warp::header::optional::<String>("if-none-match"))
+                .and(warp::header::optional::<String>("authorization"))
+                .map(
+                    move |user: String,
+                          repo: String,
+                          tail: warp::path::Tail,
+                          query: HashMap<String, String>,
+                          if_none_match: Option<String>,
+                          authorization: Option<String>| {
+                        let mut state = state.lock().expect("Poisoned lock");
+                        if state.failures > 0 {
+                            state.failures -= 1;
+                            return StatusCode::BAD_GATEWAY.into_response();
+                        }
+                        if !state
+                            .authorized(&format!("{}/{}", user, repo), authorization.as_deref())
+                        {
+                            return not_found();
+                        }
+                        let git_ref = query.get("ref").map_or("master", String::as_str);
+                        let key = format!("{}/{}/{}/{}", user, repo, git_ref, tail.as_str());
+                        let content = match state.files.get(&key) {
+                            Some(content) => content.clone(),
+                            None => {
+                                return not_found();
+                            }
+                        };
+
+                        let sha = blob_sha(&content);
+                        let etag = format!("\"{}\"", sha);
+                        if if_none_match.as_deref() == Some(etag.as_str()) {
+                            state.not_modified += 1;
+                            let mut response = StatusCode::NOT_MODIFIED.into_response();
+                            response
+                                .headers_mut()
+                                .insert(ETAG, HeaderValue::from_str(&etag).expect("Valid ETag"));
+                            return response;
+                        }
+
+                        state.full_responses += 1;
+                        let mut response = warp::reply::json(&serde_json::json!({
+                            "type": "file",
+                            "sha": sha,
+                            "size": content.len(),
+                            "encoding": "base64",
+                            "content": base64::encode(&content),
+                        }))
+                        .into_response();
+                        response
+                            .headers_mut()
+                            .insert(ETAG, HeaderValue::from_str(&etag).expect("Valid ETag"));
+                        response
+                    },
+                )
+        };
+        let repo = {
+            let state = Arc::clone(&state);
+            warp::get()
+                .and(warp::path!("api" / "repos" / String / String))
+                .and(warp::header::optional::<String>("authorization"))
+                .map(
+                    move |user: String, repo: String, authorization: Option<String>| {
+                        let state = state.lock().expect("Poisoned lock");
+                        let full_name = format!("{}/{}", user, repo);
+                        match state.repos.get(&full_name) {
+                            Some(&id) if state.authorized(&full_name, authorization.as_deref()) => {
+                                warp::reply::json(&serde_json::json!({
+                                    "id": id,
+                                    "full_name": full_name,
+                                }))
+                                .into_response()
+                            }
+                            _ => not_found(),
+                        }
+                    },
+                )
+        };
+        let installation = {
+            let state = Arc::clone(&state);
+            warp::get()
+                .and(warp::path!(
+                    "api" / "repos" / String / String / "installation"
+                ))
+                .and(app_jwt())
+                .map(move |user: String, repo: String| {
+                    let state = state.lock().expect("Poisoned lock");
+                    match state.installations.get(&format!("{}/{}", user, repo)) {
+                        Some(&id) => {
+                            warp::reply::json(&serde_json::json!({ "id": id })).into_response()
+                        }
+                        None => not_found(),
+                    }
+                })
+        };
+        let access_token = {
+            let state = Arc::clone(&state);
+            warp::post()
+                .and(warp::path!(
+                    "api" / "app" / "installations" / u64 / "access_tokens"
+                ))
+                .and(app_jwt())
+                .map(move |installation_id: u64| {
+                    let mut state = state.lock().expect("Poisoned lock");
+                    state.tokens += 1;
+                    warp::reply::with_status(
+                        warp::reply::json(&serde_json::json!({
+                            "token": format!("installation-{}-token", installation_id),
+                            "expires_at": "2100-01-01T00:00:00Z",
+                        })),
+                        StatusCode::CREATED,
+                    )
+                    .into_response()
+                })
+        };
+
+        let routes = contents.or(repo).or(installation).or(access_token);
+        let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        Self { addr, state }
+    }
+
+    /// Base URL to use as `github.web_url`
+    pub fn web_url(&self) -> String {
+        format!("http://{}/web", self.addr)
+    }
+
+    /// Base URL to use as `github.raw_url`
+    pub fn raw_url(&self) -> String {
+        format!("http://{}/raw", self.addr)
+    }
+
+    /// Base URL to use as `github.api_url`
+    pub fn api_url(&self) -> String {
+        format!("http://{}/api", self.addr)
+    }
+
+    /// Creates a repo, e.g. `add_repo("SOF3/blob-mirror", 42)`.
+    pub fn add_repo(&self, full_name: &str, id: u64) {
+        let mut state = self.state.lock().expect("Poisoned lock");
+        state.repos.insert(full_name.to_string(), id);
+    }
+
+    /// Installs the GitHub App on a repo, which makes the repo private.
+    pub fn install(&self, full_name: &str, installation_id: u64) {
+        let mut state = self.state.lock().expect("Poisoned lock");
+        state
+            .installations
+            .insert(full_name.to_string(), installation_id);
+    }
+
+    /// Makes the next `count` contents API requests fail, like a GitHub outage.
+    pub fn fail_next(&self, count: usize) {
+        self.state.lock().expect("Poisoned lock").failures = count;
+    }
+
+    /// Returns the number of installation access tokens created.
+    pub fn tokens(&self) -> usize {
+        self.state.lock().expect("Poisoned lock").tokens
+    }
+
+    /// Snapshots the files of a branch as a new commit, returning its SHA.
+    pub fn commit(&self, full_name: &str, branch: &str) -> String {
+        let mut state = self.state.lock().expect("Poisoned lock");
+        state.commits += 1;
+        let sha = format!("{:040x}", state.commits);
+
+        let prefix = format!("{}/{}/", full_name, branch);
+        let files: Vec<(String, Vec<u8>)> = state
+            .files
+            .iter()
+            .filter_map(|(key, content)| {
+                let path = key.strip_prefix(&prefix)?;
+                Some((format!("{}/{}/{}", full_name, &sha, path), content.clone()))
+            })
+            .collect();
+        state.files.extend(files);
+        sha
+    }
+
+    /// Returns the number of contents API responses with a body and with `304 Not Modified`.
+    pub fn contents_requests(&self) -> (usize, usize) {
+        let state = self.state.lock().expect("Poisoned lock");
+        (state.full_responses, state.not_modified)
+    }
+
+    /// Creates or replaces a file, e.g. `put_file("SOF3/blob-mirror", "master/README.md", "...")`.
+    pub fn put_file(&self, full_name: &str, branch_and_path: &str, content: &str) {
+        self.put_bytes(full_name, branch_and_path, content.as_bytes());
+    }
+
+    /// Creates or replaces a binary file.
+    pub fn put_bytes(&self, full_name: &str, branch_and_path: &str, content: &[u8]) {
+        let mut state = self.state.lock().expect("Poisoned lock");
+        state.files.insert(
+            format!("{}/{}", full_name, branch_and_path),
+            content.to_vec(),
+        );
+    }
+}
+
+fn not_found() -> warp::reply::Response {
+    warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({ "message": "Not Found" })),
+        StatusCode::NOT_FOUND,
+    )
+    .into_response()
+}
+
+/// Rejects requests that are not authenticated as the app.
+///
+/// The JWT signature is not verified.
+fn app_jwt() -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::header::<String>("authorization")
+        .and_then(|authorization: String| async move {
+            if authorization.starts_with("Bearer ") {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one()
+}
+
+/// A stand-in for the git blob SHA of a file
+fn blob_sha(content: &[u8]) -> String {
+    hex::encode(&Sha256::digest(content)[..20])
+}
+
+/// Computes the `X-Hub-Signature-256` header of a webhook payload.
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes()).expect("HMAC accepts any key");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Builds a `push` event payload for a commit pushed to a branch of a repo covered by `installation_id`.
+pub fn push_event(
+    installation_id: u64,
+    repo_id: u64,
+    full_name: &str,
+    branch: &str,
+    commit: &str,
+) -> serde_json::Value {
+    serde_json::json!({
+        "ref": format!("refs/heads/{}", branch),
+        "after": commit,
+        "head_commit": {
+            "id": commit,
+            "author": { "name": "Octocat" },
+        },
+        "installation": {
+            "id": installation_id,
+            "account": { "login": full_name.split('/').next() },
+        },
+        "repository": {
+            "id": repo_id,
+            "full_name": full_name,
+        },
+    })
+}
+
+/// Builds an `installation` event payload of an installation owned by the owner of `repos`.
+pub fn installation_event(
+    action: &str,
+    installation_id: u64,
+    repos: &[(u64, &str)],
+) -> serde_json::Value {
+    serde_json::json!({
+        "action": action,
+        "repositories": repos
+            .iter()
+            .map(|&(id, full_name)| repo_json(id, full_name))
+            .collect::<Vec<_>>(),
+        "installation": installation_json(installation_id, repos),
+    })
+}
+
+/// Builds an `installation_repositories` event payload.
+pub fn installation_repositories_event(
+    installation_id: u64,
+    added: &[(u64, &str)],
+    removed: &[(u64, &str)],
+) -> serde_json::Value {
+    serde_json::json!({
+        "action": if added.is_empty() { "removed" } else { "added" },
+        "repositories_added": added
+            .iter()
+            .map(|&(id, full_name)| repo_json(id, full_name))
+            .collect::<Vec<_>>(),
+        "repositories_removed": removed
+            .iter()
+            .map(|&(id, full_name)| repo_json(id, full_name))
+            .collect::<Vec<_>>(),
+        "installation": installation_json(installation_id, &[added, removed].concat()),
+    })
+}
+
+fn repo_json(id: u64, full_name: &str) -> serde_json::Value {
+    serde_json::json!({
+        "id": id,
+        "full_name": full_name,
+    })
+}
+
+fn installation_json(installation_id: u64, repos: &[(u64, &str)]) -> serde_json::Value {
+    let login = repos
+        .first()
+        .and_then(|(_, full_name)| full_name.split('/').next());
+    serde_json::json!({
+        "id": installation_id,
+        "account": { "login": login },
+        "permissions": { "contents": "read", "metadata": "read" },
+    })
+}
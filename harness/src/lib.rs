@@ -0,0 +1,123 @@
+//! Offline integration test harness.
+//!
+//! `Harness::start` runs an in-memory Redis stand-in and a mock GitHub on local ports,
+//! so that the webhook receiver and the mirror logic can be exercised by `cargo test`
+//! without network access or real credentials.
+//! Discord is replaced by `discord::RecordingChat`.
+
+use std::sync::Arc;
+
+use tokio::sync::watch;
+use warp::http::StatusCode;
+
+use common::contents::Fetcher;
+use common::db;
+use common::secret::Secret;
+use common::settings::Settings;
+
+pub mod discord;
+pub mod github;
+pub mod redis;
+
+/// The webhook secret configured in the harness
+pub const WEBHOOK_SECRET: &str = "harness-webhook-secret";
+
+/// The private key of the GitHub App, generated for the harness only
+const APP_KEY: &str = include_str!("../fixtures/app-key.pem");
+
+pub struct Harness {
+    pub redis: redis::Redis,
+    pub github: github::GitHub,
+    pub chat: discord::RecordingChat,
+    pub fetcher: Fetcher,
+    pub secret: Secret,
+    pub conn: Arc<db::Conn>,
+    pub settings: watch::Sender<Arc<Settings>>,
+    settings_rx: watch::Receiver<Arc<Settings>>,
+    /// The content cache of the web service, separate from the bot's `fetcher`
+    web_fetcher: Arc<Fetcher>,
+}
+
+impl Harness {
+    pub async fn start() -> Self {
+        let redis = redis::Redis::start().await;
+        let github = github::GitHub::start();
+
+        let secret: Secret = serde_json::from_value(serde_json::json!({
+            "discord": {
+                "client_id": 1,
+                "token": "harness-discord-token",
+            },
+            "github": {
+                "webhook_secret": WEBHOOK_SECRET,
+                "app_id": 1,
+                "private_key": APP_KEY,
+                "api_url": github.api_url(),
+                "raw_url": github.raw_url(),
+                "web_url": github.web_url(),
+            },
+            "web": {
+                "port": 0,
+            },
+            "redis": {
+                "addr": redis.addr().to_string(),
+            },
+        }))
+        .expect("Harness secret is valid");
+        let conn = Arc::new(
+            db::Conn::new(&secret)
+                .await
+                .expect("Failed to connect to redis stand-in"),
+        );
+        let (settings, settings_rx) = watch::channel(Arc::new(Settings::default()));
+        let fetcher = || Fetcher::new(&secret.github).expect("Harness app key is valid");
+
+        Self {
+            redis,
+            github,
+            chat: discord::RecordingChat::default(),
+            fetcher: fetcher(),
+            web_fetcher: Arc::new(fetcher()),
+            secret,
+            conn,
+            settings,
+            settings_rx,
+        }
+    }
+
+    /// Returns the routes of the web service, as served by `web`.
+    pub fn routes(
+        &self,
+    ) -> impl warp::Filter<Extract = impl warp::Reply, Error = std::convert::Infallible> + Clone
+    {
+        web::routes(
+            &self.secret,
+            Arc::clone(&self.conn),
+            self.settings_rx.clone(),
+            Arc::clone(&self.web_fetcher),
+        )
+    }
+
+    /// Delivers a signed webhook event to the web service routes,
+    /// returning the response status and JSON body.
+    pub async fn webhook(
+        &self,
+        event: &str,
+        payload: &serde_json::Value,
+        delivery: &str,
+    ) -> (StatusCode, serde_json::Value) {
+        let body = serde_json::to_vec(payload).expect("Payload is serializable");
+        let response = warp::test::request()
+            .method("POST")
+            .path("/webhook")
+            .header("Content-Type", "application/json")
+            .header("X-GitHub-Event", event)
+            .header("X-GitHub-Delivery", delivery)
+            .header("X-Hub-Signature-256", github::sign(WEBHOOK_SECRET, &body))
+            .body(body)
+            .reply(&self.routes())
+            .await;
+        let json = serde_json::from_slice(response.body()).unwrap_or(serde_json::Value::Null);
+        (response.status(), json)
+    }
+}
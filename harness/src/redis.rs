@@ -0,0 +1,588 @@
+//! An in-process stand-in for `redis-server`.
+//!
+//! Only the commands used by `common::db` are implemented,
+//! with the reply types that `redis-async` expects.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+/// A Redis server listening on an ephemeral local port
+pub struct Redis {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+}
+
+impl Redis {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind(("127.0.0.1", 0))
+            .await
+            .expect("Failed to bind redis stand-in");
+        let addr = listener.local_addr().expect("Listener has an address");
+        let state = Arc::new(Mutex::new(State::default()));
+
+        {
+            let state = Arc::clone(&state);
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    tokio::spawn(serve(stream, Arc::clone(&state)));
+                }
+            });
+        }
+
+        Self { addr, state }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Runs a command directly, as if sent by a client.
+    pub fn execute(&self, args: &[&str]) -> Reply {
+        let args = args.iter().map(|arg| arg.to_string()).collect();
+        execute(&mut self.state.lock().expect("Poisoned lock"), args)
+    }
+
+    /// Waits until a client has subscribed to `topic`.
+    pub async fn wait_for_subscriber(&self, topic: &str) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            {
+                let state = self.state.lock().expect("Poisoned lock");
+                if state.subscribers.get(topic).map_or(0, Vec::len) > 0 {
+                    return;
+                }
+            }
+            assert!(
+                Instant::now() < deadline,
+                "Nobody subscribed to {:?}",
+                topic
+            );
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+}
+
+/// A RESP reply
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    Status(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(String),
+    Nil,
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Self::Status(status) => out.extend_from_slice(format!("+{}\r\n", status).as_bytes()),
+            Self::Error(err) => out.extend_from_slice(format!("-{}\r\n", err).as_bytes()),
+            Self::Integer(int) => out.extend_from_slice(format!(":{}\r\n", int).as_bytes()),
+            Self::Bulk(bulk) => {
+                out.extend_from_slice(format!("${}\r\n", bulk.len()).as_bytes());
+                out.extend_from_slice(bulk.as_bytes());
+                out.extend_from_slice(b"\r\n");
+            }
+            Self::Nil => out.extend_from_slice(b"$-1\r\n"),
+            Self::Array(items) => {
+                out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.encode(out);
+                }
+            }
+        }
+    }
+
+    fn bulks(items: impl IntoIterator<Item = String>) -> Self {
+        Self::Array(items.into_iter().map(Self::Bulk).collect())
+    }
+}
+
+#[derive(Default)]
+struct State {
+    keys: HashMap<String, Entry>,
+    subscribers: HashMap<String, Vec<mpsc::UnboundedSender<(String, String)>>>,
+}
+
+struct Entry {
+    value: Value,
+    expires: Option<Instant>,
+}
+
+enum Value {
+    String(String),
+    List(Vec<String>),
+    Set(BTreeSet<String>),
+    Hash(BTreeMap<String, String>),
+    SortedSet(BTreeMap<String, f64>),
+}
+
+impl State {
+    fn purge(&mut self, key: &str) {
+        let expires = self.keys.get(key).and_then(|entry| entry.expires);
+        if matches!(expires, Some(expires) if expires <= Instant::now()) {
+            self.keys.remove(key);
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Option<&mut Value> {
+        self.purge(key);
+        self.keys.get_mut(key).map(|entry| &mut entry.value)
+    }
+
+    fn get_or_insert(&mut self, key: &str, value: impl FnOnce() -> Value) -> &mut Value {
+        self.purge(key);
+        &mut self
+            .keys
+            .entry(key.to_string())
+            .or_insert_with(|| Entry {
+                value: value(),
+                expires: None,
+            })
+            .value
+    }
+}
+
+async fn serve(stream: TcpStream, state: Arc<Mutex<State>>) {
+    let (read, mut write) = stream.into_split();
+
+    // commands are read in a separate task because `read_command` is not cancel-safe
+    let (command_tx, mut commands) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let mut read = BufReader::new(read);
+        while let Ok(Some(args)) = read_command(&mut read).await {
+            if command_tx.send(args).is_err() {
+                return;
+            }
+        }
+    });
+
+    let (message_tx, mut messages) = mpsc::unbounded_channel();
+    let mut subscriptions = 0;
+    loop {
+        let mut out = Vec::new();
+        tokio::select! {
+            args = commands.recv() => {
+                let args = match args {
+                    Some(args) => args,
+                    None => return,
+                };
+                let mut state = state.lock().expect("Poisoned lock");
+                if args[0].eq_ignore_ascii_case("SUBSCRIBE") {
+                    for topic in &args[1..] {
+                        state
+                            .subscribers
+                            .entry(topic.clone())
+                            .or_default()
+                            .push(message_tx.clone());
+                        subscriptions += 1;
+                        Reply::Array(vec![
+                            Reply::Bulk(String::from("subscribe")),
+                            Reply::Bulk(topic.clone()),
+                            Reply::Integer(subscriptions),
+                        ])
+                        .encode(&mut out);
+                    }
+                } else {
+                    execute(&mut state, args).encode(&mut out);
+                }
+            }
+            Some((topic, payload)) = messages.recv() => {
+                Reply::Array(vec![
+                    Reply::Bulk(String::from("message")),
+                    Reply::Bulk(topic),
+                    Reply::Bulk(payload),
+                ])
+                .encode(&mut out);
+            }
+        }
+        if write.write_all(&out).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn read_command(read: &mut BufReader<OwnedReadHalf>) -> io::Result<Option<Vec<String>>> {
+    fn invalid(what: &str) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, what)
+    }
+
+    async fn header(read: &mut BufReader<OwnedReadHalf>, prefix: char) -> io::Result<usize> {
+        let mut line = String::new();
+        if read.read_line(&mut line).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        line.trim_end()
+            .strip_prefix(prefix)
+            .and_then(|len| len.parse().ok())
+            .ok_or_else(|| invalid("Malformed RESP header"))
+    }
+
+    let count = match header(read, '*').await {
+        Ok(count) => count,
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        let len = header(read, '$').await?;
+        let mut buf = vec![0; len + 2];
+        read.read_exact(&mut buf).await?;
+        buf.truncate(len);
+        args.push(String::from_utf8(buf).map_err(|_| invalid("Argument is not UTF-8"))?);
+    }
+    if args.is_empty() {
+        return Err(invalid("Empty command"));
+    }
+    Ok(Some(args))
+}
+
+fn wrong_type() -> Reply {
+    Reply::Error(String::from(
+        "WRONGTYPE Operation against a key holding the wrong kind of value",
+    ))
+}
+
+fn syntax_error() -> Reply {
+    Reply::Error(String::from("ERR syntax error"))
+}
+
+/// Matches a `SCAN MATCH` pattern supporting `*` and `?`.
+fn glob(pattern: &[u8], text: &[u8]) -> bool {
+    match (pattern.split_first(), text.split_first()) {
+        (None, None) => true,
+        (Some((b'*', rest)), _) => {
+            glob(rest, text) || (!text.is_empty() && glob(pattern, &text[1..]))
+        }
+        (Some((b'?', rest)), Some((_, text))) => glob(rest, text),
+        (Some((p, rest)), Some((t, text))) => p == t && glob(rest, text),
+        _ => false,
+    }
+}
+
+fn parse_score(score: &str) -> Option<f64> {
+    match score {
+        "-inf" => Some(f64::NEG_INFINITY),
+        "+inf" | "inf" => Some(f64::INFINITY),
+        score => score.parse().ok(),
+    }
+}
+
+fn execute(state: &mut State, args: Vec<String>) -> Reply {
+    let command = args[0].to_ascii_uppercase();
+    let args = &args[1..];
+
+    match (command.as_str(), args) {
+        ("PING", _) => Reply::Status("PONG"),
+        ("GET", [key]) => match state.get(key) {
+            Some(Value::String(value)) => Reply::Bulk(value.clone()),
+            Some(_) => wrong_type(),
+            None => Reply::Nil,
+        },
+        ("SET", [key, value, options @ ..]) => {
+            let mut nx = false;
+            let mut expires = None;
+            let mut options = options.iter();
+            while let Some(option) = options.next() {
+                match option.to_ascii_uppercase().as_str() {
+                    "NX" => nx = true,
+                    "EX" => match options.next().and_then(|secs| secs.parse().ok()) {
+                        Some(secs) => expires = Some(Instant::now() + Duration::from_secs(secs)),
+                        None => return syntax_error(),
+                    },
+                    _ => return syntax_error(),
+                }
+            }
+            if nx && state.get(key).is_some() {
+                return Reply::Nil;
+            }
+            state.keys.insert(
+                key.clone(),
+                Entry {
+                    value: Value::String(value.clone()),
+                    expires,
+                },
+            );
+            Reply::Status("OK")
+        }
+        ("MGET", keys) => Reply::Array(
+            keys.iter()
+                .map(|key| match state.get(key) {
+                    Some(Value::String(value)) => Reply::Bulk(value.clone()),
+                    _ => Reply::Nil,
+                })
+                .collect(),
+        ),
+        ("MSET", pairs) if pairs.len() % 2 == 0 => {
+            for pair in pairs.chunks(2) {
+                state.keys.insert(
+                    pair[0].clone(),
+                    Entry {
+                        value: Value::String(pair[1].clone()),
+                        expires: None,
+                    },
+                );
+            }
+            Reply::Status("OK")
+        }
+        ("DEL", keys) => {
+            let mut removed = 0;
+            for key in keys {
+                state.purge(key);
+                if state.keys.remove(key).is_some() {
+                    removed += 1;
+                }
+            }
+            Reply::Integer(removed)
+        }
+        ("EXISTS", keys) => {
+            Reply::Integer(keys.iter().filter(|key| state.get(key).is_some()).count() as i64)
+        }
+        ("EXPIRE", [key, secs]) => {
+            let secs = match secs.parse() {
+                Ok(secs) => secs,
+                Err(_) => return syntax_error(),
+            };
+            state.purge(key);
+            match state.keys.get_mut(key) {
+                Some(entry) => {
+                    entry.expires = Some(Instant::now() + Duration::from_secs(secs));
+                    Reply::Integer(1)
+                }
+                None => Reply::Integer(0),
+            }
+        }
+        ("RENAME", [from, to]) => {
+            state.purge(from);
+            match state.keys.remove(from) {
+                Some(entry) => {
+                    state.keys.insert(to.clone(), entry);
+                    Reply::Status("OK")
+                }
+                None => Reply::Error(String::from("ERR no such key")),
+            }
+        }
+        ("SCAN", [_cursor, options @ ..]) => {
+            let mut pattern = "*";
+            let mut options = options.iter();
+            while let Some(option) = options.next() {
+                match (option.to_ascii_uppercase().as_str(), options.next()) {
+                    ("MATCH", Some(value)) => pattern = value,
+                    ("COUNT", Some(_)) => {}
+                    _ => return syntax_error(),
+                }
+            }
+            let now = Instant::now();
+            let keys = state
+                .keys
+                .iter()
+                .filter(|(_, entry)| !matches!(entry.expires, Some(expires) if expires <= now))
+                .map(|(key, _)| key)
+                .filter(|key| glob(pattern.as_bytes(), key.as_bytes()))
+                .cloned();
+            Reply::Array(vec![
+                Reply::Bulk(String::from("0")),
+                Reply::bulks(keys.collect::<Vec<_>>()),
+            ])
+        }
+        ("RPUSH", [key, values @ ..]) if !values.is_empty() => {
+            match state.get_or_insert(key, || Value::List(Vec::new())) {
+                Value::List(list) => {
+                    list.extend(values.iter().cloned());
+                    Reply::Integer(list.len() as i64)
+                }
+                _ => wrong_type(),
+            }
+        }
+        ("LRANGE", [key, start, stop]) | ("LTRIM", [key, start, stop]) => {
+            let (start, stop): (i64, i64) = match (start.parse(), stop.parse()) {
+                (Ok(start), Ok(stop)) => (start, stop),
+                _ => return syntax_error(),
+            };
+            let trim = command == "LTRIM";
+            let list = match state.get(key) {
+                Some(Value::List(list)) => list,
+                Some(_) => return wrong_type(),
+                None if trim => return Reply::Status("OK"),
+                None => return Reply::Array(Vec::new()),
+            };
+
+            let len = list.len() as i64;
+            let resolve = |index: i64| if index < 0 { len + index } else { index };
+            let start = resolve(start).max(0);
+            let stop = resolve(stop).min(len - 1);
+            let range = if start > stop {
+                0..0
+            } else {
+                start as usize..(stop + 1) as usize
+            };
+            if trim {
+                *list = list[range].to_vec();
+                Reply::Status("OK")
+            } else {
+                Reply::bulks(list[range].to_vec())
+            }
+        }
+        ("SADD", [key, members @ ..]) if !members.is_empty() => {
+            match state.get_or_insert(key, || Value::Set(BTreeSet::new())) {
+                Value::Set(set) => Reply::Integer(
+                    members
+                        .iter()
+                        .filter(|member| set.insert(member.to_string()))
+                        .count() as i64,
+                ),
+                _ => wrong_type(),
+            }
+        }
+        ("SREM", [key, members @ ..]) => match state.get(key) {
+            Some(Value::Set(set)) => Reply::Integer(
+                members
+                    .iter()
+                    .filter(|member| set.remove(member.as_str()))
+                    .count() as i64,
+            ),
+            Some(_) => wrong_type(),
+            None => Reply::Integer(0),
+        },
+        ("SMEMBERS", [key]) => match state.get(key) {
+            Some(Value::Set(set)) => Reply::bulks(set.iter().cloned().collect::<Vec<_>>()),
+            Some(_) => wrong_type(),
+            None => Reply::Array(Vec::new()),
+        },
+        ("SISMEMBER", [key, member]) => match state.get(key) {
+            Some(Value::Set(set)) => Reply::Integer(set.contains(member) as i64),
+            Some(_) => wrong_type(),
+            None => Reply::Integer(0),
+        },
+        ("HSET", [key, pairs @ ..]) if !pairs.is_empty() && pairs.len() % 2 == 0 => {
+            match state.get_or_insert(key, || Value::Hash(BTreeMap::new())) {
+                Value::Hash(hash) => Reply::Integer(
+                    pairs
+                        .chunks(2)
+                        .filter(|pair| hash.insert(pair[0].clone(), pair[1].clone()).is_none())
+                        .count() as i64,
+                ),
+                _ => wrong_type(),
+            }
+        }
+        ("HSETNX", [key, field, value]) => {
+            match state.get_or_insert(key, || Value::Hash(BTreeMap::new())) {
+                Value::Hash(hash) if hash.contains_key(field) => Reply::Integer(0),
+                Value::Hash(hash) => {
+                    hash.insert(field.clone(), value.clone());
+                    Reply::Integer(1)
+                }
+                _ => wrong_type(),
+            }
+        }
+        ("HGET", [key, field]) => match state.get(key) {
+            Some(Value::Hash(hash)) => hash
+                .get(field)
+                .map_or(Reply::Nil, |value| Reply::Bulk(value.clone())),
+            Some(_) => wrong_type(),
+            None => Reply::Nil,
+        },
+        ("HGETALL", [key]) => match state.get(key) {
+            Some(Value::Hash(hash)) => Reply::bulks(
+                hash.iter()
+                    .flat_map(|(field, value)| vec![field.clone(), value.clone()])
+                    .collect::<Vec<_>>(),
+            ),
+            Some(_) => wrong_type(),
+            None => Reply::Array(Vec::new()),
+        },
+        ("ZADD", [key, pairs @ ..]) if !pairs.is_empty() && pairs.len() % 2 == 0 => {
+            let mut scored = Vec::new();
+            for pair in pairs.chunks(2) {
+                match parse_score(&pair[0]) {
+                    Some(score) => scored.push((pair[1].clone(), score)),
+                    None => return syntax_error(),
+                }
+            }
+            match state.get_or_insert(key, || Value::SortedSet(BTreeMap::new())) {
+                Value::SortedSet(set) => Reply::Integer(
+                    scored
+                        .into_iter()
+                        .filter(|(member, score)| set.insert(member.clone(), *score).is_none())
+                        .count() as i64,
+                ),
+                _ => wrong_type(),
+            }
+        }
+        ("ZRANGEBYSCORE", [key, min, max]) => {
+            let (min, max) = match (parse_score(min), parse_score(max)) {
+                (Some(min), Some(max)) => (min, max),
+                _ => return syntax_error(),
+            };
+            match state.get(key) {
+                Some(Value::SortedSet(set)) => {
+                    let mut members: Vec<_> = set
+                        .iter()
+                        .filter(|(_, &score)| min <= score && score <= max)
+                        .collect();
+                    members.sort_by(|a, b| a.1.partial_cmp(b.1).expect("Scores are not NaN"));
+                    Reply::bulks(
+                        members
+                            .into_iter()
+                            .map(|(member, _)| member.clone())
+                            .collect::<Vec<_>>(),
+                    )
+                }
+                Some(_) => wrong_type(),
+                None => Reply::Array(Vec::new()),
+            }
+        }
+        ("ZREM", [key, members @ ..]) => match state.get(key) {
+            Some(Value::SortedSet(set)) => Reply::Integer(
+                members
+                    .iter()
+                    .filter(|member| set.remove(member.as_str()).is_some())
+                    .count() as i64,
+            ),
+            Some(_) => wrong_type(),
+            None => Reply::Integer(0),
+        },
+        ("PUBLISH", [topic, payload]) => {
+            let subscribers = state.subscribers.entry(topic.clone()).or_default();
+            subscribers.retain(|tx| tx.send((topic.clone(), payload.clone())).is_ok());
+            Reply::Integer(subscribers.len() as i64)
+        }
+        _ => Reply::Error(format!(
+            "ERR unknown command or wrong number of arguments for '{}'",
+            command
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_matches_prefix_patterns() {
+        assert!(glob(b"repo:*", b"repo:1"));
+        assert!(!glob(b"repo:*", b"repo-name:1"));
+        assert!(glob(b"a?c", b"abc"));
+    }
+
+    #[test]
+    fn ltrim_keeps_tail() {
+        let mut state = State::default();
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect();
+        execute(&mut state, args(&["RPUSH", "list", "1", "2", "3", "4"]));
+        execute(&mut state, args(&["LTRIM", "list", "-2", "-1"]));
+        assert_eq!(
+            execute(&mut state, args(&["LRANGE", "list", "0", "-1"])),
+            Reply::bulks(vec![String::from("3"), String::from("4")])
+        );
+    }
+}
@@ -0,0 +1,1094 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+
+use bot::chat::{Channel, ChannelKind};
+use bot::mirror::{Author, Mirror, Options, Target};
+use bot::permissions;
+use common::settings::Settings;
+use common::{db, format, render, template};
+use harness::discord::Op;
+use harness::redis::Reply;
+use harness::{github, Harness};
+
+const REPO: &str = "SOF3/blob-mirror";
+const REPO_ID: u64 = 42;
+const INSTALLATION_ID: u64 = 7;
+const GUILD_ID: u64 = 100;
+const CHANNEL_ID: u64 = 200;
+const COMMIT: &str = "0123456789abcdef0123456789abcdef01234567";
+const README: &str = "master/README.md";
+/// A channel that mirrors are posted in by commands issued in `CHANNEL_ID`
+const OTHER_CHANNEL_ID: u64 = 201;
+/// A role that may manage messages in `GUILD_ID`
+const MODERATOR_ROLE: u64 = 300;
+/// The user issuing mirror commands
+const AUTHOR_ID: u64 = 400;
+
+/// The channel that mirrors are posted in unless a test says otherwise
+const TARGET: Target = Target {
+    guild_id: GUILD_ID,
+    channel_id: CHANNEL_ID,
+    forum: false,
+};
+
+/// Returns the web interface link of a file, where `path` starts with the branch.
+fn file_url(harness: &Harness, path: &str) -> String {
+    format!("{}/{}/blob/{}", harness.github.web_url(), REPO, path)
+}
+
+async fn create(
+    harness: &Harness,
+    target: Target,
+    path: &str,
+    options: Options,
+) -> anyhow::Result<Mirror> {
+    bot::mirror::create(
+        &harness.chat,
+        &harness.conn,
+        &harness.fetcher,
+        &harness.secret.github,
+        target,
+        &file_url(harness, path),
+        options,
+    )
+    .await
+}
+
+async fn mirror_with(harness: &Harness, path: &str, options: Options) -> Mirror {
+    create(harness, TARGET, path, options)
+        .await
+        .expect("Failed to create mirror")
+}
+
+async fn mirror(harness: &Harness, pages: usize) -> Mirror {
+    mirror_with(
+        harness,
+        README,
+        Options {
+            pages,
+            ..Options::default()
+        },
+    )
+    .await
+}
+
+fn text_channel(id: u64, guild_id: u64) -> Channel {
+    Channel {
+        id,
+        guild_id: Some(guild_id),
+        kind: Some(ChannelKind::Text),
+        archived: false,
+        parent_id: None,
+        overwrites: Vec::new(),
+    }
+}
+
+/// Adds `GUILD_ID` with the command and other channel,
+/// where everyone can post and `MODERATOR_ROLE` can manage messages.
+fn add_guild(harness: &Harness) {
+    harness.chat.add_guild(permissions::Guild {
+        id: GUILD_ID,
+        owner_id: 0,
+        roles: vec![
+            (
+                GUILD_ID,
+                permissions::VIEW_CHANNEL | permissions::SEND_MESSAGES | permissions::EMBED_LINKS,
+            ),
+            (MODERATOR_ROLE, permissions::MANAGE_MESSAGES),
+        ]
+        .into_iter()
+        .collect(),
+    });
+    harness.chat.add_channel(text_channel(CHANNEL_ID, GUILD_ID));
+    harness
+        .chat
+        .add_channel(text_channel(OTHER_CHANNEL_ID, GUILD_ID));
+    harness
+        .chat
+        .add_member(GUILD_ID, harness::discord::BOT_USER_ID, Vec::new());
+}
+
+/// Resolves the target of a `mirror` command issued in `CHANNEL_ID` by `AUTHOR_ID`.
+async fn resolve_target(
+    harness: &Harness,
+    target_channel: u64,
+    roles: Vec<u64>,
+) -> anyhow::Result<Target> {
+    let author = Author {
+        id: AUTHOR_ID,
+        roles: Some(roles),
+    };
+    bot::mirror::resolve_target(
+        &harness.chat,
+        &text_channel(CHANNEL_ID, GUILD_ID),
+        Some(target_channel),
+        &author,
+        &Options::default(),
+    )
+    .await
+}
+
+/// Subscribes to the `updates` topic like the bot.
+async fn subscribe(harness: &Harness) -> mpsc::Receiver<db::Update> {
+    let updates = db::subscriber::<db::Update>(&harness.secret, "updates").unwrap();
+    harness.redis.wait_for_subscriber("updates").await;
+    updates
+}
+
+async fn recv(updates: &mut mpsc::Receiver<db::Update>) -> db::Update {
+    tokio::time::timeout(Duration::from_secs(5), updates.recv())
+        .await
+        .expect("No update published")
+        .expect("Subscriber closed")
+}
+
+/// Delivers a push of `commit` to master and returns the published update.
+async fn deliver(
+    harness: &Harness,
+    updates: &mut mpsc::Receiver<db::Update>,
+    commit: &str,
+) -> db::Update {
+    let payload = github::push_event(INSTALLATION_ID, REPO_ID, REPO, "master", commit);
+    let (status, body) = harness.webhook("push", &payload, commit).await;
+    assert_eq!(status, 200, "{}", body);
+    recv(updates).await
+}
+
+/// Commits `contents` to `path` on master and delivers the push,
+/// returning the commit and the published update.
+async fn push(
+    harness: &Harness,
+    updates: &mut mpsc::Receiver<db::Update>,
+    path: &str,
+    contents: impl AsRef<[u8]>,
+) -> (String, db::Update) {
+    harness.github.put_bytes(REPO, path, contents.as_ref());
+    let commit = harness.github.commit(REPO, "master");
+    let update = deliver(harness, updates, &commit).await;
+    (commit, update)
+}
+
+/// Like `push`, and applies the update like the bot, returning the commit.
+async fn push_and_update(
+    harness: &Harness,
+    updates: &mut mpsc::Receiver<db::Update>,
+    path: &str,
+    contents: impl AsRef<[u8]>,
+) -> String {
+    let (commit, update) = push(harness, updates, path, contents).await;
+    bot::mirror::update(&harness.chat, &harness.conn, &harness.fetcher, update)
+        .await
+        .expect("Failed to update mirror");
+    commit
+}
+
+#[tokio::test]
+async fn mirror_push_edit() {
+    let harness = Harness::start().await;
+    harness.github.add_repo(REPO, REPO_ID);
+    harness.github.put_file(REPO, README, "version 1");
+
+    let mirror = mirror(&harness, 2).await;
+    assert_eq!(mirror.repo_id, REPO_ID);
+    assert_eq!(
+        harness.chat.ops(),
+        vec![
+            Op::Send {
+                channel_id: CHANNEL_ID,
+                message_id: 1,
+                content: String::from("version 1"),
+            },
+            Op::Send {
+                channel_id: CHANNEL_ID,
+                message_id: 2,
+                content: String::from("*(message reserved for expansion)*"),
+            },
+        ]
+    );
+
+    let mut updates = subscribe(&harness).await;
+    harness.github.put_file(REPO, README, "version 2");
+    let commit = harness.github.commit(REPO, "master");
+    // the branch moves on before the update is rendered
+    harness.github.put_file(REPO, README, "version 3");
+    let update = deliver(&harness, &mut updates, &commit).await;
+    assert_eq!(update.group, mirror.group);
+    assert_eq!(update.message_ids, vec![1, 2]);
+    assert_eq!(update.commit.as_ref(), Some(&commit));
+
+    assert_eq!(
+        update.pages,
+        vec![String::from("version 2"), String::from(render::RESERVED)]
+    );
+
+    bot::mirror::update(&harness.chat, &harness.conn, &harness.fetcher, update)
+        .await
+        .expect("Failed to update mirror");
+    assert_eq!(harness.chat.content(1).as_deref(), Some("version 2"));
+    assert_eq!(
+        harness.chat.ops()[2..],
+        [
+            Op::Edit {
+                channel_id: CHANNEL_ID,
+                message_id: 1,
+                content: String::from("version 2"),
+            },
+            Op::Edit {
+                channel_id: CHANNEL_ID,
+                message_id: 2,
+                content: String::from("*(message reserved for expansion)*"),
+            },
+        ]
+    );
+}
+
+#[tokio::test]
+async fn duplicate_delivery_is_ignored() {
+    let harness = Harness::start().await;
+    harness.github.add_repo(REPO, REPO_ID);
+    harness.github.put_file(REPO, README, "content");
+    mirror(&harness, 1).await;
+
+    let commit = harness.github.commit(REPO, "master");
+    let payload = github::push_event(INSTALLATION_ID, REPO_ID, REPO, "master", &commit);
+    let (status, _) = harness.webhook("push", &payload, "delivery-1").await;
+    assert_eq!(status, 200);
+    let (status, body) = harness.webhook("push", &payload, "delivery-1").await;
+    assert_eq!(status, 202);
+    assert_eq!(body["status"], "ignored");
+}
+
+#[tokio::test]
+async fn bad_signature_is_rejected() {
+    let harness = Harness::start().await;
+    let payload = github::push_event(INSTALLATION_ID, REPO_ID, REPO, "master", COMMIT);
+    let body = serde_json::to_vec(&payload).unwrap();
+
+    let response = warp::test::request()
+        .method("POST")
+        .path("/webhook")
+        .header("X-GitHub-Event", "push")
+        .header("X-Hub-Signature-256", github::sign("wrong secret", &body))
+        .body(body)
+        .reply(&harness.routes())
+        .await;
+    assert!(response.status().is_client_error());
+}
+
+#[tokio::test]
+async fn missing_file_fails_mirror() {
+    let harness = Harness::start().await;
+    harness.github.add_repo(REPO, REPO_ID);
+
+    let result = create(&harness, TARGET, "master/missing.md", Options::default()).await;
+    assert!(result.is_err());
+    assert!(harness.chat.ops().is_empty());
+}
+
+#[tokio::test]
+async fn warning_removed_when_repo_seen() {
+    let harness = Harness::start().await;
+    harness.github.add_repo(REPO, REPO_ID);
+    harness.github.put_file(REPO, README, "content");
+
+    let command = harness.chat.post(CHANNEL_ID, "@blob-mirror mirror ...");
+    let mirror = mirror(&harness, 1).await;
+    bot::seen::warn(
+        &harness.chat,
+        &harness.conn,
+        &harness.secret.bot.on_seen,
+        mirror,
+        CHANNEL_ID,
+        command,
+    )
+    .await
+    .expect("Failed to warn");
+
+    let ops = harness.chat.ops();
+    let warning = match &ops[ops.len() - 2..] {
+        [Op::Send { message_id, .. }, Op::React { .. }] => *message_id,
+        ops => panic!("Unexpected {:?}", ops),
+    };
+    assert_eq!(
+        harness.chat.reactions(command),
+        vec![bot::seen::UNSEEN_REACTION]
+    );
+
+    let mut on_seen = db::subscriber::<db::OnSeen>(&harness.secret, "on_seen").unwrap();
+    harness.redis.wait_for_subscriber("on_seen").await;
+
+    let commit = harness.github.commit(REPO, "master");
+    let (status, body) = harness
+        .webhook(
+            "push",
+            &github::push_event(INSTALLATION_ID, REPO_ID, REPO, "master", &commit),
+            "delivery-1",
+        )
+        .await;
+    assert_eq!(status, 200, "{}", body);
+
+    let on_seen = tokio::time::timeout(Duration::from_secs(5), on_seen.recv())
+        .await
+        .expect("No on_seen published")
+        .expect("Subscriber closed");
+    bot::seen::handle_on_seen(&harness.chat, on_seen).await;
+
+    assert_eq!(harness.chat.content(warning), None);
+    assert!(harness.chat.reactions(command).is_empty());
+}
+
+/// Applies the freeze policy to a mirror like the bot, as if its repo was archived.
+async fn freeze(harness: &Harness, mirror: &Mirror) {
+    bot::lifecycle::handle(
+        &harness.chat,
+        &harness.conn,
+        db::Lifecycle {
+            repo_id: REPO_ID,
+            repo_name: String::from(REPO),
+            group: mirror.group.clone(),
+            channel_id: CHANNEL_ID,
+            message_ids: vec![1],
+            event: db::LifecycleEvent::Archived,
+            policy: db::LifecyclePolicy::Freeze,
+        },
+    )
+    .await
+    .expect("Failed to handle lifecycle");
+}
+
+#[tokio::test]
+async fn freeze_adds_banner() {
+    let harness = Harness::start().await;
+    harness.github.add_repo(REPO, REPO_ID);
+    harness.github.put_file(REPO, README, "content");
+    let mirror = mirror(&harness, 1).await;
+
+    freeze(&harness, &mirror).await;
+    // a repeated event does not post another banner
+    freeze(&harness, &mirror).await;
+
+    assert_eq!(harness.chat.content(1).as_deref(), Some("content"));
+    match &harness.chat.ops()[1..] {
+        [Op::Send {
+            channel_id: CHANNEL_ID,
+            content,
+            ..
+        }] => assert!(
+            content
+                .starts_with("🧊 **This mirror is frozen** because SOF3/blob-mirror was archived"),
+            "{}",
+            content
+        ),
+        ops => panic!("Unexpected {:?}", ops),
+    }
+}
+
+#[tokio::test]
+async fn frozen_mirror_ignores_pushes() {
+    let harness = Harness::start().await;
+    harness.github.add_repo(REPO, REPO_ID);
+    harness.github.put_file(REPO, README, "content");
+    let mirror = mirror(&harness, 1).await;
+    freeze(&harness, &mirror).await;
+    let ops = harness.chat.ops();
+
+    let mut updates = subscribe(&harness).await;
+    harness.github.put_file(REPO, README, "changed");
+    let commit = harness.github.commit(REPO, "master");
+    let payload = github::push_event(INSTALLATION_ID, REPO_ID, REPO, "master", &commit);
+    let (status, body) = harness.webhook("push", &payload, &commit).await;
+    assert_eq!(status, 200, "{}", body);
+
+    assert!(
+        tokio::time::timeout(Duration::from_millis(200), updates.recv())
+            .await
+            .is_err()
+    );
+    assert_eq!(harness.chat.content(1).as_deref(), Some("content"));
+    assert_eq!(harness.chat.ops(), ops);
+}
+
+#[tokio::test]
+async fn push_to_other_branch_is_skipped() {
+    let harness = Harness::start().await;
+    harness.github.add_repo(REPO, REPO_ID);
+    harness.github.put_file(REPO, README, "content");
+    mirror(&harness, 1).await;
+
+    let mut updates = subscribe(&harness).await;
+    let payload = github::push_event(INSTALLATION_ID, REPO_ID, REPO, "dev", COMMIT);
+    let (status, body) = harness.webhook("push", &payload, "delivery-1").await;
+    assert_eq!(status, 200, "{}", body);
+    let commit = harness.github.commit(REPO, "master");
+
+    // only the push to master is published
+    let update = deliver(&harness, &mut updates, &commit).await;
+    assert_eq!(update.commit, Some(commit));
+    assert!(
+        tokio::time::timeout(Duration::from_millis(200), updates.recv())
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn private_repo_is_fetched_as_installation() {
+    let harness = Harness::start().await;
+    harness.github.add_repo(REPO, REPO_ID);
+    harness.github.install(REPO, INSTALLATION_ID);
+    harness.github.put_file(REPO, README, "version 1");
+
+    mirror(&harness, 1).await;
+    assert_eq!(harness.chat.content(1).as_deref(), Some("version 1"));
+
+    let mut updates = subscribe(&harness).await;
+    let (_, update) = push(&harness, &mut updates, README, "version 2").await;
+    assert_eq!(update.installation_id, Some(INSTALLATION_ID));
+    assert_eq!(update.pages, vec![String::from("version 2")]);
+    // the bot and the web service each create a token and reuse it for every request
+    assert_eq!(harness.github.tokens(), 2);
+}
+
+#[tokio::test]
+async fn unchanged_file_is_served_from_cache() {
+    let harness = Harness::start().await;
+    harness.github.add_repo(REPO, REPO_ID);
+    harness.github.put_file(REPO, README, "content");
+
+    let url = harness
+        .secret
+        .github
+        .contents_url(REPO, "README.md", "master");
+    for _ in 0..3 {
+        let content = harness.fetcher.fetch(&url, None).await.unwrap();
+        assert_eq!(&*content, "content");
+    }
+    assert_eq!(harness.github.contents_requests(), (1, 2));
+
+    harness.github.put_file(REPO, README, "changed");
+    let content = harness.fetcher.fetch(&url, None).await.unwrap();
+    assert_eq!(&*content, "changed");
+    assert_eq!(harness.github.contents_requests(), (2, 2));
+}
+
+#[tokio::test]
+async fn shared_file_is_fetched_once_per_push() {
+    let harness = Harness::start().await;
+    harness.github.add_repo(REPO, REPO_ID);
+    harness.github.put_file(REPO, README, "version 1");
+    mirror(&harness, 1).await;
+    mirror(&harness, 1).await;
+
+    let mut updates = subscribe(&harness).await;
+    let (before, _) = harness.github.contents_requests();
+    let (_, update) = push(&harness, &mut updates, README, "version 2").await;
+    let second = recv(&mut updates).await;
+    let (after, _) = harness.github.contents_requests();
+    assert_eq!(after - before, 1);
+
+    for update in [update, second] {
+        assert_eq!(update.pages, vec![String::from("version 2")]);
+    }
+}
+
+#[tokio::test]
+async fn embed_mode_edits_embeds() {
+    let harness = Harness::start().await;
+    harness.github.add_repo(REPO, REPO_ID);
+    let text = "a".repeat(render::MESSAGE_MAX_LENGTH + 1);
+    harness.github.put_file(REPO, README, &text);
+
+    mirror_with(
+        &harness,
+        README,
+        Options {
+            mode: render::Mode::Embed,
+            ..Options::default()
+        },
+    )
+    .await;
+    // the file fits in one embed but not in one message
+    match &harness.chat.ops()[..] {
+        [Op::SendEmbed {
+            embed,
+            description,
+            fields,
+            ..
+        }] => {
+            assert!(fields.is_empty());
+            assert_eq!(embed.title, "SOF3/blob-mirror: README.md");
+            assert_eq!(embed.footer, "Branch master");
+            assert_eq!(description, &text);
+        }
+        ops => panic!("Unexpected {:?}", ops),
+    }
+
+    let mut updates = subscribe(&harness).await;
+    let commit = push_and_update(&harness, &mut updates, README, "version 2").await;
+    match &harness.chat.ops()[1..] {
+        [Op::EditEmbed {
+            embed,
+            description,
+            fields,
+            ..
+        }] => {
+            assert!(fields.is_empty());
+            assert_eq!(embed.footer, format!("Commit {}", &commit[..7]));
+            assert_eq!(description, "version 2");
+        }
+        ops => panic!("Unexpected {:?}", ops),
+    }
+}
+
+#[tokio::test]
+async fn structured_file_is_pretty_printed() {
+    const SERVERS: &str = "master/servers.json";
+
+    let harness = Harness::start().await;
+    harness.github.add_repo(REPO, REPO_ID);
+    harness
+        .github
+        .put_file(REPO, SERVERS, r#"{"servers": [{"name": "a"}]}"#);
+
+    mirror_with(
+        &harness,
+        SERVERS,
+        Options {
+            select: Some(String::from("servers.0")),
+            ..Options::default()
+        },
+    )
+    .await;
+    assert_eq!(
+        harness.chat.content(1).as_deref(),
+        Some("```json\n{\n  \"name\": \"a\"\n}\n```")
+    );
+
+    // the stored format and selection apply to updates
+    let mut updates = subscribe(&harness).await;
+    let (_, update) = push(
+        &harness,
+        &mut updates,
+        SERVERS,
+        r#"{"servers": [{"name": "b"}]}"#,
+    )
+    .await;
+    assert_eq!(
+        update.pages,
+        vec![String::from("```json\n{\n  \"name\": \"b\"\n}\n```")]
+    );
+}
+
+#[tokio::test]
+async fn sections_grow_and_shrink() {
+    const RULES: &str = "master/RULES.md";
+
+    let harness = Harness::start().await;
+    harness.github.add_repo(REPO, REPO_ID);
+    harness.github.put_file(REPO, RULES, "# Conduct\nbe nice");
+
+    let mirror = mirror_with(
+        &harness,
+        RULES,
+        Options {
+            pages: 5,
+            split: render::Split::Sections,
+            ..Options::default()
+        },
+    )
+    .await;
+    // the page count is ignored when splitting by sections
+    assert_eq!(harness.chat.ops().len(), 1);
+
+    let mut updates = subscribe(&harness).await;
+    let (_, update) = push(
+        &harness,
+        &mut updates,
+        RULES,
+        "# Conduct\nbe nice\n# Channels\nstay on topic\n# Bans\nappeal by mail",
+    )
+    .await;
+    assert_eq!(update.pages.len(), 3);
+    bot::mirror::update(&harness.chat, &harness.conn, &harness.fetcher, update)
+        .await
+        .expect("Failed to update mirror");
+    let group = harness.conn.group(&mirror.group).await.unwrap();
+    assert_eq!(group.message_ids, vec![1, 2, 3]);
+    assert_eq!(
+        harness.chat.content(3).as_deref(),
+        Some("# Bans\nappeal by mail")
+    );
+
+    push_and_update(&harness, &mut updates, RULES, "# Conduct\nbe nicer").await;
+    let group = harness.conn.group(&mirror.group).await.unwrap();
+    assert_eq!(group.message_ids, vec![1]);
+    assert_eq!(
+        harness.chat.content(1).as_deref(),
+        Some("# Conduct\nbe nicer")
+    );
+    assert_eq!(harness.chat.content(2), None);
+    assert_eq!(harness.chat.content(3), None);
+}
+
+#[tokio::test]
+async fn sections_are_capped_by_max_messages() {
+    const RULES: &str = "master/RULES.md";
+
+    let harness = Harness::start().await;
+    harness.github.add_repo(REPO, REPO_ID);
+    harness
+        .github
+        .put_file(REPO, RULES, "# Conduct\nbe nice\n# Channels\nstay on topic");
+    let settings = Settings {
+        max_messages: 2,
+        ..Settings::default()
+    };
+    harness.settings.send(Arc::new(settings.clone())).unwrap();
+
+    let options = Options {
+        split: render::Split::Sections,
+        ..Options::new(&settings)
+    };
+    mirror_with(&harness, RULES, options).await;
+    assert_eq!(harness.chat.ops().len(), 2);
+
+    let mut updates = subscribe(&harness).await;
+    let (_, update) = push(
+        &harness,
+        &mut updates,
+        RULES,
+        "# Conduct\nbe nice\n# Channels\nstay on topic\n# Bans\nappeal by mail",
+    )
+    .await;
+    assert_eq!(update.pages.len(), 2);
+    assert!(
+        update.pages[1].ends_with(&format!("See <{}> for more", file_url(&harness, RULES))),
+        "{}",
+        update.pages[1]
+    );
+
+    let result = create(
+        &harness,
+        TARGET,
+        README,
+        Options {
+            pages: 3,
+            ..Options::new(&settings)
+        },
+    )
+    .await;
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn header_and_footer_follow_pushes() {
+    let harness = Harness::start().await;
+    harness.github.add_repo(REPO, REPO_ID);
+    harness.github.put_file(REPO, README, "version 1");
+
+    let mirror = mirror_with(
+        &harness,
+        README,
+        Options {
+            frame: template::Frame {
+                header: String::from("Auto-synced from {{repo}}/{{path}}, do not edit"),
+                footer: String::from("{{page}}/{{pages}} at {{commit}} by {{author}}"),
+            },
+            ..Options::default()
+        },
+    )
+    .await;
+    assert_eq!(
+        harness.chat.content(1).as_deref(),
+        Some("Auto-synced from SOF3/blob-mirror/README.md, do not edit\nversion 1\n1/1 at master by ")
+    );
+    let group = harness.conn.group(&mirror.group).await.unwrap();
+    assert_eq!(
+        group.frame.header,
+        "Auto-synced from {{repo}}/{{path}}, do not edit"
+    );
+
+    let mut updates = subscribe(&harness).await;
+    let commit = push_and_update(&harness, &mut updates, README, "version 2").await;
+    assert_eq!(
+        harness.chat.content(1),
+        Some(format!(
+            "Auto-synced from SOF3/blob-mirror/README.md, do not edit\nversion 2\n1/1 at {} by Octocat",
+            commit
+        ))
+    );
+}
+
+#[tokio::test]
+async fn attachment_is_reposted_when_changed() {
+    const LOGO: &str = "master/docs/logo";
+    const LOGO_V1: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR version 1";
+    const LOGO_V2: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR version 2";
+
+    let harness = Harness::start().await;
+    harness.github.add_repo(REPO, REPO_ID);
+    harness.github.put_bytes(REPO, LOGO, LOGO_V1);
+
+    let mirror = mirror_with(
+        &harness,
+        LOGO,
+        Options {
+            pages: 3,
+            frame: template::Frame {
+                header: String::new(),
+                footer: String::from("at {{commit}}"),
+            },
+            ..Options::default()
+        },
+    )
+    .await;
+    // binary files are a single attachment regardless of the page count
+    assert_eq!(
+        harness.chat.ops(),
+        vec![Op::SendFile {
+            channel_id: CHANNEL_ID,
+            message_id: 1,
+            content: String::from("at master"),
+            filename: String::from("logo.png"),
+            data: LOGO_V1.to_vec(),
+        }]
+    );
+
+    // an unrelated push only changes the caption
+    let mut updates = subscribe(&harness).await;
+    let (commit, update) = push(&harness, &mut updates, README, "unrelated").await;
+    assert_eq!(update.pages, vec![format!("at {}", commit)]);
+    bot::mirror::update(&harness.chat, &harness.conn, &harness.fetcher, update)
+        .await
+        .expect("Failed to update mirror");
+    assert_eq!(
+        harness.chat.ops()[1..],
+        [Op::Edit {
+            channel_id: CHANNEL_ID,
+            message_id: 1,
+            content: format!("at {}", commit),
+        }]
+    );
+
+    let commit = push_and_update(&harness, &mut updates, LOGO, LOGO_V2).await;
+    assert_eq!(
+        harness.chat.ops()[2..],
+        [
+            Op::SendFile {
+                channel_id: CHANNEL_ID,
+                message_id: 2,
+                content: format!("at {}", commit),
+                filename: String::from("logo.png"),
+                data: LOGO_V2.to_vec(),
+            },
+            Op::Delete {
+                channel_id: CHANNEL_ID,
+                message_id: 1,
+            },
+        ]
+    );
+    let group = harness.conn.group(&mirror.group).await.unwrap();
+    assert_eq!(group.message_ids, vec![2]);
+    assert_eq!(
+        harness.redis.execute(&["GET", "mirror-group-rev:2"]),
+        Reply::Bulk(mirror.group.clone())
+    );
+    assert_eq!(
+        harness.redis.execute(&["GET", "mirror-group-rev:1"]),
+        Reply::Nil
+    );
+}
+
+#[tokio::test]
+async fn forum_post_is_unarchived_for_edits() {
+    const FORUM_ID: u64 = 300;
+
+    let harness = Harness::start().await;
+    harness.github.add_repo(REPO, REPO_ID);
+    harness.github.put_file(REPO, README, "version 1");
+
+    let target = Target {
+        channel_id: FORUM_ID,
+        forum: true,
+        ..TARGET
+    };
+    let mirror = create(&harness, target, README, Options::default())
+        .await
+        .expect("Failed to create mirror");
+    let thread_id = 1;
+    assert_eq!(
+        harness.chat.ops(),
+        vec![
+            Op::CreatePost {
+                forum_id: FORUM_ID,
+                thread_id,
+                title: String::from("README.md"),
+                content: format!("Mirror of <{}>", file_url(&harness, README)),
+            },
+            Op::Send {
+                channel_id: thread_id,
+                message_id: 2,
+                content: String::from("version 1"),
+            },
+        ]
+    );
+    let group = harness.conn.group(&mirror.group).await.unwrap();
+    assert_eq!(group.channel_id, thread_id);
+
+    let mut updates = subscribe(&harness).await;
+    harness.chat.archive(thread_id);
+    push_and_update(&harness, &mut updates, README, "version 2").await;
+    assert_eq!(
+        harness.chat.ops()[2..],
+        [
+            Op::Unarchive {
+                channel_id: thread_id,
+            },
+            Op::Edit {
+                channel_id: thread_id,
+                message_id: 2,
+                content: String::from("version 2"),
+            },
+        ]
+    );
+}
+
+#[tokio::test]
+async fn cross_channel_mirror_is_confirmed_in_command_channel() {
+    let harness = Harness::start().await;
+    harness.github.add_repo(REPO, REPO_ID);
+    harness.github.put_file(REPO, README, "version 1");
+    add_guild(&harness);
+
+    let target = resolve_target(&harness, OTHER_CHANNEL_ID, vec![MODERATOR_ROLE])
+        .await
+        .expect("Moderators can mirror to other channels");
+    assert_eq!(target.channel_id, OTHER_CHANNEL_ID);
+    let mirror = create(&harness, target, README, Options::default())
+        .await
+        .expect("Failed to create mirror");
+    let url = file_url(&harness, README);
+    bot::mirror::confirm(&harness.chat, CHANNEL_ID, &url, &mirror)
+        .await
+        .expect("Failed to confirm mirror");
+
+    assert_eq!(
+        harness.chat.ops(),
+        vec![
+            Op::Send {
+                channel_id: OTHER_CHANNEL_ID,
+                message_id: 1,
+                content: String::from("version 1"),
+            },
+            Op::Send {
+                channel_id: CHANNEL_ID,
+                message_id: 2,
+                content: format!("Mirroring <{}> in <#{}>", url, OTHER_CHANNEL_ID),
+            },
+        ]
+    );
+}
+
+#[tokio::test]
+async fn foreign_guild_channel_is_rejected() {
+    let harness = Harness::start().await;
+    add_guild(&harness);
+    harness
+        .chat
+        .add_channel(text_channel(OTHER_CHANNEL_ID + 1, GUILD_ID + 1));
+
+    let err = resolve_target(&harness, OTHER_CHANNEL_ID + 1, vec![MODERATOR_ROLE])
+        .await
+        .expect_err("Channels of other servers are rejected");
+    assert_eq!(err.to_string(), "The target channel is not in this server");
+    assert!(harness.chat.ops().is_empty());
+}
+
+#[tokio::test]
+async fn author_without_manage_messages_is_rejected() {
+    let harness = Harness::start().await;
+    add_guild(&harness);
+
+    let err = resolve_target(&harness, OTHER_CHANNEL_ID, Vec::new())
+        .await
+        .expect_err("Authors need Manage Messages in the target channel");
+    assert_eq!(
+        err.to_string(),
+        "You are missing permissions in the target channel: Manage Messages"
+    );
+    assert!(harness.chat.ops().is_empty());
+}
+
+/// Delivers an installation event and returns the lifecycles published for it.
+async fn deliver_installation(
+    harness: &Harness,
+    lifecycles: &mut mpsc::Receiver<db::Lifecycle>,
+    event: &str,
+    payload: serde_json::Value,
+    delivery: &str,
+) -> Vec<db::Lifecycle> {
+    let (status, body) = harness.webhook(event, &payload, delivery).await;
+    assert_eq!(status, 200, "{}", body);
+    let mut published = Vec::new();
+    while let Ok(Some(lifecycle)) =
+        tokio::time::timeout(Duration::from_millis(200), lifecycles.recv()).await
+    {
+        published.push(lifecycle);
+    }
+    published
+}
+
+fn members(reply: Reply) -> Vec<String> {
+    let mut members: Vec<String> = match reply {
+        Reply::Array(items) => items
+            .into_iter()
+            .map(|item| match item {
+                Reply::Bulk(member) => member,
+                item => panic!("Unexpected {:?}", item),
+            })
+            .collect(),
+        reply => panic!("Unexpected {:?}", reply),
+    };
+    members.sort();
+    members
+}
+
+#[tokio::test]
+async fn installation_events_track_repos_and_publish_lifecycles() {
+    const OTHER_REPO: &str = "SOF3/other";
+    const OTHER_REPO_ID: u64 = 43;
+
+    let harness = Harness::start().await;
+    harness.github.add_repo(REPO, REPO_ID);
+    harness.github.put_file(REPO, README, "content");
+    let mirror = mirror(&harness, 1).await;
+    let mut lifecycles = db::subscriber::<db::Lifecycle>(&harness.secret, "lifecycle").unwrap();
+    harness.redis.wait_for_subscriber("lifecycle").await;
+    let repos = [(REPO_ID, REPO), (OTHER_REPO_ID, OTHER_REPO)];
+
+    let published = deliver_installation(
+        &harness,
+        &mut lifecycles,
+        "installation",
+        github::installation_event("created", INSTALLATION_ID, &repos),
+        "delivery-created",
+    )
+    .await;
+    assert!(published.is_empty());
+    assert_eq!(
+        members(harness.redis.execute(&["SMEMBERS", "installation:7"])),
+        vec!["42", "43"]
+    );
+    for repo_id in &["42", "43"] {
+        assert_eq!(
+            harness
+                .redis
+                .execute(&["HGET", &format!("repo-info:{}", repo_id), "installation"]),
+            Reply::Bulk(String::from("7"))
+        );
+    }
+
+    let published = deliver_installation(
+        &harness,
+        &mut lifecycles,
+        "installation_repositories",
+        github::installation_repositories_event(INSTALLATION_ID, &[], &repos[..1]),
+        "delivery-removed",
+    )
+    .await;
+    match &published[..] {
+        [lifecycle] => {
+            assert_eq!(lifecycle.repo_id, REPO_ID);
+            assert_eq!(lifecycle.repo_name, REPO);
+            assert_eq!(lifecycle.group, mirror.group);
+            assert_eq!(lifecycle.channel_id, CHANNEL_ID);
+            assert_eq!(lifecycle.message_ids, vec![1]);
+            assert_eq!(lifecycle.event, db::LifecycleEvent::Uninstalled);
+        }
+        published => panic!("Unexpected {:?}", published),
+    }
+    assert_eq!(
+        members(harness.redis.execute(&["SMEMBERS", "installation:7"])),
+        vec!["43"]
+    );
+    assert_eq!(
+        harness.redis.execute(&["EXISTS", "repo-info:42"]),
+        Reply::Integer(0)
+    );
+    assert_eq!(
+        harness
+            .redis
+            .execute(&["HGET", "repo-info:43", "installation"]),
+        Reply::Bulk(String::from("7"))
+    );
+
+    let published = deliver_installation(
+        &harness,
+        &mut lifecycles,
+        "installation",
+        github::installation_event("deleted", INSTALLATION_ID, &repos[1..]),
+        "delivery-deleted",
+    )
+    .await;
+    // the mirrored repo was already removed and the other repo has no mirrors
+    assert!(published.is_empty());
+    assert_eq!(
+        harness.redis.execute(&["EXISTS", "installation:7"]),
+        Reply::Integer(0)
+    );
+    assert_eq!(
+        harness.redis.execute(&["EXISTS", "repo-info:43"]),
+        Reply::Integer(0)
+    );
+}
+
+#[tokio::test]
+async fn failed_fetch_is_delivered_again() {
+    let harness = Harness::start().await;
+    harness.github.add_repo(REPO, REPO_ID);
+    harness.github.put_file(REPO, README, "version 1");
+    let mirror = mirror(&harness, 1).await;
+
+    let mut updates = subscribe(&harness).await;
+    harness.github.put_file(REPO, README, "version 2");
+    let commit = harness.github.commit(REPO, "master");
+    let payload = github::push_event(INSTALLATION_ID, REPO_ID, REPO, "master", &commit);
+    harness.github.fail_next(1);
+    let (status, body) = harness.webhook("push", &payload, &commit).await;
+    assert_eq!(status, 502, "{}", body);
+    assert!(
+        tokio::time::timeout(Duration::from_millis(200), updates.recv())
+            .await
+            .is_err()
+    );
+
+    // the delivery was released, so that GitHub can deliver it again
+    let update = deliver(&harness, &mut updates, &commit).await;
+    assert_eq!(update.group, mirror.group);
+    assert_eq!(update.pages, vec![String::from("version 2")]);
+}
+
+#[tokio::test]
+async fn dashboard_target_checks_bot_permissions() {
+    let harness = Harness::start().await;
+    add_guild(&harness);
+
+    // dashboard commands have no author, whose permissions the dashboard checked
+    let channel = text_channel(OTHER_CHANNEL_ID, GUILD_ID);
+    bot::mirror::check_target(&harness.chat, &channel, None, &Options::default())
+        .await
+        .expect("blob-mirror can post text mirrors");
+    let options = Options {
+        format: Some(format::Format::Attachment),
+        ..Options::default()
+    };
+    let err = bot::mirror::check_target(&harness.chat, &channel, None, &options)
+        .await
+        .expect_err("Attachments need Attach Files");
+    assert_eq!(
+        err.to_string(),
+        "blob-mirror is missing permissions in the target channel: Attach Files"
+    );
+}
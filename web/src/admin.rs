@@ -0,0 +1,215 @@
+//! Authenticated JSON API for inspecting and fixing mirror state.
+
+use std::sync::Arc;
+
+use anyhow::Context;
+use ring::constant_time;
+use warp::http::StatusCode;
+use warp::reply::{self, Reply};
+use warp::Filter;
+
+use crate::{publish, SettingsRx};
+
+use common::contents::Fetcher;
+use common::db;
+use common::secret::Redacted;
+use common::settings::Settings;
+
+pub fn routes(
+    token: Option<Redacted>,
+    conn: Arc<db::Conn>,
+    settings: SettingsRx,
+    fetcher: Arc<Fetcher>,
+) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    let conn = warp::any().map(move || Arc::clone(&conn));
+    let fetcher = warp::any().map(move || Arc::clone(&fetcher));
+
+    let list_repos = warp::get()
+        .and(warp::path!("repos"))
+        .and(conn.clone())
+        .and_then(|conn: Arc<db::Conn>| async move {
+            let result = async {
+                let mut repos = Vec::new();
+                for repo_id in conn.repos().await? {
+                    repos.push(repo(&conn, repo_id).await?);
+                }
+                Ok(repos)
+            };
+            Ok::<_, warp::Rejection>(json(result.await))
+        });
+
+    let get_repo = warp::get()
+        .and(warp::path!("repos" / u64))
+        .and(conn.clone())
+        .and_then(|repo_id: u64, conn: Arc<db::Conn>| async move {
+            Ok::<_, warp::Rejection>(json(repo(&conn, repo_id).await))
+        });
+
+    let resend_on_seen = warp::post()
+        .and(warp::path!("repos" / u64 / "on-seen"))
+        .and(conn.clone())
+        .and_then(|repo_id: u64, conn: Arc<db::Conn>| async move {
+            Ok::<_, warp::Rejection>(json(conn.publish_on_seen(repo_id).await))
+        });
+
+    let refresh_group = warp::post()
+        .and(warp::path!("repos" / u64 / "groups" / String / "refresh"))
+        .and(conn.clone())
+        .and(fetcher)
+        .and(warp::any().map(move || settings.clone()))
+        .and_then(
+            |repo_id: u64,
+             group: String,
+             conn: Arc<db::Conn>,
+             fetcher: Arc<Fetcher>,
+             settings: SettingsRx| async move {
+                let result = match conn.has_group(repo_id, &group).await {
+                    Ok(true) => match conn.group_update(repo_id, &group).await {
+                        Ok(update) => {
+                            let max_messages = settings.borrow().max_messages;
+                            match publish::publish(&conn, &fetcher, max_messages, vec![update])
+                                .await
+                            {
+                                Ok(failed) if !failed.is_empty() => {
+                                    Err(anyhow::anyhow!("Could not fetch the file of the group"))
+                                }
+                                result => result.map(drop),
+                            }
+                        }
+                        Err(err) => Err(err),
+                    },
+                    Ok(false) => return Ok(group_not_found()),
+                    Err(err) => Err(err),
+                };
+                Ok::<_, warp::Rejection>(json(result))
+            },
+        );
+
+    let get_settings = warp::get()
+        .and(warp::path!("settings"))
+        .and(conn.clone())
+        .and_then(|conn: Arc<db::Conn>| async move {
+            let result = conn.settings().await.map(|hash| Settings::from_hash(&hash));
+            Ok::<_, warp::Rejection>(json(result))
+        });
+
+    let put_settings = warp::put()
+        .and(warp::path!("settings"))
+        .and(warp::body::json::<Settings>())
+        .and(conn.clone())
+        .and_then(|settings: Settings, conn: Arc<db::Conn>| async move {
+            if let Err(err) = settings.validate() {
+                return Ok::<_, warp::Rejection>(bad_request(err));
+            }
+            Ok(json(conn.set_settings(&settings.to_hash()).await))
+        });
+
+    let delete_group = warp::delete()
+        .and(warp::path!("repos" / u64 / "groups" / String))
+        .and(conn)
+        .and_then(
+            |repo_id: u64, group: String, conn: Arc<db::Conn>| async move {
+                let result = match conn.has_group(repo_id, &group).await {
+                    Ok(true) => conn.delete_group(repo_id, &group).await,
+                    Ok(false) => return Ok(group_not_found()),
+                    Err(err) => Err(err),
+                };
+                Ok::<_, warp::Rejection>(json(result))
+            },
+        );
+
+    warp::path("admin").and(authorized(token)).and(
+        list_repos
+            .or(get_repo)
+            .or(resend_on_seen)
+            .or(refresh_group)
+            .or(delete_group)
+            .or(get_settings)
+            .or(put_settings),
+    )
+}
+
+/// Rejects requests without the `Authorization: Bearer {token}` header.
+fn authorized(
+    token: Option<Redacted>,
+) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let token = token.clone();
+            async move {
+                let given = header
+                    .as_deref()
+                    .and_then(|header| header.strip_prefix("Bearer "));
+                match (token, given) {
+                    // compared in constant time so that the token cannot be guessed byte by byte
+                    (Some(token), Some(given))
+                        if constant_time::verify_slices_are_equal(
+                            given.as_bytes(),
+                            token.expose().as_bytes(),
+                        )
+                        .is_ok() =>
+                    {
+                        Ok(())
+                    }
+                    _ => Err(warp::reject::not_found()),
+                }
+            }
+        })
+        .untuple_one()
+}
+
+#[derive(serde::Serialize)]
+struct Repo {
+    id: u64,
+    name: Option<String>,
+    seen: db::SeenStatus,
+    groups: Vec<Group>,
+    pending_on_seen: db::OnSeen,
+}
+
+#[derive(serde::Serialize)]
+struct Group {
+    id: String,
+    #[serde(flatten)]
+    group: db::Group,
+}
+
+async fn repo(conn: &db::Conn, repo_id: u64) -> anyhow::Result<Repo> {
+    let mut groups = Vec::new();
+    for id in conn.repo_groups(repo_id).await? {
+        let group = conn
+            .group(&id)
+            .await
+            .with_context(|| format!("Error fetching mirror group {}", &id))?;
+        groups.push(Group { id, group });
+    }
+
+    Ok(Repo {
+        id: repo_id,
+        name: conn.repo_name(repo_id).await?,
+        seen: conn.is_seen(repo_id).await?,
+        groups,
+        pending_on_seen: conn.pending_on_seen(repo_id).await?,
+    })
+}
+
+fn json(result: anyhow::Result<impl serde::Serialize>) -> reply::Response {
+    match result {
+        Ok(value) => reply::json(&value).into_response(),
+        Err(err) => {
+            log::error!("Admin API error: {:?}", err);
+            let body = reply::json(&serde_json::json!({ "error": format!("{:#}", err) }));
+            reply::with_status(body, StatusCode::INTERNAL_SERVER_ERROR).into_response()
+        }
+    }
+}
+
+fn bad_request(err: anyhow::Error) -> reply::Response {
+    let body = reply::json(&serde_json::json!({ "error": format!("{:#}", err) }));
+    reply::with_status(body, StatusCode::BAD_REQUEST).into_response()
+}
+
+fn group_not_found() -> reply::Response {
+    let body = reply::json(&serde_json::json!({ "error": "No such mirror group" }));
+    reply::with_status(body, StatusCode::NOT_FOUND).into_response()
+}
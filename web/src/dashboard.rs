@@ -0,0 +1,703 @@
+//! Server-rendered dashboard where users log in with Discord to manage the mirrors of their guilds.
+
+use std::sync::Arc;
+
+use anyhow::Context;
+use warp::http::{header, Response, StatusCode};
+use warp::hyper::Body;
+use warp::reply::Reply;
+use warp::Filter;
+
+use common::secret::{Redacted, Secret};
+use common::{db, format, render, template};
+
+use crate::SettingsRx;
+
+mod pages;
+
+const DISCORD_API: &str = "https://discord.com/api/v9";
+const DISCORD_AUTHORIZE: &str = "https://discord.com/api/oauth2/authorize";
+/// The `ADMINISTRATOR` permission bit
+const ADMINISTRATOR: u64 = 1 << 3;
+/// The `MANAGE_GUILD` permission bit
+const MANAGE_GUILD: u64 = 1 << 5;
+const SESSION_COOKIE: &str = "session";
+const SESSION_TTL: u64 = 86400;
+const OAUTH_STATE_TTL: u64 = 600;
+
+struct Config {
+    client_id: u64,
+    client_secret: Redacted,
+    bot_token: Redacted,
+    redirect_uri: String,
+    client: reqwest::Client,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Session {
+    user: String,
+    /// The Discord user ID; `0` in sessions created before it was stored
+    #[serde(default)]
+    user_id: u64,
+    /// Token that must be submitted with every form, to prevent cross-site request forgery
+    csrf: String,
+    /// Guilds in which the user has the Manage Server permission
+    guilds: Vec<SessionGuild>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SessionGuild {
+    id: u64,
+    name: String,
+}
+
+impl Session {
+    fn guild(&self, id: u64) -> Option<&SessionGuild> {
+        self.guilds.iter().find(|guild| guild.id == id)
+    }
+}
+
+pub fn routes(
+    secret: &Secret,
+    conn: Arc<db::Conn>,
+    settings: SettingsRx,
+) -> impl Filter<Extract = (Response<Body>,), Error = warp::Rejection> + Clone {
+    let config = secret.web.public_url.as_ref().map(|public_url| {
+        Arc::new(Config {
+            client_id: secret.discord.client_id,
+            client_secret: secret
+                .discord
+                .client_secret
+                .clone()
+                .expect("Checked by secret::load"),
+            bot_token: secret
+                .discord
+                .token
+                .clone()
+                .expect("Checked by secret::load"),
+            redirect_uri: format!("{}/dashboard/callback", public_url.trim_end_matches('/')),
+            client: reqwest::Client::new(),
+        })
+    });
+    let config = warp::any().and_then(move || {
+        let config = config.clone();
+        async move { config.ok_or_else(warp::reject::not_found) }
+    });
+    let conn = warp::any().map(move || Arc::clone(&conn));
+    let session = warp::cookie::optional::<String>(SESSION_COOKIE);
+
+    let index = warp::get()
+        .and(warp::path::end())
+        .and(conn.clone())
+        .and(session)
+        .and_then(|conn: Arc<db::Conn>, cookie: Option<String>| async move {
+            let result = async {
+                Ok(match load_session(&conn, cookie).await? {
+                    Some((_, session)) => html(StatusCode::OK, pages::guilds(&session)),
+                    None => redirect("/dashboard/login"),
+                })
+            };
+            Ok::<_, warp::Rejection>(respond(result.await))
+        });
+
+    let login = warp::get()
+        .and(warp::path!("login"))
+        .and(config.clone())
+        .and(conn.clone())
+        .and_then(|config: Arc<Config>, conn: Arc<db::Conn>| async move {
+            let result = async {
+                let state = db::random_id(32);
+                conn.put_oauth_state(&state, OAUTH_STATE_TTL).await?;
+                let url = reqwest::Url::parse_with_params(
+                    DISCORD_AUTHORIZE,
+                    &[
+                        ("client_id", config.client_id.to_string().as_str()),
+                        ("redirect_uri", &config.redirect_uri),
+                        ("response_type", "code"),
+                        ("scope", "identify guilds"),
+                        ("state", &state),
+                    ],
+                )?;
+                Ok(redirect(url.as_str()))
+            };
+            Ok::<_, warp::Rejection>(respond(result.await))
+        });
+
+    #[derive(serde::Deserialize)]
+    struct Callback {
+        code: String,
+        state: String,
+    }
+
+    let callback = warp::get()
+        .and(warp::path!("callback"))
+        .and(warp::query::<Callback>())
+        .and(config.clone())
+        .and(conn.clone())
+        .and_then(
+            |query: Callback, config: Arc<Config>, conn: Arc<db::Conn>| async move {
+                let result = async {
+                    if !conn.take_oauth_state(&query.state).await? {
+                        return Ok(html(
+                            StatusCode::BAD_REQUEST,
+                            pages::error("The login request has expired. Please try again."),
+                        ));
+                    }
+                    let session = login_session(&config, &query.code).await?;
+                    let id = db::random_id(32);
+                    conn.put_session(&id, &session, SESSION_TTL).await?;
+
+                    let mut response = redirect("/dashboard");
+                    response.headers_mut().insert(
+                        header::SET_COOKIE,
+                        format!(
+                            "{}={}; Path=/dashboard; Max-Age={}; HttpOnly; Secure; SameSite=Lax",
+                            SESSION_COOKIE, id, SESSION_TTL
+                        )
+                        .parse()?,
+                    );
+                    Ok(response)
+                };
+                Ok::<_, warp::Rejection>(respond(result.await))
+            },
+        );
+
+    #[derive(serde::Deserialize)]
+    struct CsrfForm {
+        csrf: String,
+    }
+
+    let logout = warp::post()
+        .and(warp::path!("logout"))
+        .and(warp::body::form::<CsrfForm>())
+        .and(conn.clone())
+        .and(session)
+        .and_then(
+            |form: CsrfForm, conn: Arc<db::Conn>, cookie: Option<String>| async move {
+                let result = async {
+                    if let Some((id, session)) = load_session(&conn, cookie).await? {
+                        if session.csrf == form.csrf {
+                            conn.delete_session(&id).await?;
+                        }
+                    }
+                    let mut response = redirect("/dashboard");
+                    response.headers_mut().insert(
+                        header::SET_COOKIE,
+                        format!("{}=; Path=/dashboard; Max-Age=0", SESSION_COOKIE).parse()?,
+                    );
+                    Ok(response)
+                };
+                Ok::<_, warp::Rejection>(respond(result.await))
+            },
+        );
+
+    let guild =
+        warp::get()
+            .and(warp::path!("guilds" / u64))
+            .and(config.clone())
+            .and(conn.clone())
+            .and(session)
+            .and_then(
+                |guild_id: u64,
+                 config: Arc<Config>,
+                 conn: Arc<db::Conn>,
+                 cookie: Option<String>| async move {
+                    let result = async {
+                        let session = match load_session(&conn, cookie).await? {
+                            Some((_, session)) => session,
+                            None => return Ok(redirect("/dashboard/login")),
+                        };
+                        let guild = match session.guild(guild_id) {
+                            Some(guild) => guild,
+                            None => return Ok(forbidden()),
+                        };
+
+                        let channels = match guild_channels(&config, guild_id).await {
+                            Ok(channels) => channels,
+                            Err(err) => {
+                                log::warn!("Cannot list channels of guild {}: {:?}", guild_id, err);
+                                return Ok(html(
+                                    StatusCode::OK,
+                                    pages::not_joined(&session, guild, config.client_id),
+                                ));
+                            }
+                        };
+
+                        let mut mirrors = Vec::new();
+                        for id in conn.guild_groups(guild_id).await? {
+                            let group = conn.group(&id).await?;
+                            let (repo_name, seen) = match group.repo_id {
+                                Some(repo_id) => {
+                                    (conn.repo_name(repo_id).await?, conn.is_seen(repo_id).await?)
+                                }
+                                None => (None, db::SeenStatus::NotInstalled),
+                            };
+                            mirrors.push(pages::Mirror {
+                                id,
+                                group,
+                                repo_name,
+                                seen,
+                            });
+                        }
+
+                        Ok(html(
+                            StatusCode::OK,
+                            pages::guild(&session, guild, &channels, &mirrors),
+                        ))
+                    };
+                    Ok::<_, warp::Rejection>(respond(result.await))
+                },
+            );
+
+    #[derive(serde::Deserialize)]
+    struct MirrorForm {
+        csrf: String,
+        channel: u64,
+        url: String,
+        pages: usize,
+        #[serde(default)]
+        split: render::Split,
+        lifecycle: db::LifecyclePolicy,
+        #[serde(default)]
+        mode: Option<render::Mode>,
+        /// Empty to detect the format from the extension
+        #[serde(default)]
+        format: String,
+        #[serde(default)]
+        select: String,
+        #[serde(default)]
+        header: String,
+        #[serde(default)]
+        footer: String,
+    }
+
+    let create_mirror = warp::post()
+        .and(warp::path!("guilds" / u64 / "mirrors"))
+        .and(warp::body::form::<MirrorForm>())
+        .and(config.clone())
+        .and(conn.clone())
+        .and(session)
+        .and(warp::any().map(move || settings.clone()))
+        .and_then(
+            |guild_id: u64,
+             form: MirrorForm,
+             config: Arc<Config>,
+             conn: Arc<db::Conn>,
+             cookie: Option<String>,
+             settings: SettingsRx| async move {
+                let result = async {
+                    let session = match load_session(&conn, cookie).await? {
+                        Some((_, session)) if session.csrf == form.csrf => session,
+                        _ => return Ok(forbidden()),
+                    };
+                    if let Some(denied) = check_access(&config, &session, guild_id).await? {
+                        return Ok(denied);
+                    }
+                    if !settings.borrow().allows_guild(guild_id) {
+                        return Ok(forbidden());
+                    }
+                    // the bot checks its permissions in the channel before posting,
+                    // but cannot report failures back to the dashboard
+                    let channels = guild_channels(&config, guild_id).await?;
+                    if !channels.iter().any(|channel| channel.id == form.channel) {
+                        return Ok(html(
+                            StatusCode::BAD_REQUEST,
+                            pages::error(
+                                "The channel is not a text channel, thread or forum of this server.",
+                            ),
+                        ));
+                    }
+                    let format = match form.format.as_str() {
+                        "" => None,
+                        format => Some(format.parse::<format::Format>()?),
+                    };
+                    let select = Some(form.select).filter(|select| !select.is_empty());
+                    let frame = template::Frame {
+                        header: form.header,
+                        footer: form.footer,
+                    };
+                    frame.validate()?;
+                    conn.publish_command(&db::Command::Mirror {
+                        guild_id,
+                        channel_id: form.channel,
+                        url: form.url,
+                        pages: form.pages,
+                        split: form.split,
+                        lifecycle: form.lifecycle,
+                        mode: form.mode,
+                        format,
+                        select,
+                        header: frame.header,
+                        footer: frame.footer,
+                    })
+                    .await?;
+                    Ok(redirect(&format!("/dashboard/guilds/{}", guild_id)))
+                };
+                Ok::<_, warp::Rejection>(respond(result.await))
+            },
+        );
+
+    let remove_mirror = warp::post()
+        .and(warp::path!("guilds" / u64 / "mirrors" / String / "remove"))
+        .and(warp::body::form::<CsrfForm>())
+        .and(config.clone())
+        .and(conn)
+        .and(session)
+        .and_then(
+            |guild_id: u64,
+             group: String,
+             form: CsrfForm,
+             config: Arc<Config>,
+             conn: Arc<db::Conn>,
+             cookie: Option<String>| async move {
+                let result = async {
+                    let session = match load_session(&conn, cookie).await? {
+                        Some((_, session)) if session.csrf == form.csrf => session,
+                        _ => return Ok(forbidden()),
+                    };
+                    if let Some(denied) = check_access(&config, &session, guild_id).await? {
+                        return Ok(denied);
+                    }
+                    let info = conn.group(&group).await?;
+                    let repo_id = match (info.guild_id, info.repo_id) {
+                        (Some(owner), Some(repo_id)) if owner == guild_id => repo_id,
+                        _ => return Ok(forbidden()),
+                    };
+                    conn.publish_command(&db::Command::Unmirror { repo_id, group })
+                        .await?;
+                    Ok(redirect(&format!("/dashboard/guilds/{}", guild_id)))
+                };
+                Ok::<_, warp::Rejection>(respond(result.await))
+            },
+        );
+
+    let enabled = config.clone().map(|_| ()).untuple_one();
+
+    warp::path("dashboard").and(enabled).and(
+        index
+            .or(login)
+            .unify()
+            .or(callback)
+            .unify()
+            .or(logout)
+            .unify()
+            .or(guild)
+            .unify()
+            .or(create_mirror)
+            .unify()
+            .or(remove_mirror)
+            .unify(),
+    )
+}
+
+async fn load_session(
+    conn: &db::Conn,
+    cookie: Option<String>,
+) -> anyhow::Result<Option<(String, Session)>> {
+    let id = match cookie {
+        Some(id) => id,
+        None => return Ok(None),
+    };
+    let session = conn.session::<Session>(&id).await?;
+    Ok(session.map(|session| (id, session)))
+}
+
+/// Exchanges an OAuth2 code for the user's identity and manageable guilds.
+async fn login_session(config: &Config, code: &str) -> anyhow::Result<Session> {
+    #[derive(serde::Deserialize)]
+    struct Token {
+        access_token: String,
+    }
+
+    #[derive(serde::Deserialize)]
+    struct User {
+        id: String,
+        username: String,
+        discriminator: String,
+    }
+
+    #[derive(serde::Deserialize)]
+    struct Guild {
+        id: String,
+        name: String,
+        owner: bool,
+        permissions: String,
+    }
+
+    let token = config
+        .client
+        .post(format!("{}/oauth2/token", DISCORD_API))
+        .form(&[
+            ("client_id", config.client_id.to_string().as_str()),
+            ("client_secret", config.client_secret.expose()),
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &config.redirect_uri),
+        ])
+        .send()
+        .await
+        .context("Failed to exchange OAuth code")?
+        .error_for_status()
+        .context("Discord rejected the OAuth code")?
+        .json::<Token>()
+        .await
+        .context("Discord OAuth API is not working correctly")?;
+
+    let user = config
+        .client
+        .get(format!("{}/users/@me", DISCORD_API))
+        .bearer_auth(&token.access_token)
+        .send()
+        .await
+        .context("Failed to fetch user")?
+        .error_for_status()?
+        .json::<User>()
+        .await
+        .context("Discord API is not working correctly")?;
+
+    let guilds = config
+        .client
+        .get(format!("{}/users/@me/guilds", DISCORD_API))
+        .bearer_auth(&token.access_token)
+        .send()
+        .await
+        .context("Failed to fetch guilds")?
+        .error_for_status()?
+        .json::<Vec<Guild>>()
+        .await
+        .context("Discord API is not working correctly")?;
+
+    let mut session_guilds = Vec::new();
+    for guild in guilds {
+        let permissions = guild
+            .permissions
+            .parse::<u64>()
+            .context("Guild permissions is not an integer")?;
+        if guild.owner || permissions & MANAGE_GUILD != 0 {
+            session_guilds.push(SessionGuild {
+                id: guild.id.parse().context("Guild ID is not an integer")?,
+                name: guild.name,
+            });
+        }
+    }
+
+    Ok(Session {
+        user: format!("{}#{}", user.username, user.discriminator),
+        user_id: user.id.parse().context("User ID is not an integer")?,
+        csrf: db::random_id(32),
+        guilds: session_guilds,
+    })
+}
+
+/// Checks with the bot token that the bot is still in a guild of the session
+/// and that the user still has the Manage Server permission there,
+/// since both may have changed after login.
+///
+/// Returns the page to respond with if access is denied.
+async fn check_access(
+    config: &Config,
+    session: &Session,
+    guild_id: u64,
+) -> anyhow::Result<Option<Response<Body>>> {
+    #[derive(serde::Deserialize)]
+    struct Guild {
+        owner_id: String,
+        roles: Vec<Role>,
+    }
+
+    #[derive(serde::Deserialize)]
+    struct Role {
+        id: String,
+        permissions: String,
+    }
+
+    #[derive(serde::Deserialize)]
+    struct Member {
+        roles: Vec<String>,
+    }
+
+    let session_guild = match session.guild(guild_id) {
+        Some(guild) => guild,
+        None => return Ok(Some(forbidden())),
+    };
+
+    let response = config
+        .client
+        .get(format!("{}/guilds/{}", DISCORD_API, guild_id))
+        .header(
+            header::AUTHORIZATION,
+            format!("Bot {}", config.bot_token.expose()),
+        )
+        .send()
+        .await
+        .context("Failed to fetch guild")?;
+    if matches!(
+        response.status(),
+        StatusCode::FORBIDDEN | StatusCode::NOT_FOUND
+    ) {
+        return Ok(Some(html(
+            StatusCode::OK,
+            pages::not_joined(session, session_guild, config.client_id),
+        )));
+    }
+    let guild = response
+        .error_for_status()?
+        .json::<Guild>()
+        .await
+        .context("Discord API is not working correctly")?;
+    if guild.owner_id == session.user_id.to_string() {
+        return Ok(None);
+    }
+
+    let response = config
+        .client
+        .get(format!(
+            "{}/guilds/{}/members/{}",
+            DISCORD_API, guild_id, session.user_id
+        ))
+        .header(
+            header::AUTHORIZATION,
+            format!("Bot {}", config.bot_token.expose()),
+        )
+        .send()
+        .await
+        .context("Failed to fetch member")?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(Some(forbidden()));
+    }
+    let member = response
+        .error_for_status()?
+        .json::<Member>()
+        .await
+        .context("Discord API is not working correctly")?;
+
+    let mut permissions = 0;
+    for role in &guild.roles {
+        // the `@everyone` role has the ID of the guild
+        if role.id == guild_id.to_string() || member.roles.contains(&role.id) {
+            permissions |= role
+                .permissions
+                .parse::<u64>()
+                .context("Role permissions is not an integer")?;
+        }
+    }
+    if permissions & (ADMINISTRATOR | MANAGE_GUILD) == 0 {
+        return Ok(Some(forbidden()));
+    }
+    Ok(None)
+}
+
+/// Lists the text channels, forums and active threads of a guild,
+/// failing if the bot is not in the guild.
+async fn guild_channels(config: &Config, guild_id: u64) -> anyhow::Result<Vec<pages::Channel>> {
+    /// Type of guild text channels
+    const GUILD_TEXT: u8 = 0;
+    /// Types of announcement, public and private threads
+    const GUILD_THREADS: [u8; 3] = [10, 11, 12];
+    /// Type of guild forum channels
+    const GUILD_FORUM: u8 = 15;
+
+    #[derive(serde::Deserialize)]
+    struct Channel {
+        id: String,
+        name: String,
+        #[serde(rename = "type")]
+        kind: u8,
+        #[serde(default)]
+        position: i64,
+    }
+
+    #[derive(serde::Deserialize)]
+    struct ActiveThreads {
+        threads: Vec<Channel>,
+    }
+
+    let mut channels = config
+        .client
+        .get(format!("{}/guilds/{}/channels", DISCORD_API, guild_id))
+        .header(
+            header::AUTHORIZATION,
+            format!("Bot {}", config.bot_token.expose()),
+        )
+        .send()
+        .await
+        .context("Failed to fetch channels")?
+        .error_for_status()?
+        .json::<Vec<Channel>>()
+        .await
+        .context("Discord API is not working correctly")?;
+    channels.sort_by_key(|channel| channel.position);
+
+    let threads = config
+        .client
+        .get(format!(
+            "{}/guilds/{}/threads/active",
+            DISCORD_API, guild_id
+        ))
+        .header(
+            header::AUTHORIZATION,
+            format!("Bot {}", config.bot_token.expose()),
+        )
+        .send()
+        .await
+        .context("Failed to fetch threads")?
+        .error_for_status()?
+        .json::<ActiveThreads>()
+        .await
+        .context("Discord API is not working correctly")?;
+    channels.extend(threads.threads);
+
+    channels
+        .into_iter()
+        .filter_map(|channel| {
+            let kind = match channel.kind {
+                GUILD_TEXT => None,
+                GUILD_FORUM => Some("forum"),
+                kind if GUILD_THREADS.contains(&kind) => Some("thread"),
+                _ => return None,
+            };
+            Some((channel, kind))
+        })
+        .map(|(channel, kind)| {
+            Ok(pages::Channel {
+                id: channel.id.parse().context("Channel ID is not an integer")?,
+                name: channel.name,
+                kind,
+            })
+        })
+        .collect()
+}
+
+fn respond(result: anyhow::Result<Response<Body>>) -> Response<Body> {
+    match result {
+        Ok(response) => response,
+        Err(err) => {
+            log::error!("Dashboard error: {:?}", err);
+            html(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                pages::error("Something went wrong. Please try again later."),
+            )
+        }
+    }
+}
+
+fn html(status: StatusCode, body: String) -> Response<Body> {
+    let mut response = warp::reply::html(body).into_response();
+    *response.status_mut() = status;
+    response
+}
+
+fn redirect(location: &str) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(header::LOCATION, location)
+        .body(Body::empty())
+        .expect("Location header is valid")
+}
+
+fn forbidden() -> Response<Body> {
+    html(
+        StatusCode::FORBIDDEN,
+        pages::error("You do not have the Manage Server permission in this guild."),
+    )
+}
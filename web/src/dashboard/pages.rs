@@ -0,0 +1,224 @@
+//! HTML rendering of dashboard pages.
+
+use std::fmt::Write;
+use std::time::{Duration, UNIX_EPOCH};
+
+use common::db;
+
+use super::{Session, SessionGuild};
+
+pub struct Channel {
+    pub id: u64,
+    pub name: String,
+    /// Shown after the name for channels other than text channels, e.g. `forum`
+    pub kind: Option<&'static str>,
+}
+
+pub struct Mirror {
+    pub id: String,
+    pub group: db::Group,
+    pub repo_name: Option<String>,
+    pub seen: db::SeenStatus,
+}
+
+pub fn guilds(session: &Session) -> String {
+    let mut body = String::new();
+    body += "<h2>Your servers</h2>";
+    if session.guilds.is_empty() {
+        body += "<p>You do not have the Manage Server permission in any server.</p>";
+    } else {
+        body += "<ul>";
+        for guild in &session.guilds {
+            let _ = write!(
+                body,
+                r#"<li><a href="/dashboard/guilds/{}">{}</a></li>"#,
+                guild.id,
+                escape(&guild.name)
+            );
+        }
+        body += "</ul>";
+    }
+    layout(Some(session), "Servers", &body)
+}
+
+pub fn not_joined(session: &Session, guild: &SessionGuild, client_id: u64) -> String {
+    let body = format!(
+        r#"<h2>{}</h2>
+<p>blob-mirror is not in this server.
+<a href="https://discord.com/oauth2/authorize?client_id={}&amp;scope=bot&amp;guild_id={}">Invite it</a> first.</p>"#,
+        escape(&guild.name),
+        client_id,
+        guild.id,
+    );
+    layout(Some(session), &guild.name, &body)
+}
+
+pub fn guild(
+    session: &Session,
+    guild: &SessionGuild,
+    channels: &[Channel],
+    mirrors: &[Mirror],
+) -> String {
+    let channel_name = |id: u64| {
+        channels
+            .iter()
+            .find(|channel| channel.id == id)
+            .map_or_else(|| id.to_string(), |channel| format!("#{}", channel.name))
+    };
+
+    let mut body = String::new();
+    let _ = write!(body, "<h2>{}</h2>", escape(&guild.name));
+
+    body += "<h3>Mirrors</h3>";
+    if mirrors.is_empty() {
+        body += "<p>There are no mirrors in this server yet.</p>";
+    } else {
+        body += "<table><tr><th>File</th><th>Channel</th><th>Messages</th>\
+                 <th>Mode</th><th>When lost</th><th>Status</th><th>Last update</th><th></th></tr>";
+        for mirror in mirrors {
+            let file = match &mirror.repo_name {
+                Some(repo) => format!("{}/{}", repo, mirror.group.path),
+                None => mirror.group.path.clone(),
+            };
+            let status = match mirror.seen {
+                db::SeenStatus::Installed(_) => "Installed",
+                db::SeenStatus::Suspended(_) => "GitHub App suspended",
+                db::SeenStatus::NotInstalled => "GitHub App not installed",
+            };
+            let updated = mirror.group.updated.map_or_else(
+                || String::from("Never"),
+                |time| {
+                    humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(time))
+                        .to_string()
+                },
+            );
+            let _ = write!(
+                body,
+                r#"<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td>
+<td><form method="post" action="/dashboard/guilds/{}/mirrors/{}/remove">
+<input type="hidden" name="csrf" value="{}"><button>Remove</button></form></td></tr>"#,
+                escape(&file),
+                escape(&channel_name(mirror.group.channel_id)),
+                mirror.group.message_ids.len(),
+                mirror.group.mode.as_str(),
+                mirror.group.lifecycle.as_str(),
+                status,
+                updated,
+                guild.id,
+                escape(&mirror.id),
+                escape(&session.csrf),
+            );
+        }
+        body += "</table>";
+    }
+
+    body += "<h3>New mirror</h3>";
+    let _ = write!(
+        body,
+        r#"<form method="post" action="/dashboard/guilds/{}/mirrors">
+<input type="hidden" name="csrf" value="{}">
+<p><label>File URL <input name="url" type="url" size="60" required
+  placeholder="https://github.com/user/repo/blob/master/README.md"></label></p>
+<p><label>Channel <select name="channel">"#,
+        guild.id,
+        escape(&session.csrf),
+    );
+    for channel in channels {
+        let _ = write!(
+            body,
+            r#"<option value="{}">#{}{}</option>"#,
+            channel.id,
+            escape(&channel.name),
+            channel
+                .kind
+                .map(|kind| format!(" ({})", kind))
+                .unwrap_or_default()
+        );
+    }
+    body += r#"</select></label></p>
+<p><label>Split into <select name="split">
+<option value="pages">a fixed number of messages</option>
+<option value="sections">a message per top-level section</option>
+</select></label>
+<label>Messages <input name="pages" type="number" min="1" value="1"></label></p>
+<p><label>Display as <select name="mode">
+<option value="text">Plain messages</option>
+<option value="embed">Embeds</option>
+<option value="fields">Embeds with a field per entry of JSON, YAML or TOML files</option>
+</select></label></p>
+<p><label>File format <select name="format">
+<option value="">Detect from the extension</option>
+<option value="raw">Raw text</option>
+<option value="json">JSON</option>
+<option value="yaml">YAML</option>
+<option value="toml">TOML</option>
+<option value="markdown">Markdown</option>
+<option value="attachment">Attachment</option>
+</select></label>
+<label>Show only <input name="select" placeholder="/json/pointer or key.path"></label></p>
+<p><label>Header <input name="header" placeholder="Auto-synced from {{repo}}, do not edit"></label>
+<label>Footer <input name="footer" placeholder="Page {{page}}/{{pages}} at {{commit}}"></label></p>
+<p><label>When the repo is deleted, archived or made private <select name="lifecycle">
+<option value="notify">Notify the channel</option>
+<option value="freeze">Freeze the content with a banner</option>
+<option value="teardown">Delete the messages</option>
+</select></label></p>
+<p><button>Create</button></p>
+</form>"#;
+
+    layout(Some(session), &guild.name, &body)
+}
+
+pub fn error(message: &str) -> String {
+    layout(None, "Error", &format!("<p>{}</p>", escape(message)))
+}
+
+fn layout(session: Option<&Session>, title: &str, body: &str) -> String {
+    let mut header = String::from(r#"<a href="/dashboard">blob-mirror</a>"#);
+    if let Some(session) = session {
+        let _ = write!(
+            header,
+            r#" &middot; {} <form method="post" action="/dashboard/logout" style="display: inline">
+<input type="hidden" name="csrf" value="{}"><button>Log out</button></form>"#,
+            escape(&session.user),
+            escape(&session.csrf),
+        );
+    }
+
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{} - blob-mirror</title>
+<style>
+body {{ font-family: sans-serif; margin: 2em; }}
+table {{ border-collapse: collapse; }}
+td, th {{ border: 1px solid #ccc; padding: 0.3em 0.6em; text-align: left; }}
+</style>
+</head>
+<body>
+<header>{}</header>
+{}
+</body>
+</html>"#,
+        escape(title),
+        header,
+        body
+    )
+}
+
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out += "&amp;",
+            '<' => out += "&lt;",
+            '>' => out += "&gt;",
+            '"' => out += "&quot;",
+            '\'' => out += "&#39;",
+            c => out.push(c),
+        }
+    }
+    out
+}
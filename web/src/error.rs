@@ -0,0 +1,59 @@
+//! Error responses of the web service.
+
+use std::convert::Infallible;
+
+use warp::http::StatusCode;
+use warp::reply::{self, Reply};
+
+/// An error while handling a request
+#[derive(Debug)]
+pub enum Error {
+    /// The request is well-formed but its content cannot be handled
+    BadPayload(String),
+    /// The database operation failed
+    Storage(anyhow::Error),
+    /// A request to GitHub failed
+    Upstream(anyhow::Error),
+}
+
+impl warp::reject::Reject for Error {}
+
+/// Converts rejections into JSON error responses with matching status codes.
+pub async fn recover(rejection: warp::Rejection) -> Result<reply::Response, Infallible> {
+    let (status, message) = if let Some(err) = rejection.find::<Error>() {
+        match err {
+            Error::BadPayload(message) => (StatusCode::BAD_REQUEST, message.clone()),
+            Error::Storage(err) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Storage failure: {}", err),
+            ),
+            Error::Upstream(err) => (StatusCode::BAD_GATEWAY, format!("GitHub failure: {}", err)),
+        }
+    } else if let Some(err) = rejection.find::<warp_github_webhook::Error>() {
+        (
+            StatusCode::BAD_REQUEST,
+            format!("Invalid webhook payload: {:?}", err),
+        )
+    } else if let Some(err) = rejection.find::<warp::reject::MissingHeader>() {
+        let status = if err.name() == "X-Hub-Signature-256" {
+            StatusCode::UNAUTHORIZED
+        } else {
+            StatusCode::BAD_REQUEST
+        };
+        (status, err.to_string())
+    } else if rejection.is_not_found() {
+        (StatusCode::NOT_FOUND, String::from("Not found"))
+    } else if let Some(err) = rejection.find::<warp::reject::MethodNotAllowed>() {
+        (StatusCode::METHOD_NOT_ALLOWED, err.to_string())
+    } else if let Some(err) = rejection.find::<warp::reject::PayloadTooLarge>() {
+        (StatusCode::PAYLOAD_TOO_LARGE, err.to_string())
+    } else {
+        (StatusCode::BAD_REQUEST, format!("{:?}", rejection))
+    };
+
+    let body = reply::json(&serde_json::json!({
+        "status": "error",
+        "error": message,
+    }));
+    Ok(reply::with_status(body, status).into_response())
+}
@@ -0,0 +1,234 @@
+//! Tracking of the repos covered by each installation of the GitHub App.
+
+use std::collections::HashMap;
+
+use common::db::{self, LifecycleEvent};
+
+use crate::schema::{self, InstallationEventAction};
+
+/// The state changes caused by an installation event
+#[derive(Debug, Default, PartialEq)]
+pub struct Plan {
+    /// Repos newly covered by the installation
+    pub add: Vec<u64>,
+    /// Repos no longer covered by the installation
+    pub remove: Vec<u64>,
+    /// Whether the installation no longer exists
+    pub delete: bool,
+    /// Repos that can be mirrored from now on
+    pub seen: Vec<u64>,
+    /// Repos that can no longer be mirrored, with the reason
+    pub lost: Vec<(u64, LifecycleEvent)>,
+}
+
+/// Plans an `installation` event.
+///
+/// `payload` is the list of repos in the event and `stored` is the list of repos
+/// previously recorded for the installation.
+pub fn installation(action: InstallationEventAction, payload: &[u64], stored: &[u64]) -> Plan {
+    let all = union(payload, stored);
+    match action {
+        InstallationEventAction::Created => Plan {
+            add: payload.to_vec(),
+            seen: payload.to_vec(),
+            ..Plan::default()
+        },
+        InstallationEventAction::Unsuspend | InstallationEventAction::NewPermissionsAccepted => {
+            Plan {
+                add: difference(payload, stored),
+                seen: all,
+                ..Plan::default()
+            }
+        }
+        InstallationEventAction::Suspend => Plan {
+            lost: all
+                .into_iter()
+                .map(|id| (id, LifecycleEvent::Suspended))
+                .collect(),
+            ..Plan::default()
+        },
+        InstallationEventAction::Deleted => Plan {
+            delete: true,
+            lost: all
+                .into_iter()
+                .map(|id| (id, LifecycleEvent::Uninstalled))
+                .collect(),
+            ..Plan::default()
+        },
+    }
+}
+
+/// Plans an `installation_repositories` event.
+pub fn installation_repositories(added: &[u64], removed: &[u64]) -> Plan {
+    Plan {
+        add: added.to_vec(),
+        remove: removed.to_vec(),
+        seen: added.to_vec(),
+        lost: removed
+            .iter()
+            .map(|&id| (id, LifecycleEvent::Uninstalled))
+            .collect(),
+        ..Plan::default()
+    }
+}
+
+impl Plan {
+    /// Applies the plan to the database and warns the mirror channels of lost repos.
+    ///
+    /// `repos` provides the repos known from the event payload.
+    pub async fn apply(
+        self,
+        conn: &db::Conn,
+        installation: &schema::Installation,
+        repos: &HashMap<u64, &schema::Repo>,
+    ) -> anyhow::Result<()> {
+        let installation_id = installation.id;
+        conn.add_installation_repos(installation_id, &self.add)
+            .await?;
+        conn.remove_installation_repos(installation_id, &self.remove)
+            .await?;
+        if self.delete {
+            conn.delete_installation(installation_id).await?;
+        }
+
+        for repo_id in self.seen {
+            let stored_name;
+            let account = match (&installation.account, repos.get(&repo_id)) {
+                (Some(account), _) => account.login.as_str(),
+                (None, Some(repo)) => repo.owner(),
+                (None, None) => {
+                    stored_name = conn.repo_name(repo_id).await?.unwrap_or_default();
+                    stored_name.split('/').next().unwrap_or_default()
+                }
+            };
+            let record = db::Installation {
+                id: installation_id,
+                account,
+                permissions: installation.permissions.as_ref(),
+            };
+            conn.mark_installed(repo_id, &record).await?;
+        }
+
+        for (repo_id, event) in self.lost {
+            match event {
+                LifecycleEvent::Suspended => conn.mark_suspended(repo_id).await?,
+                _ => conn.mark_uninstalled(repo_id).await?,
+            }
+
+            let name = match repos.get(&repo_id) {
+                Some(repo) => repo.full_name.clone(),
+                None => conn
+                    .repo_name(repo_id)
+                    .await?
+                    .unwrap_or_else(|| format!("Repo #{}", repo_id)),
+            };
+            conn.on_repo_lifecycle(repo_id, &name, event).await?;
+        }
+
+        Ok(())
+    }
+}
+
+fn union(a: &[u64], b: &[u64]) -> Vec<u64> {
+    let mut ret: Vec<u64> = a.iter().chain(b).copied().collect();
+    ret.sort_unstable();
+    ret.dedup();
+    ret
+}
+
+fn difference(a: &[u64], b: &[u64]) -> Vec<u64> {
+    a.iter().filter(|id| !b.contains(id)).copied().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn created_adds_and_sees_payload() {
+        let plan = installation(InstallationEventAction::Created, &[1, 2], &[]);
+        assert_eq!(
+            plan,
+            Plan {
+                add: vec![1, 2],
+                seen: vec![1, 2],
+                ..Plan::default()
+            }
+        );
+    }
+
+    #[test]
+    fn deleted_loses_stored_and_payload_repos() {
+        let plan = installation(InstallationEventAction::Deleted, &[2], &[1, 2]);
+        assert_eq!(
+            plan,
+            Plan {
+                delete: true,
+                lost: vec![
+                    (1, LifecycleEvent::Uninstalled),
+                    (2, LifecycleEvent::Uninstalled)
+                ],
+                ..Plan::default()
+            }
+        );
+    }
+
+    #[test]
+    fn suspend_keeps_installation_repos() {
+        let plan = installation(InstallationEventAction::Suspend, &[], &[1, 2]);
+        assert_eq!(
+            plan,
+            Plan {
+                lost: vec![
+                    (1, LifecycleEvent::Suspended),
+                    (2, LifecycleEvent::Suspended)
+                ],
+                ..Plan::default()
+            }
+        );
+    }
+
+    #[test]
+    fn unsuspend_sees_stored_repos() {
+        let plan = installation(InstallationEventAction::Unsuspend, &[3], &[1, 2]);
+        assert_eq!(
+            plan,
+            Plan {
+                add: vec![3],
+                seen: vec![1, 2, 3],
+                ..Plan::default()
+            }
+        );
+    }
+
+    #[test]
+    fn new_permissions_accepted_does_not_lose_repos() {
+        let plan = installation(
+            InstallationEventAction::NewPermissionsAccepted,
+            &[1],
+            &[1, 2],
+        );
+        assert_eq!(
+            plan,
+            Plan {
+                seen: vec![1, 2],
+                ..Plan::default()
+            }
+        );
+    }
+
+    #[test]
+    fn repositories_added_and_removed_are_planned_separately() {
+        let plan = installation_repositories(&[1, 2], &[3]);
+        assert_eq!(
+            plan,
+            Plan {
+                add: vec![1, 2],
+                remove: vec![3],
+                seen: vec![1, 2],
+                lost: vec![(3, LifecycleEvent::Uninstalled)],
+                ..Plan::default()
+            }
+        );
+    }
+}
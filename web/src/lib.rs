@@ -0,0 +1,56 @@
+//! The webhook receiver, admin API and dashboard, also used by the integration tests.
+
+use std::sync::Arc;
+
+use warp::Filter;
+
+use common::contents::Fetcher;
+use common::secret::Secret;
+use common::settings::Settings;
+use common::{db, health};
+
+mod admin;
+mod dashboard;
+mod error;
+mod installation;
+mod publish;
+mod schema;
+mod webhook;
+
+pub type SettingsRx = tokio::sync::watch::Receiver<Arc<Settings>>;
+
+/// Returns all routes of the web service.
+///
+/// `fetcher` downloads the files of pushed mirror groups and keeps them cached across requests.
+pub fn routes(
+    secret: &Secret,
+    conn: Arc<db::Conn>,
+    settings: SettingsRx,
+    fetcher: Arc<Fetcher>,
+) -> impl Filter<Extract = impl warp::Reply, Error = std::convert::Infallible> + Clone {
+    webhook::routes(
+        secret
+            .github
+            .webhook_secret
+            .as_ref()
+            .expect("Checked by secret::load")
+            .expose()
+            .to_string(),
+        secret.web.delivery_ttl,
+        Arc::clone(&conn),
+        settings.clone(),
+        Arc::clone(&fetcher),
+    )
+    .or(admin::routes(
+        secret.web.admin_token.clone(),
+        Arc::clone(&conn),
+        settings.clone(),
+        fetcher,
+    ))
+    .or(dashboard::routes(secret, Arc::clone(&conn), settings))
+    .or(health::routes(move || {
+        let conn = Arc::clone(&conn);
+        async move { health::check_redis(&conn).await }
+    }))
+    .recover(error::recover)
+}
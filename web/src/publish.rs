@@ -0,0 +1,87 @@
+//! Rendering updates once per file before publishing them to the bot.
+
+use std::collections::HashMap;
+
+use futures::future;
+
+use common::attachment::Attachment;
+use common::contents::{Blob, Fetched, Fetcher};
+use common::{db, format, metrics, render};
+
+/// Fetches each distinct file of `updates` once, renders the pages of every group
+/// and publishes the updates.
+///
+/// Groups split by sections get at most `max_messages` pages.
+/// Groups whose file cannot be fetched are skipped and returned,
+/// so that the caller can have the event delivered again.
+/// Files in `format::Format::Attachment` are described but not uploaded;
+/// the bot downloads them itself if the blob changed.
+pub async fn publish(
+    conn: &db::Conn,
+    fetcher: &Fetcher,
+    max_messages: usize,
+    updates: Vec<db::Update>,
+) -> anyhow::Result<Vec<String>> {
+    let mut urls: Vec<(&str, Option<u64>)> = updates
+        .iter()
+        .map(|update| (update.contents_url.as_str(), update.installation_id))
+        .collect();
+    urls.sort_unstable();
+    urls.dedup_by_key(|&mut (url, _)| url);
+
+    let fetched = future::join_all(urls.into_iter().map(|(url, installation_id)| async move {
+        let result = fetcher.fetch_blob(url, installation_id).await;
+        if let Err(err) = &result {
+            log::error!("Error fetching {}: {:?}", url, err);
+        }
+        (url.to_string(), result.ok())
+    }))
+    .await;
+    let files: HashMap<String, Option<Fetched>> = fetched.into_iter().collect();
+
+    let mut failed = Vec::new();
+    for mut update in updates {
+        let file = match files.get(&update.contents_url) {
+            Some(Some(file)) => file,
+            _ => {
+                failed.push(update.group);
+                continue;
+            }
+        };
+        let layout = format::Layout {
+            spec: &update.format,
+            embed: update.embed.as_ref(),
+            frame: &update.frame,
+            vars: &update.vars,
+            url: &update.url,
+        };
+        if update.format.format == format::Format::Attachment {
+            let attachment = Attachment::new(&update.url, file.blob.as_bytes(), &file.sha);
+            update.pages = vec![attachment.caption(&layout)];
+            update.attachment = Some(attachment);
+        } else {
+            let text = match &file.blob {
+                Blob::Text(text) => text,
+                Blob::Binary(_) => {
+                    log::error!(
+                        "Group {} mirrors {} as text, but the file is not valid UTF-8",
+                        &update.group,
+                        &update.url
+                    );
+                    continue;
+                }
+            };
+            let count = match update.split {
+                render::Split::Pages => update.message_ids.len(),
+                render::Split::Sections => format::page_count(text, &layout).min(max_messages),
+            };
+            let rendered = format::pages(text, &layout, count);
+            update.pages = rendered.pages;
+            update.fields = rendered.fields;
+        }
+        update.published_at = metrics::unix_millis();
+        conn.publish_update(&update).await?;
+    }
+
+    Ok(failed)
+}
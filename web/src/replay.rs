@@ -0,0 +1,91 @@
+//! The `replay` subcommand, which resends a saved webhook payload for debugging.
+
+use std::sync::Arc;
+
+use anyhow::Context;
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use tokio::sync::watch;
+
+use common::contents::Fetcher;
+use common::db;
+use common::secret::Secret;
+use common::settings::Settings;
+
+const USAGE: &str = "Usage: web replay <event type> <payload file> [instance URL]";
+
+/// Replays a webhook payload file.
+///
+/// If an instance URL is given, the payload is signed with the webhook secret and posted to
+/// `{url}/webhook`. Otherwise, it is handled directly by the webhook routes against the database.
+/// Replayed payloads have no delivery ID, so they are never deduplicated.
+pub async fn run(secret: Secret, mut args: impl Iterator<Item = String>) -> anyhow::Result<()> {
+    let event = args.next().context(USAGE)?;
+    let file = args.next().context(USAGE)?;
+    let url = args.next();
+
+    let payload = std::fs::read(&file).with_context(|| format!("Failed to read {}", &file))?;
+
+    let mut mac = Hmac::<Sha256>::new_varkey(
+        secret
+            .github
+            .webhook_secret
+            .as_ref()
+            .expect("Checked by secret::load")
+            .expose()
+            .as_bytes(),
+    )
+    .expect("HMAC can take a key of any size");
+    mac.update(&payload);
+    let signature = format!("sha256={}", hex::encode(mac.finalize().into_bytes()));
+
+    let (status, body) = match url {
+        Some(url) => {
+            let resp = reqwest::Client::new()
+                .post(format!("{}/webhook", url.trim_end_matches('/')))
+                .header("Content-Type", "application/json")
+                .header("X-GitHub-Event", &event)
+                .header("X-Hub-Signature-256", &signature)
+                .body(payload)
+                .send()
+                .await
+                .context("Failed to send payload")?;
+            let status = resp.status();
+            let body = resp.text().await.context("Failed to read response")?;
+            (status, body)
+        }
+        None => {
+            let conn = db::Conn::new(&secret)
+                .await
+                .context("Failed initializing database")?;
+            let settings = Settings::from_hash(&conn.settings().await?);
+            let (_, settings) = watch::channel(Arc::new(settings));
+            let resp = warp::test::request()
+                .method("POST")
+                .path("/webhook")
+                .header("Content-Type", "application/json")
+                .header("X-GitHub-Event", &event)
+                .header("X-Hub-Signature-256", &signature)
+                .body(payload)
+                .reply(&web::routes(
+                    &secret,
+                    Arc::new(conn),
+                    settings,
+                    Arc::new(
+                        Fetcher::new(&secret.github)
+                            .context("Failed initializing GitHub client")?,
+                    ),
+                ))
+                .await;
+            let body = String::from_utf8_lossy(resp.body()).into_owned();
+            (resp.status(), body)
+        }
+    };
+
+    println!("{}\n{}", status, body);
+    if !status.is_success() {
+        anyhow::bail!("Replay failed with status {}", status);
+    }
+
+    Ok(())
+}
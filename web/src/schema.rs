@@ -1,11 +1,16 @@
+use std::collections::BTreeMap;
+
 use serde::Deserialize;
 
+use common::db::{self, LifecycleEvent};
+
 #[derive(Deserialize)]
 pub struct PingEvent {}
 
 #[derive(Deserialize)]
 pub struct InstallationEvent {
     pub action: InstallationEventAction,
+    #[serde(default)]
     pub repositories: Vec<Repo>,
     pub installation: Installation,
 }
@@ -20,36 +25,11 @@ pub enum InstallationEventAction {
     NewPermissionsAccepted,
 }
 
-impl InstallationEventAction {
-    pub fn seen(self) -> bool {
-        match self {
-            Self::Created | Self::Unsuspend | Self::NewPermissionsAccepted => true,
-            Self::Deleted | Self::Suspend => false,
-        }
-    }
-}
-
 #[derive(Deserialize)]
 pub struct InstallationRepositoriesEvent {
-    pub action: InstallationRepositoriesEventAction,
     pub repositories_added: Vec<Repo>,
     pub repositories_removed: Vec<Repo>,
-}
-
-#[derive(Debug, Clone, Copy, Deserialize)]
-#[serde(rename_all = "snake_case")]
-pub enum InstallationRepositoriesEventAction {
-    Added,
-    Removed,
-}
-
-impl InstallationRepositoriesEventAction {
-    pub fn seen(self) -> bool {
-        match self {
-            Self::Added => true,
-            Self::Removed => false,
-        }
-    }
+    pub installation: Installation,
 }
 
 #[derive(Deserialize)]
@@ -58,6 +38,42 @@ pub struct PushEvent {
     pub repository: Repo,
     #[serde(rename = "ref")]
     pub ref_: String,
+    /// SHA of the head commit after the push
+    pub after: String,
+    /// Whether the push deleted the ref
+    #[serde(default)]
+    pub deleted: bool,
+    /// `None` if the push deleted the ref
+    #[serde(default)]
+    pub head_commit: Option<Commit>,
+}
+
+#[derive(Deserialize)]
+pub struct Commit {
+    pub author: CommitAuthor,
+}
+
+#[derive(Deserialize)]
+pub struct CommitAuthor {
+    pub name: String,
+}
+
+impl PushEvent {
+    /// Returns the pushed branch and commit, or `None` for tags and deleted branches.
+    pub fn branch_push(&self) -> Option<db::Push<'_>> {
+        if self.deleted {
+            return None;
+        }
+        let branch = self.ref_.strip_prefix("refs/heads/")?;
+        Some(db::Push {
+            branch,
+            commit: &self.after,
+            author: self
+                .head_commit
+                .as_ref()
+                .map(|commit| commit.author.name.as_str()),
+        })
+    }
 }
 
 #[derive(Deserialize)]
@@ -90,13 +106,48 @@ impl RepoEventAction {
             Self::Deleted | Self::Archived | Self::Privatized => Some(false),
             Self::Transferred => None,
         }
-        // TODO extra logic with deletion
+    }
+
+    pub fn lifecycle(self) -> Option<LifecycleEvent> {
+        match self {
+            Self::Deleted => Some(LifecycleEvent::Deleted),
+            Self::Archived => Some(LifecycleEvent::Archived),
+            Self::Privatized => Some(LifecycleEvent::Privatized),
+            _ => None,
+        }
     }
 }
 
 #[derive(Deserialize)]
 pub struct Installation {
     pub id: u64,
+    /// Only present in `installation` and `installation_repositories` events
+    #[serde(default)]
+    pub account: Option<Account>,
+    /// Only present in `installation` and `installation_repositories` events
+    #[serde(default)]
+    pub permissions: Option<BTreeMap<String, String>>,
+}
+
+impl Installation {
+    /// Converts to the stored installation record of `repo`.
+    ///
+    /// If the payload does not contain the account, it is taken from the repo owner.
+    pub fn record<'t>(&'t self, repo: &'t Repo) -> db::Installation<'t> {
+        db::Installation {
+            id: self.id,
+            account: match &self.account {
+                Some(account) => &account.login,
+                None => repo.owner(),
+            },
+            permissions: self.permissions.as_ref(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Account {
+    pub login: String,
 }
 
 #[derive(Deserialize)]
@@ -104,3 +155,10 @@ pub struct Repo {
     pub id: u64,
     pub full_name: String,
 }
+
+impl Repo {
+    /// Returns the user or organization part of `full_name`.
+    pub fn owner(&self) -> &str {
+        self.full_name.split('/').next().unwrap_or_default()
+    }
+}
@@ -0,0 +1,380 @@
+//! Handlers of GitHub webhook events.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+
+use serde::de::DeserializeOwned;
+use warp::http::StatusCode;
+use warp::reply::{self, Reply};
+use warp::Filter;
+use warp_github_webhook::{webhook, Kind as EventType};
+
+use common::contents::Fetcher;
+use common::{db, metrics};
+
+use crate::error::Error;
+use crate::{installation, publish, schema, SettingsRx};
+
+/// The event types handled by `routes`; other event types are acknowledged and ignored.
+const KNOWN_EVENTS: &[&str] = &[
+    "ping",
+    "installation",
+    "installation_repositories",
+    "push",
+    "repository",
+];
+
+pub fn routes(
+    webhook_secret: String,
+    delivery_ttl: u64,
+    conn: Arc<db::Conn>,
+    settings: SettingsRx,
+    fetcher: Arc<Fetcher>,
+) -> impl Filter<Extract = (reply::Response,), Error = warp::Rejection> + Clone {
+    let conn = warp::any().map(move || Arc::clone(&conn));
+
+    let ping = event::<schema::PingEvent>(EventType::PING, "ping", webhook_secret.clone())
+        .and(delivery())
+        .map(|_, delivery: Delivery| {
+            log::info!("Received ping delivery {}", delivery);
+            Outcome::Ok
+        });
+    let installation = event(
+        EventType::INSTALLATION,
+        "installation",
+        webhook_secret.clone(),
+    )
+    .and(conn.clone())
+    .and(delivery())
+    .and_then(move |event, conn, delivery| {
+        dispatch(
+            "installation",
+            event,
+            conn,
+            delivery,
+            delivery_ttl,
+            installation_event,
+        )
+    });
+    let installation_repositories = event(
+        EventType::INSTALLATION_REPOSITORIES,
+        "installation_repositories",
+        webhook_secret.clone(),
+    )
+    .and(conn.clone())
+    .and(delivery())
+    .and_then(move |event, conn, delivery| {
+        dispatch(
+            "installation_repositories",
+            event,
+            conn,
+            delivery,
+            delivery_ttl,
+            installation_repositories_event,
+        )
+    });
+    let push = event(EventType::PUSH, "push", webhook_secret.clone())
+        .and(conn.clone())
+        .and(delivery())
+        .and_then(move |event, conn, delivery| {
+            let fetcher = Arc::clone(&fetcher);
+            let max_messages = settings.borrow().max_messages;
+            dispatch(
+                "push",
+                event,
+                conn,
+                delivery,
+                delivery_ttl,
+                move |event, conn| push_event(event, conn, fetcher, max_messages),
+            )
+        });
+    let repository = event(EventType::REPOSITORY, "repository", webhook_secret)
+        .and(conn)
+        .and(delivery())
+        .and_then(move |event, conn, delivery| {
+            dispatch(
+                "repository",
+                event,
+                conn,
+                delivery,
+                delivery_ttl,
+                repository_event,
+            )
+        });
+
+    warp::post().and(warp::path("webhook")).and(
+        ping.map(|outcome| respond("ping", outcome))
+            .or(installation.map(|outcome| respond("installation", outcome)))
+            .unify()
+            .or(installation_repositories
+                .map(|outcome| respond("installation_repositories", outcome)))
+            .unify()
+            .or(push.map(|outcome| respond("push", outcome)))
+            .unify()
+            .or(repository.map(|outcome| respond("repository", outcome)))
+            .unify()
+            .or(unknown_event())
+            .unify(),
+    )
+}
+
+/// The result of a successfully handled event
+enum Outcome {
+    Ok,
+    Ignored(&'static str),
+}
+
+/// The `X-GitHub-Delivery` header of a webhook request, for logging
+struct Delivery(Option<String>);
+
+impl fmt::Display for Delivery {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.0 {
+            Some(id) => f.write_str(id),
+            None => f.write_str("(no delivery ID)"),
+        }
+    }
+}
+
+fn delivery() -> impl Filter<Extract = (Delivery,), Error = warp::Rejection> + Clone {
+    warp::header::optional("X-GitHub-Delivery").map(Delivery)
+}
+
+/// Parses a webhook event, counting payloads that fail to parse or verify.
+fn event<T: DeserializeOwned + Send + 'static>(
+    kind: EventType,
+    name: &'static str,
+    webhook_secret: String,
+) -> impl Filter<Extract = (T,), Error = warp::Rejection> + Clone {
+    webhook(kind, webhook_secret).or_else(move |rejection: warp::Rejection| async move {
+        if rejection.find::<warp_github_webhook::Error>().is_some() {
+            metrics::WEBHOOK_EVENTS
+                .with_label_values(&[name, "invalid"])
+                .inc();
+        }
+        Err(rejection)
+    })
+}
+
+/// Acknowledges event types that are not handled.
+fn unknown_event() -> impl Filter<Extract = (reply::Response,), Error = warp::Rejection> + Clone {
+    warp::header::<String>("X-GitHub-Event")
+        .and(delivery())
+        .and_then(|event: String, delivery: Delivery| async move {
+            if KNOWN_EVENTS.contains(&event.as_str()) {
+                return Err(warp::reject::not_found());
+            }
+            log::info!("Ignoring {} delivery {}", &event, delivery);
+            metrics::WEBHOOK_EVENTS
+                .with_label_values(&["unknown", "ignored"])
+                .inc();
+            let body = reply::json(&serde_json::json!({
+                "status": "ignored",
+                "reason": format!("Event type {:?} is not handled", event),
+            }));
+            Ok(reply::with_status(body, StatusCode::ACCEPTED).into_response())
+        })
+}
+
+fn respond(event: &'static str, outcome: Outcome) -> reply::Response {
+    match outcome {
+        Outcome::Ok => {
+            metrics::WEBHOOK_EVENTS
+                .with_label_values(&[event, "ok"])
+                .inc();
+            reply::json(&serde_json::json!({ "status": "ok" })).into_response()
+        }
+        Outcome::Ignored(reason) => {
+            metrics::WEBHOOK_EVENTS
+                .with_label_values(&[event, "ignored"])
+                .inc();
+            let body = reply::json(&serde_json::json!({
+                "status": "ignored",
+                "reason": reason,
+            }));
+            reply::with_status(body, StatusCode::ACCEPTED).into_response()
+        }
+    }
+}
+
+/// Logs and counts a failed event, converting it to a rejection.
+fn reject(event: &'static str, delivery: &Delivery, err: Error) -> warp::Rejection {
+    log::error!("Error handling {} delivery {}: {:?}", event, delivery, &err);
+    metrics::WEBHOOK_EVENTS
+        .with_label_values(&[event, "error"])
+        .inc();
+    warp::reject::custom(err)
+}
+
+/// Runs an event handler unless the delivery was already handled.
+///
+/// The delivery ID is claimed before running the handler,
+/// and released if the handler fails so that GitHub can retry it.
+async fn dispatch<T, F, Fut>(
+    name: &'static str,
+    event: T,
+    conn: Arc<db::Conn>,
+    delivery: Delivery,
+    delivery_ttl: u64,
+    handler: F,
+) -> Result<Outcome, warp::Rejection>
+where
+    F: FnOnce(T, Arc<db::Conn>) -> Fut,
+    Fut: Future<Output = Result<Outcome, Error>>,
+{
+    log::info!("Received {} delivery {}", name, &delivery);
+
+    if let Some(id) = &delivery.0 {
+        match conn.claim_delivery(id, delivery_ttl).await {
+            Ok(true) => {}
+            Ok(false) => {
+                log::info!("Ignoring duplicate {} delivery {}", name, &delivery);
+                return Ok(Outcome::Ignored("Duplicate delivery"));
+            }
+            Err(err) => return Err(reject(name, &delivery, Error::Storage(err))),
+        }
+    }
+
+    match handler(event, Arc::clone(&conn)).await {
+        Ok(outcome) => Ok(outcome),
+        Err(err) => {
+            if let Some(id) = &delivery.0 {
+                if let Err(err) = conn.release_delivery(id).await {
+                    log::error!("Error releasing delivery {}: {:?}", id, err);
+                }
+            }
+            Err(reject(name, &delivery, err))
+        }
+    }
+}
+
+async fn installation_event(
+    event: schema::InstallationEvent,
+    conn: Arc<db::Conn>,
+) -> Result<Outcome, Error> {
+    let installation_id = event.installation.id;
+    let stored = conn
+        .installation_repos(installation_id)
+        .await
+        .map_err(Error::Storage)?;
+    let payload: Vec<u64> = event.repositories.iter().map(|repo| repo.id).collect();
+
+    let plan = installation::installation(event.action, &payload, &stored);
+    plan.apply(
+        &conn,
+        &event.installation,
+        &repos_by_id(&event.repositories),
+    )
+    .await
+    .map_err(Error::Storage)?;
+    Ok(Outcome::Ok)
+}
+
+async fn installation_repositories_event(
+    event: schema::InstallationRepositoriesEvent,
+    conn: Arc<db::Conn>,
+) -> Result<Outcome, Error> {
+    let added: Vec<u64> = event
+        .repositories_added
+        .iter()
+        .map(|repo| repo.id)
+        .collect();
+    let removed: Vec<u64> = event
+        .repositories_removed
+        .iter()
+        .map(|repo| repo.id)
+        .collect();
+
+    let plan = installation::installation_repositories(&added, &removed);
+    let repos = event
+        .repositories_added
+        .iter()
+        .chain(&event.repositories_removed);
+    plan.apply(
+        &conn,
+        &event.installation,
+        &repos.map(|repo| (repo.id, repo)).collect(),
+    )
+    .await
+    .map_err(Error::Storage)?;
+    Ok(Outcome::Ok)
+}
+
+fn repos_by_id(repos: &[schema::Repo]) -> HashMap<u64, &schema::Repo> {
+    repos.iter().map(|repo| (repo.id, repo)).collect()
+}
+
+async fn repository_event(event: schema::RepoEvent, conn: Arc<db::Conn>) -> Result<Outcome, Error> {
+    let seen = match event.action.seen() {
+        Some(seen) => seen,
+        None => return Ok(Outcome::Ignored("Repository action is not handled")),
+    };
+
+    if let schema::RepoEventAction::Deleted = event.action {
+        conn.mark_uninstalled(event.repository.id)
+            .await
+            .map_err(Error::Storage)?;
+        conn.remove_installation_repos(event.installation.id, &[event.repository.id])
+            .await
+            .map_err(Error::Storage)?;
+    } else if seen {
+        let record = event.installation.record(&event.repository);
+        conn.mark_installed(event.repository.id, &record)
+            .await
+            .map_err(Error::Storage)?;
+    }
+
+    if let Some(lifecycle) = event.action.lifecycle() {
+        conn.on_repo_lifecycle(event.repository.id, &event.repository.full_name, lifecycle)
+            .await
+            .map_err(Error::Storage)?;
+    }
+
+    Ok(Outcome::Ok)
+}
+
+async fn push_event(
+    event: schema::PushEvent,
+    conn: Arc<db::Conn>,
+    fetcher: Arc<Fetcher>,
+    max_messages: usize,
+) -> Result<Outcome, Error> {
+    let mut split = event.repository.full_name.split('/');
+    let (user, repo) = match (split.next(), split.next()) {
+        (Some(user), Some(repo)) => (user, repo),
+        _ => {
+            return Err(Error::BadPayload(format!(
+                "Repository name {:?} is not in the form user/repo",
+                &event.repository.full_name
+            )))
+        }
+    };
+    let record = event.installation.record(&event.repository);
+    conn.mark_installed(event.repository.id, &record)
+        .await
+        .map_err(Error::Storage)?;
+    conn.mark_pushed(event.repository.id)
+        .await
+        .map_err(Error::Storage)?;
+    let push = match event.branch_push() {
+        Some(push) => push,
+        None => return Ok(Outcome::Ignored("Push is not a branch update")),
+    };
+    let updates = conn
+        .on_repo_update(event.repository.id, user, repo, push)
+        .await
+        .map_err(Error::Storage)?;
+    let failed = publish::publish(&conn, &fetcher, max_messages, updates)
+        .await
+        .map_err(Error::Storage)?;
+    // failing the event releases the delivery, so that it can be delivered again
+    if !failed.is_empty() {
+        return Err(Error::Upstream(anyhow::anyhow!(
+            "Could not fetch the files of mirror groups {}",
+            failed.join(", ")
+        )));
+    }
+    Ok(Outcome::Ok)
+}